@@ -0,0 +1,126 @@
+//! This module locates the ACPI system description tables which the firmware places in memory.
+//!
+//! The root system description pointer (RSDP) is found by scanning the BIOS memory areas for its
+//! signature. It points to the root table (the RSDT, or the XSDT from ACPI 2.0 onwards), which
+//! lists the physical addresses of the other tables. Drivers look up the table they need by its
+//! four byte signature, for example "HPET" or "APIC".
+//!
+//! Tables are accessed through the bootloader's mapping of physical memory, so memory::init
+//! must be called before this module is used.
+
+use crate::bytes::read;
+use crate::memory::phys_to_virt;
+use conquer_once::spin::OnceCell;
+use x86_64::PhysAddr;
+
+/// Signature at the start of the RSDP
+const RSDP_SIGNATURE: &[u8; 8] = b"RSD PTR ";
+
+/// Physical address of the BIOS data area word containing the EBDA segment
+const EBDA_SEGMENT_PTR: u64 = 0x40e;
+
+/// Physical address range of the main BIOS area which may contain the RSDP
+const BIOS_AREA_START: u64 = 0xe0000;
+const BIOS_AREA_END: u64 = 0x100000;
+
+/// Size of every ACPI table's header
+pub const SDT_HEADER_SIZE: usize = 36;
+
+/// The root table, and the size of the table addresses it contains
+/// (4 bytes for the RSDT, 8 bytes for the XSDT)
+struct RootTable {
+    table: &'static [u8],
+    entry_size: usize,
+}
+
+/// The root table, which is found once by init()
+static ROOT_TABLE: OnceCell<RootTable> = OnceCell::uninit();
+
+/// Returns `len` bytes of physical memory starting at `phys`
+///
+/// # Safety
+///
+/// The caller must guarantee that the memory is mapped and is not being written to.
+unsafe fn phys_slice(phys: u64, len: usize) -> Option<&'static [u8]> {
+    let virt = phys_to_virt(PhysAddr::try_new(phys).ok()?)?;
+    Some(unsafe { core::slice::from_raw_parts(virt.as_ptr(), len) })
+}
+
+/// Returns whether the bytes sum to zero, which is how ACPI structures are checksummed
+fn checksum_valid(bytes: &[u8]) -> bool {
+    bytes.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte)) == 0
+}
+
+/// Searches `len` bytes from `start` on 16 byte boundaries for a valid RSDP
+fn find_rsdp(start: u64, len: usize) -> Option<&'static [u8]> {
+    let area = unsafe { phys_slice(start, len)? };
+    area.chunks_exact(16)
+        .enumerate()
+        .filter(|(_, chunk)| chunk.starts_with(RSDP_SIGNATURE))
+        .map(|(i, _)| &area[i * 16..])
+        .find(|rsdp| rsdp.len() >= 20 && checksum_valid(&rsdp[..20]))
+}
+
+/// Returns the table at physical address `phys` if its checksum is valid
+fn table_at(phys: u64) -> Option<&'static [u8]> {
+    let header = unsafe { phys_slice(phys, SDT_HEADER_SIZE)? };
+    let len = usize::try_from(read::<u32>(header, 4)?).ok()?;
+    let table = unsafe { phys_slice(phys, len.max(SDT_HEADER_SIZE))? };
+    checksum_valid(table).then_some(table)
+}
+
+/// Finds the RSDP and the root table it points to.
+///
+/// If no valid tables are found, find_table() will return None for every table,
+/// so drivers which depend on ACPI will not be initialised.
+pub fn init() {
+    let ebda = unsafe { phys_slice(EBDA_SEGMENT_PTR, 2) }
+        .and_then(|bytes| read::<u16>(bytes, 0))
+        .map(|segment| u64::from(segment) << 4);
+
+    // The RSDP is either in the first KiB of the EBDA, or in the main BIOS area
+    let rsdp = ebda
+        .and_then(|ebda| find_rsdp(ebda, 1024))
+        .or_else(|| find_rsdp(BIOS_AREA_START, (BIOS_AREA_END - BIOS_AREA_START) as usize));
+    let Some(rsdp) = rsdp else {
+        return;
+    };
+
+    // Use the XSDT if this is ACPI 2.0 or later, as it can hold 64 bit addresses
+    let revision = rsdp[15];
+    let root = if revision >= 2 {
+        read::<u64>(rsdp, 24)
+            .and_then(table_at)
+            .map(|table| RootTable {
+                table,
+                entry_size: 8,
+            })
+    } else {
+        None
+    };
+    let root = root.or_else(|| {
+        read::<u32>(rsdp, 16)
+            .and_then(|addr| table_at(u64::from(addr)))
+            .map(|table| RootTable {
+                table,
+                entry_size: 4,
+            })
+    });
+
+    if let Some(root) = root {
+        ROOT_TABLE.init_once(|| root);
+    }
+}
+
+/// Finds the table with the four byte `signature`, returning all of its bytes including the header
+pub fn find_table(signature: &[u8; 4]) -> Option<&'static [u8]> {
+    let root = ROOT_TABLE.try_get().ok()?;
+    root.table[SDT_HEADER_SIZE..]
+        .chunks_exact(root.entry_size)
+        .filter_map(|entry| match root.entry_size {
+            8 => read::<u64>(entry, 0),
+            _ => read::<u32>(entry, 0).map(u64::from),
+        })
+        .filter_map(table_at)
+        .find(|table| table.starts_with(signature))
+}
@@ -1,6 +1,8 @@
 //! This module provides a data type which implements the GlobalAlloc trait for use by the kernel
 
+use core::alloc::{GlobalAlloc, Layout};
 use linked_list_allocator::LockedHeap;
+use x86_64::instructions::interrupts::without_interrupts;
 use x86_64::{
     VirtAddr,
     structures::paging::{
@@ -15,12 +17,29 @@ pub mod linked_list;
 /// Starting address of heap region in virtual memory
 pub const HEAP_START: usize = 0x_4444_4444_0000;
 
-/// Size of heap (100 KiB)
-pub const HEAP_SIZE: usize = 100 * 1024;
+/// Size of heap (1 MiB), which also holds the stacks of kernel threads
+pub const HEAP_SIZE: usize = 1024 * 1024;
 
 // This attribute tells the Rust compiler that ALLOCATOR should be used as the heap allocator
 #[global_allocator]
-static ALLOCATOR: LockedHeap = LockedHeap::empty();
+static ALLOCATOR: IrqSafeHeap = IrqSafeHeap(LockedHeap::empty());
+
+/// A LockedHeap whose lock is only held with interrupts disabled.
+///
+/// Kernel threads are preempted from the timer interrupt, so without this a thread could be
+/// switched out while holding the lock, and the scheduler or an interrupt handler allocating
+/// with interrupts disabled would then spin on it forever.
+struct IrqSafeHeap(LockedHeap);
+
+unsafe impl GlobalAlloc for IrqSafeHeap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        without_interrupts(|| unsafe { self.0.alloc(layout) })
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        without_interrupts(|| unsafe { self.0.dealloc(ptr, layout) })
+    }
+}
 
 /// Initialises heap by allocating frames of physical memory,
 /// and mapping pages in the heap region to them
@@ -47,7 +66,7 @@ pub fn init_heap(
     }
 
     unsafe {
-        ALLOCATOR.lock().init(HEAP_START, HEAP_SIZE);
+        ALLOCATOR.0.lock().init(HEAP_START, HEAP_SIZE);
     }
 
     Ok(())
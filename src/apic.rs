@@ -0,0 +1,140 @@
+//! This module provides an interface to each CPU's local advanced programmable interrupt
+//! controller (local APIC).
+//!
+//! The kernel still receives device interrupts through the chained PICs, so the local APIC
+//! is only used to identify CPUs and to send inter-processor interrupts (IPIs), which is
+//! how the bootstrap processor starts the other CPUs.
+//!
+//! Every CPU's local APIC is at the same physical address, and each CPU only sees its own,
+//! so the registers are accessed through the bootloader's mapping of physical memory.
+
+use crate::{acpi, bytes, memory::phys_to_virt};
+use conquer_once::spin::OnceCell;
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::{PhysAddr, VirtAddr};
+
+/// Offset of the local APIC's physical address within the ACPI MADT
+const MADT_LOCAL_APIC_ADDRESS_OFFSET: usize = 36;
+
+/// Register offsets from the local APIC's base address
+const ID: u64 = 0x20;
+const EOI: u64 = 0xb0;
+const SPURIOUS_INTERRUPT_VECTOR: u64 = 0xf0;
+const INTERRUPT_COMMAND_LOW: u64 = 0x300;
+const INTERRUPT_COMMAND_HIGH: u64 = 0x310;
+
+/// Set in the spurious interrupt vector register to enable the local APIC
+const APIC_SOFTWARE_ENABLE: u32 = 1 << 8;
+
+/// Vector of spurious interrupts, which do not need an EOI
+pub const SPURIOUS_VECTOR: u8 = 0xff;
+
+/// Vector of the IPI sent to wake an idle CPU when it is given a Task to run
+pub const WAKE_VECTOR: u8 = 0xf0;
+
+/// Interrupt command register fields
+const DELIVERY_MODE_FIXED: u32 = 0b000 << 8;
+const DELIVERY_MODE_INIT: u32 = 0b101 << 8;
+const DELIVERY_MODE_STARTUP: u32 = 0b110 << 8;
+const DELIVERY_STATUS_PENDING: u32 = 1 << 12;
+const LEVEL_ASSERT: u32 = 1 << 14;
+
+/// The local APIC of whichever CPU accesses it
+pub struct LocalApic {
+    /// Virtual address of the local APIC's registers
+    base: VirtAddr,
+}
+
+/// The local APIC, which is found once by init()
+static LOCAL_APIC: OnceCell<LocalApic> = OnceCell::uninit();
+
+impl LocalApic {
+    /// Reads the 32 bit register at `offset` from the local APIC's base address
+    fn read(&self, offset: u64) -> u32 {
+        unsafe { (self.base + offset).as_ptr::<u32>().read_volatile() }
+    }
+
+    /// Writes `value` to the 32 bit register at `offset` from the local APIC's base address
+    fn write(&self, offset: u64, value: u32) {
+        unsafe {
+            (self.base + offset)
+                .as_mut_ptr::<u32>()
+                .write_volatile(value)
+        }
+    }
+
+    /// Returns the APIC ID of the current CPU
+    pub fn id(&self) -> u8 {
+        (self.read(ID) >> 24) as u8
+    }
+
+    /// Enables the current CPU's local APIC, so it can send and receive IPIs
+    pub fn enable(&self) {
+        let value = self.read(SPURIOUS_INTERRUPT_VECTOR);
+        self.write(
+            SPURIOUS_INTERRUPT_VECTOR,
+            value | APIC_SOFTWARE_ENABLE | u32::from(SPURIOUS_VECTOR),
+        );
+    }
+
+    /// Signals the end of an interrupt delivered by the local APIC, such as an IPI
+    pub fn end_of_interrupt(&self) {
+        self.write(EOI, 0);
+    }
+
+    /// Sends an interrupt command to the CPU with APIC ID `apic_id`, and waits until
+    /// the local APIC has delivered it
+    fn send_command(&self, apic_id: u8, command: u32) {
+        // Writing the low half of the ICR sends the IPI to the destination in the high half,
+        // so an interrupt handler which sent an IPI between the two writes would leave its
+        // own destination in the high half, and this IPI would go to the wrong CPU. Waiting
+        // for delivery is included, so a handler's IPI cannot overwrite a pending one.
+        without_interrupts(|| {
+            self.write(INTERRUPT_COMMAND_HIGH, u32::from(apic_id) << 24);
+            self.write(INTERRUPT_COMMAND_LOW, command);
+            while self.read(INTERRUPT_COMMAND_LOW) & DELIVERY_STATUS_PENDING != 0 {
+                core::hint::spin_loop();
+            }
+        })
+    }
+
+    /// Sends an INIT IPI, which resets the target CPU into a state where it waits for a startup IPI
+    pub fn send_init(&self, apic_id: u8) {
+        self.send_command(apic_id, DELIVERY_MODE_INIT | LEVEL_ASSERT);
+    }
+
+    /// Sends a startup IPI, which starts the target CPU in real mode at physical address
+    /// `page << 12`, so the startup code must be in the first MiB of memory
+    pub fn send_startup(&self, apic_id: u8, page: u8) {
+        self.send_command(
+            apic_id,
+            DELIVERY_MODE_STARTUP | LEVEL_ASSERT | u32::from(page),
+        );
+    }
+
+    /// Sends an interrupt with vector `vector` to the CPU with APIC ID `apic_id`
+    pub fn send_ipi(&self, apic_id: u8, vector: u8) {
+        self.send_command(
+            apic_id,
+            DELIVERY_MODE_FIXED | LEVEL_ASSERT | u32::from(vector),
+        );
+    }
+}
+
+/// Finds the local APIC through the ACPI MADT and enables the current CPU's local APIC.
+///
+/// Returns None if there is no MADT, in which case the system only has one CPU.
+pub fn init() -> Option<&'static LocalApic> {
+    let madt = acpi::find_table(b"APIC")?;
+    let phys = bytes::read::<u32>(madt, MADT_LOCAL_APIC_ADDRESS_OFFSET)?;
+    let base = phys_to_virt(PhysAddr::new(u64::from(phys)))?;
+
+    let local_apic = LOCAL_APIC.get_or_init(|| LocalApic { base });
+    local_apic.enable();
+    Some(local_apic)
+}
+
+/// Returns the local APIC, if init has found it
+pub fn local_apic() -> Option<&'static LocalApic> {
+    LOCAL_APIC.try_get().ok()
+}
@@ -0,0 +1,203 @@
+//! This module provides stack backtraces by walking the chain of saved frame pointers.
+//!
+//! The kernel target forces frame pointers on (see x86_64-rust_os.json), so every
+//! function's prologue pushes the caller's rbp, then points rbp at it. Each frame
+//! therefore starts with the previous frame pointer, followed by the return address.
+//!
+//! Frame pointers are only followed while they lie within the bounds of a registered stack,
+//! so a corrupted frame chain ends the backtrace rather than causing a page fault. A frame
+//! pointer outside every registered stack is not followed at all, as nothing says the
+//! memory it points to is mapped.
+
+use crate::symbols::Symbolized;
+use core::{arch::asm, fmt};
+use x86_64::VirtAddr;
+
+/// Maximum number of return addresses recorded in a Backtrace
+const MAX_FRAMES: usize = 32;
+
+/// Maximum number of stacks which can be registered at once
+const MAX_STACKS: usize = 64;
+
+/// Number of pages the bootloader maps for the kernel's boot stack
+const BOOT_STACK_PAGES: u64 = 512;
+
+/// The lowest and highest addresses of a stack. The stack grows downwards
+/// from top, and all of its frames lie in bottom..top.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StackBounds {
+    pub bottom: VirtAddr,
+    pub top: VirtAddr,
+}
+
+impl StackBounds {
+    /// Returns whether a frame (the saved frame pointer and return address) at `addr` lies on this stack
+    fn contains_frame(&self, addr: u64) -> bool {
+        addr >= self.bottom.as_u64() && addr.saturating_add(16) <= self.top.as_u64()
+    }
+}
+
+/// Table of the stacks which frame pointers may point into.
+///
+/// A fixed size array is used as backtraces are needed before the heap is
+/// initialised, and in the panic handler where allocating is unwise.
+static STACKS: spin::Mutex<[Option<StackBounds>; MAX_STACKS]> =
+    spin::Mutex::new([None; MAX_STACKS]);
+
+/// Registers a stack so backtraces can walk frames on it.
+///
+/// If the table is full the stack is not registered, and backtraces taken on it
+/// are empty.
+pub fn register_stack(bounds: StackBounds) {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut stacks = STACKS.lock();
+        if let Some(slot) = stacks.iter_mut().find(|slot| slot.is_none()) {
+            *slot = Some(bounds);
+        }
+    });
+}
+
+/// Removes a stack from the table, which must be done before its memory is freed
+pub fn unregister_stack(bounds: StackBounds) {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut stacks = STACKS.lock();
+        if let Some(slot) = stacks.iter_mut().find(|slot| **slot == Some(bounds)) {
+            *slot = None;
+        }
+    });
+}
+
+/// Registers the stack the bootloader created for the kernel.
+///
+/// The bootloader maps BOOT_STACK_PAGES pages and starts the kernel with rsp at the
+/// page aligned top of the stack. This must be called near the start of the kernel,
+/// while rsp is still in the top page of the stack.
+pub(crate) fn register_boot_stack() {
+    let top = VirtAddr::new(read_rsp()).align_up(4096u64);
+    register_stack(StackBounds {
+        bottom: top - BOOT_STACK_PAGES * 4096,
+        top,
+    });
+}
+
+/// Finds the registered stack which contains `addr`.
+///
+/// try_lock is used so that a panic raised while the table is locked
+/// does not deadlock the panic handler.
+fn find_stack(addr: u64) -> Option<StackBounds> {
+    let stacks = STACKS.try_lock()?;
+    stacks
+        .iter()
+        .flatten()
+        .find(|bounds| bounds.contains_frame(addr))
+        .copied()
+}
+
+/// Reads the current stack pointer
+#[inline(always)]
+fn read_rsp() -> u64 {
+    let rsp: u64;
+    unsafe { asm!("mov {}, rsp", out(reg) rsp, options(nomem, nostack, preserves_flags)) };
+    rsp
+}
+
+/// Reads the current frame pointer
+#[inline(always)]
+fn read_rbp() -> u64 {
+    let rbp: u64;
+    unsafe { asm!("mov {}, rbp", out(reg) rbp, options(nomem, nostack, preserves_flags)) };
+    rbp
+}
+
+/// A list of return addresses, innermost frame first
+#[derive(Debug, Clone, Copy)]
+pub struct Backtrace {
+    frames: [u64; MAX_FRAMES],
+    len: usize,
+
+    /// The first frame pointer was outside every registered stack, so it was not followed
+    unknown_stack: bool,
+}
+
+impl Backtrace {
+    /// Captures a backtrace of the caller's stack
+    #[inline(always)]
+    pub fn capture() -> Backtrace {
+        Backtrace::from_frame_pointer(read_rbp())
+    }
+
+    /// Walks the frame chain starting at the frame pointer `rbp`.
+    ///
+    /// The walk stops at a null return address, at the first frame pointer which is
+    /// outside the stack containing `rbp`, or which does not move towards the top of the
+    /// stack (which would mean the chain loops). If `rbp` is in no registered stack, for
+    /// example because it was corrupted, the backtrace is empty.
+    pub fn from_frame_pointer(rbp: u64) -> Backtrace {
+        let mut backtrace = Backtrace {
+            frames: [0; MAX_FRAMES],
+            len: 0,
+            unknown_stack: false,
+        };
+
+        let Some(bounds) = find_stack(rbp) else {
+            backtrace.unknown_stack = true;
+            return backtrace;
+        };
+
+        let mut frame_pointer = rbp;
+        while backtrace.len < MAX_FRAMES
+            && frame_pointer.is_multiple_of(8)
+            && bounds.contains_frame(frame_pointer)
+        {
+            // The frame lies within the stack, so both of these reads are of mapped memory
+            let (next, return_address) = unsafe {
+                let frame = frame_pointer as *const u64;
+                (frame.read_volatile(), frame.add(1).read_volatile())
+            };
+            if return_address == 0 {
+                break;
+            }
+            backtrace.frames[backtrace.len] = return_address;
+            backtrace.len += 1;
+
+            if next <= frame_pointer {
+                break;
+            }
+            frame_pointer = next;
+        }
+
+        backtrace
+    }
+
+    /// Returns the captured return addresses, innermost frame first
+    pub fn frames(&self) -> &[u64] {
+        &self.frames[..self.len]
+    }
+}
+
+impl fmt::Display for Backtrace {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Backtrace:")?;
+        if self.unknown_stack {
+            write!(f, " frame pointer outside known stacks")?;
+        }
+        for (i, address) in self.frames().iter().enumerate() {
+            write!(f, "\n  #{:<2} {}", i, Symbolized(*address))?;
+        }
+        Ok(())
+    }
+}
+
+/// Prints the backtrace to both the VGA text buffer and the serial port
+pub fn print_backtrace(backtrace: &Backtrace) {
+    crate::println!("{}", backtrace);
+    crate::serial_println!("{}", backtrace);
+}
+
+/// Tests that a frame pointer outside every registered stack is not followed
+#[test_case]
+fn test_unknown_stack_is_not_walked() {
+    let backtrace = Backtrace::from_frame_pointer(0xdead_beef_0000);
+    assert!(backtrace.frames().is_empty());
+    assert!(backtrace.unknown_stack);
+}
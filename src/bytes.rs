@@ -0,0 +1,18 @@
+//! This module reads fixed-layout values out of byte slices, such as the firmware and ELF
+//! structures which the kernel parses in place.
+
+use core::{mem, ptr};
+
+/// Reads a value of type T at `offset` in `bytes`, returning None if it would be out of bounds
+///
+/// T should be a plain data type for which every bit pattern is valid, such as an integer or a
+/// `#[repr(C)]` struct of integers.
+pub fn read<T: Copy>(bytes: &[u8], offset: usize) -> Option<T> {
+    let end = offset.checked_add(mem::size_of::<T>())?;
+    if end > bytes.len() {
+        return None;
+    }
+
+    // The bounds were checked above, and the structures read are not guaranteed to be aligned
+    Some(unsafe { ptr::read_unaligned(bytes.as_ptr().add(offset) as *const T) })
+}
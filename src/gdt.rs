@@ -4,7 +4,12 @@
 //! The kernel's GDT contains a reference to the kernel's task state segment
 //! (TSS) which contains an interrupt stack table (IST) in which a known good
 //! stack is created for use by the double fault handler.
+//!
+//! A CPU's TSS must not be loaded by any other CPU, so the bootstrap processor uses
+//! the static GDT and TSS, and each application processor gets its own from init_ap().
 
+use crate::backtrace::{self, StackBounds};
+use alloc::boxed::Box;
 use lazy_static::lazy_static;
 use x86_64::VirtAddr;
 use x86_64::structures::gdt::{Descriptor, GlobalDescriptorTable, SegmentSelector};
@@ -13,6 +18,21 @@ use x86_64::structures::tss::TaskStateSegment;
 /// The double fault handler will use the first stack defined in the IST
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
 
+/// Size of the known good stack used by the double fault handler
+const DOUBLE_FAULT_STACK_SIZE: usize = 4096 * 5;
+
+// Currently using static mut for stack allocation until proper stack allocation is implemented.
+static mut DOUBLE_FAULT_STACK: [u8; DOUBLE_FAULT_STACK_SIZE] = [0; DOUBLE_FAULT_STACK_SIZE];
+
+/// Returns the bounds of the double fault handler's stack
+fn double_fault_stack_bounds() -> StackBounds {
+    let bottom = VirtAddr::from_ptr(&raw const DOUBLE_FAULT_STACK);
+    StackBounds {
+        bottom,
+        top: bottom + DOUBLE_FAULT_STACK_SIZE,
+    }
+}
+
 /// Segment selectors for a code segment and TSS.
 ///
 /// This will be used to load the CS register and task register
@@ -30,14 +50,8 @@ struct Selectors {
 lazy_static! {
     static ref TSS: TaskStateSegment = {
         let mut tss = TaskStateSegment::new();
-        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-            const STACK_SIZE: usize = 4096 * 5;
-            // Currently using static mut for stack allocation until proper stack allocation is implemented.
-            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
-
-            let stack_start = VirtAddr::from_ptr(&raw const STACK);
-            stack_start + STACK_SIZE
-        };
+        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] =
+            double_fault_stack_bounds().top;
         tss
     };
 }
@@ -58,11 +72,18 @@ lazy_static! {
     };
 }
 
-/// Loads the GDT onto the CPU.
+/// Returns the bootstrap processor's TSS
+pub fn bsp_tss() -> &'static TaskStateSegment {
+    &TSS
+}
+
+/// Loads the GDT onto the CPU, and registers the double fault
+/// handler's stack so backtraces can be taken on it.
 pub fn init() {
     use x86_64::instructions::segmentation::{CS, Segment};
     use x86_64::instructions::tables::load_tss;
 
+    backtrace::register_stack(double_fault_stack_bounds());
     GDT.0.load();
 
     // Update the state of the CS and task registers to refer
@@ -72,3 +93,32 @@ pub fn init() {
         load_tss(GDT.1.tss_selector);
     }
 }
+
+/// Creates a GDT and TSS for an application processor, using the given stack for double
+/// faults, and loads them onto the current CPU.
+///
+/// The GDT and TSS are leaked, as they must live for as long as the CPU runs.
+/// Returns the TSS, so it can be recorded in the CPU's per-CPU data.
+pub fn init_ap(double_fault_stack: StackBounds) -> &'static TaskStateSegment {
+    use x86_64::instructions::segmentation::{CS, Segment};
+    use x86_64::instructions::tables::load_tss;
+
+    let tss: &'static TaskStateSegment = Box::leak(Box::new({
+        let mut tss = TaskStateSegment::new();
+        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = double_fault_stack.top;
+        tss
+    }));
+
+    let mut gdt = GlobalDescriptorTable::new();
+    let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
+    let tss_selector = gdt.add_entry(Descriptor::tss_segment(tss));
+    let gdt: &'static GlobalDescriptorTable = Box::leak(Box::new(gdt));
+
+    backtrace::register_stack(double_fault_stack);
+    gdt.load();
+    unsafe {
+        CS::set_reg(code_selector);
+        load_tss(tss_selector);
+    }
+    tss
+}
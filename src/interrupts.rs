@@ -1,45 +1,35 @@
 //! This module creates an interrupt descriptor table (IDT)
 //! and loads it on the CPU.
 //!
-//! Currently the only exception which the IDT handles
-//! is the breakpoint exception.
-
-use crate::{gdt, hlt_loop, print, println, task::keyboard::add_scancode};
+//! Fatal exceptions enter through assembly stubs (see registers.rs)
+//! which save the register file for the crash report. Hardware interrupts
+//! are dispatched to handlers registered at runtime (see irq.rs).
+
+use crate::apic;
+use crate::backtrace::{self, Backtrace};
+use crate::registers::{self, RegisterDump, fatal_exception_stub_with_error_code};
+use crate::serial::{self, ComPort};
+use crate::symbols::Symbolized;
+use crate::sync::IrqSafeMutex;
+use crate::task::{keyboard::add_scancode, serial::add_received_byte};
+use crate::{gdt, hlt_loop, print, println, serial_println};
 use lazy_static::lazy_static;
 use pic8259::ChainedPics;
-use spin;
+use x86_64::VirtAddr;
 use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
 
+pub mod irq;
+
 /// PIC1 will send interrupt vector indices 32-39
 pub const PIC_1_OFFSET: u8 = 32;
 
 /// PIC2 will send interrupt vector indices 40-47
 pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
 
-/// Address of PS/2 controller's data port
-const PS2_DATA_PORT_ADDR: u16 = 0x60;
-
-/// Index to the IDT
-#[derive(Debug, Clone, Copy)]
-#[repr(u8)]
-pub enum InterruptIndex {
-    Timer = PIC_1_OFFSET,
-    Keyboard,
-}
-
-impl InterruptIndex {
-    fn as_u8(self) -> u8 {
-        self as u8
-    }
-
-    fn as_usize(self) -> usize {
-        usize::from(self.as_u8())
-    }
-}
-
-/// Spinlock protected interface to 2 chained programmable interrupt controllers (PICs)
-pub static PICS: spin::Mutex<ChainedPics> =
-    spin::Mutex::new(unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) });
+/// Spinlock protected interface to 2 chained programmable interrupt controllers (PICs),
+/// which disables interrupts while held as the IRQ handlers use it to send EOIs
+pub static PICS: IrqSafeMutex<ChainedPics> =
+    IrqSafeMutex::new(unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) });
 
 // Create IDT and set its breakpoint handler to the breakpoint_handler function
 lazy_static! {
@@ -47,15 +37,16 @@ lazy_static! {
         let mut idt = InterruptDescriptorTable::new();
         idt.breakpoint.set_handler_fn(breakpoint_handler);
 
-        // Double fault handler uses known good stack in the IST
+        // Double fault handler uses known good stack in the IST. Its entry point is an
+        // assembly stub which saves the registers before calling double_fault_handler
         unsafe {
             idt.double_fault
-                .set_handler_fn(double_fault_handler)
+                .set_handler_addr(VirtAddr::new(double_fault_entry as *const () as u64))
                 .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
         }
-        idt[InterruptIndex::Timer.as_usize()]
-            .set_handler_fn(timer_interrupt_handler);
-        idt[InterruptIndex::Keyboard.as_usize()].set_handler_fn(keyboard_interrupt_handler);
+        irq::set_idt_entries(&mut idt);
+        idt[usize::from(apic::SPURIOUS_VECTOR)].set_handler_fn(spurious_interrupt_handler);
+        idt[usize::from(apic::WAKE_VECTOR)].set_handler_fn(wake_interrupt_handler);
         idt.page_fault.set_handler_fn(page_fault_handler);
         idt
     };
@@ -66,6 +57,17 @@ pub fn init_idt() {
     IDT.load();
 }
 
+/// Initialise the PICs with every IRQ line masked, then register the
+/// kernel's built-in handlers, which unmasks their lines
+pub fn init_irqs() {
+    unsafe { PICS.lock().initialize() };
+    irq::mask_all();
+    irq::register(irq::TIMER, timer_interrupt_handler).expect("timer IRQ line full");
+    irq::register(irq::KEYBOARD, keyboard_interrupt_handler).expect("keyboard IRQ line full");
+    irq::register(irq::COM1, serial_interrupt_handler).expect("COM1 IRQ line full");
+    irq::register(irq::COM2, serial_interrupt_handler).expect("COM2 IRQ line full");
+}
+
 /// Handles breakpoint exception by pretty printing the stack frame.
 ///
 /// Handling exceptions does not require the use of naked functions as
@@ -74,43 +76,60 @@ extern "x86-interrupt" fn breakpoint_handler(stack_frame: InterruptStackFrame) {
     println!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
 }
 
-/// Handles double fault by pretty printing the stack frame using the panic macro.
+// Assembly entry point for double faults which saves the register file, then calls double_fault_handler
+fatal_exception_stub_with_error_code!(double_fault_entry, double_fault_handler);
+
+/// Handles spurious interrupts from the local APIC, which must not be acknowledged with an EOI
+extern "x86-interrupt" fn spurious_interrupt_handler(_stack_frame: InterruptStackFrame) {}
+
+/// Handles the IPI which wakes an idle CPU. Waking from hlt is all it is for,
+/// so it only acknowledges the interrupt
+extern "x86-interrupt" fn wake_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    if let Some(local_apic) = apic::local_apic() {
+        local_apic.end_of_interrupt();
+    }
+}
+
+/// Handles double fault by printing the registers saved by double_fault_entry and a
+/// backtrace of the interrupted code, then halting.
 ///
-/// The panic macro is used as this function is diverging as x86-64 does
-/// not allow double fault handlers to return.
-extern "x86-interrupt" fn double_fault_handler(
-    stack_frame: InterruptStackFrame,
-    _error_code: u64,
-) -> ! {
-    panic!("EXCEPTION: DOUBLE FAULT\n{:#?}", stack_frame);
+/// x86-64 does not allow double fault handlers to return. The handler halts rather than
+/// panicking, as the panic handler would print a second register dump and backtrace, of
+/// this handler rather than of the code which faulted.
+extern "C" fn double_fault_handler(registers: &RegisterDump, error_code: u64) -> ! {
+    println!("EXCEPTION: DOUBLE FAULT (error code {:#x})", error_code);
+    serial_println!("EXCEPTION: DOUBLE FAULT (error code {:#x})", error_code);
+    registers::print_dump(registers);
+    backtrace::print_backtrace(&Backtrace::from_frame_pointer(registers.rbp));
+    hlt_loop()
 }
 
-/// Timer interrupt handler
-extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: InterruptStackFrame) {
+/// Timer interrupt handler, which wakes sleeping Tasks and drives the thread scheduler
+fn timer_interrupt_handler() {
     print!(".");
-
-    // Send 'end-of-interrupt' (EOI) signal to PIC, so it knows the interrupt has been
-    // processed, and that it can send more.
-    unsafe {
-        PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::Timer.as_u8());
-    }
+    crate::task::timer::tick();
+    crate::thread::tick();
 }
 
 /// Keyboard interrupt handler which handles the user entering keys by adding the scancode to a queue
-extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStackFrame) {
-    use x86_64::instructions::port::Port;
-
+fn keyboard_interrupt_handler() {
     // Read scancode which can be used to determine which key was pressed.
     // The PS2 keyboard controller will not send another interrupt until the scancode has been read.
-    let mut port = Port::new(PS2_DATA_PORT_ADDR);
-    let scancode: u8 = unsafe { port.read() };
-    add_scancode(scancode);
-
-    // Send EOI signal to notify PIC that the interrupt has been handled
-    unsafe {
-        PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::Keyboard.as_u8());
+    if let Some(scancode) = crate::ps2::read_keyboard_byte() {
+        add_scancode(scancode);
+    }
+}
+
+/// Serial interrupt handler which handles received bytes by adding them to their port's queue
+///
+/// Two ports share each IRQ line, so every port is checked, rather than only those on the line.
+fn serial_interrupt_handler() {
+    // Read every byte in the receive FIFOs, as a port only raises the interrupt again once
+    // more bytes are received
+    for port in ComPort::ALL {
+        while let Some(byte) = serial::try_receive(port) {
+            add_received_byte(port, byte);
+        }
     }
 }
 
@@ -124,6 +143,10 @@ extern "x86-interrupt" fn page_fault_handler(
     println!("EXCEPTION: PAGE FAULT");
     println!("Accessed Address: {:?}", Cr2::read());
     println!("Error Code: {:?}", error_code);
+    println!(
+        "Instruction: {}",
+        Symbolized(stack_frame.instruction_pointer.as_u64())
+    );
     println!("{:#?}", stack_frame);
 
     // Loops forever as this handler does not actually resolve the page fault, therefore execution cannot continue
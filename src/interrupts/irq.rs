@@ -0,0 +1,210 @@
+//! This module provides a runtime registry of hardware interrupt (IRQ) handlers.
+//!
+//! Every IRQ line of the chained PICs has a generic IDT entry which calls each handler
+//! registered for that line, then sends the end-of-interrupt (EOI) signal. Drivers therefore
+//! register a handler with register() rather than editing the IDT. Several handlers can be
+//! registered on one line, in which case they are all called in registration order, as a
+//! shared line gives no indication of which device raised the interrupt.
+
+use super::{PIC_1_OFFSET, PICS};
+use crate::sync::IrqSafeRwLock;
+use alloc::boxed::Box;
+use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
+
+/// Number of IRQ lines provided by the two chained PICs
+pub const IRQ_LINES: usize = 16;
+
+/// Maximum number of handlers which can share a single IRQ line
+const MAX_HANDLERS_PER_LINE: usize = 4;
+
+/// IRQ line of the programmable interval timer
+pub const TIMER: u8 = 0;
+
+/// IRQ line of the PS/2 keyboard
+pub const KEYBOARD: u8 = 1;
+
+/// IRQ line on the primary PIC which the secondary PIC is chained to
+pub const CASCADE: u8 = 2;
+
+/// IRQ line of the COM2 and COM4 serial ports
+pub const COM2: u8 = 3;
+
+/// IRQ line of the COM1 and COM3 serial ports
+pub const COM1: u8 = 4;
+
+/// An interrupt handler. These are called with interrupts disabled, so
+/// they must not block, and should not allocate.
+type Handler = Box<dyn Fn() + Send + Sync>;
+
+/// The handlers registered on each IRQ line
+///
+/// Handlers are stored in fixed size arrays rather than a Vec, as the built-in
+/// handlers are registered before the heap is initialised.
+static HANDLERS: IrqSafeRwLock<[[Option<Handler>; MAX_HANDLERS_PER_LINE]; IRQ_LINES]> = {
+    const EMPTY_SLOT: Option<Handler> = None;
+    const EMPTY_LINE: [Option<Handler>; MAX_HANDLERS_PER_LINE] =
+        [EMPTY_SLOT; MAX_HANDLERS_PER_LINE];
+    IrqSafeRwLock::new([EMPTY_LINE; IRQ_LINES])
+};
+
+/// Errors which can occur when registering an IRQ handler
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IrqError {
+    /// The IRQ line is not one of the 16 lines of the chained PICs
+    InvalidLine(u8),
+
+    /// MAX_HANDLERS_PER_LINE handlers are already registered on the IRQ line
+    LineFull(u8),
+}
+
+/// Identifies a registered handler so it can be unregistered.
+///
+/// It is deliberately not Clone, so unregister consumes the only HandlerId of a handler,
+/// and cannot remove a handler registered later in the same slot.
+#[derive(Debug, PartialEq, Eq)]
+pub struct HandlerId {
+    irq: u8,
+    slot: usize,
+}
+
+impl HandlerId {
+    /// The IRQ line the handler is registered on
+    pub fn irq(&self) -> u8 {
+        self.irq
+    }
+}
+
+/// Registers `handler` to be called whenever IRQ line `irq` is raised, and unmasks the line.
+///
+/// Handlers run in interrupt context, so they must not block or register/unregister handlers,
+/// as this would deadlock on the registry lock. Boxing a function item or a closure which
+/// captures nothing does not allocate, so such handlers can be registered before the heap
+/// is initialised.
+pub fn register(
+    irq: u8,
+    handler: impl Fn() + Send + Sync + 'static,
+) -> Result<HandlerId, IrqError> {
+    if usize::from(irq) >= IRQ_LINES {
+        return Err(IrqError::InvalidLine(irq));
+    }
+
+    // The registry lock disables interrupts, so an interrupt handler cannot deadlock on it
+    let mut handlers = HANDLERS.write();
+    let line = &mut handlers[usize::from(irq)];
+    let slot = line
+        .iter()
+        .position(Option::is_none)
+        .ok_or(IrqError::LineFull(irq))?;
+    line[slot] = Some(Box::new(handler));
+    set_masked(irq, false);
+    Ok(HandlerId { irq, slot })
+}
+
+/// Unregisters a handler, and masks its IRQ line if no other handlers are registered on it
+pub fn unregister(id: HandlerId) {
+    let mut handlers = HANDLERS.write();
+    let line = &mut handlers[usize::from(id.irq)];
+    line[id.slot] = None;
+    if line.iter().all(Option::is_none) {
+        set_masked(id.irq, true);
+    }
+}
+
+/// Masks or unmasks an IRQ line on the PICs.
+///
+/// Unmasking a line on the secondary PIC also unmasks the cascade line, as
+/// the secondary PIC's interrupts are delivered through it.
+fn set_masked(irq: u8, masked: bool) {
+    let mut pics = PICS.lock();
+    let mut masks = unsafe { pics.read_masks() };
+    let (pic, bit) = (usize::from(irq / 8), irq % 8);
+    if masked {
+        masks[pic] |= 1 << bit;
+    } else {
+        masks[pic] &= !(1 << bit);
+        if pic == 1 {
+            masks[0] &= !(1 << CASCADE);
+        }
+    }
+    unsafe { pics.write_masks(masks[0], masks[1]) };
+}
+
+/// Masks every IRQ line except the cascade line, so that only lines
+/// with a registered handler raise interrupts
+pub(super) fn mask_all() {
+    unsafe { PICS.lock().write_masks(!(1 << CASCADE), 0xff) };
+}
+
+/// Calls every handler registered on IRQ line `irq`, then sends the EOI signal to the PICs
+fn dispatch(irq: u8) {
+    for handler in HANDLERS.read()[usize::from(irq)].iter().flatten() {
+        handler();
+    }
+
+    // Send 'end-of-interrupt' (EOI) signal to PIC, so it knows the interrupt has been
+    // processed, and that it can send more.
+    unsafe {
+        PICS.lock().notify_end_of_interrupt(PIC_1_OFFSET + irq);
+    }
+}
+
+/// Generic IDT entry for IRQ line IRQ
+extern "x86-interrupt" fn irq_stub<const IRQ: u8>(_stack_frame: InterruptStackFrame) {
+    dispatch(IRQ);
+
+    // The interrupt has been acknowledged, so it is now safe to switch threads
+    crate::thread::preempt_if_requested();
+}
+
+/// Points the IDT entries of all 16 IRQ lines at their generic stubs
+pub(super) fn set_idt_entries(idt: &mut InterruptDescriptorTable) {
+    let stubs: [extern "x86-interrupt" fn(InterruptStackFrame); IRQ_LINES] = [
+        irq_stub::<0>,
+        irq_stub::<1>,
+        irq_stub::<2>,
+        irq_stub::<3>,
+        irq_stub::<4>,
+        irq_stub::<5>,
+        irq_stub::<6>,
+        irq_stub::<7>,
+        irq_stub::<8>,
+        irq_stub::<9>,
+        irq_stub::<10>,
+        irq_stub::<11>,
+        irq_stub::<12>,
+        irq_stub::<13>,
+        irq_stub::<14>,
+        irq_stub::<15>,
+    ];
+    for (irq, stub) in stubs.into_iter().enumerate() {
+        idt[usize::from(PIC_1_OFFSET) + irq].set_handler_fn(stub);
+    }
+}
+
+/// Tests that registering a handler on a line which does not exist fails
+#[test_case]
+fn test_register_invalid_line() {
+    let result = register(IRQ_LINES as u8, || {});
+    assert_eq!(result, Err(IrqError::InvalidLine(IRQ_LINES as u8)));
+}
+
+/// Tests that a software interrupt on an IRQ vector is dispatched to a registered handler,
+/// and is no longer dispatched to it once it is unregistered
+#[test_case]
+fn test_dispatch_to_registered_handler() {
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    static CALLS: AtomicUsize = AtomicUsize::new(0);
+    const TEST_IRQ: u8 = 5;
+
+    let id = register(TEST_IRQ, || {
+        CALLS.fetch_add(1, Ordering::SeqCst);
+    })
+    .expect("failed to register test handler");
+    unsafe { core::arch::asm!("int {}", const PIC_1_OFFSET + TEST_IRQ) };
+    assert_eq!(CALLS.load(Ordering::SeqCst), 1);
+
+    unregister(id);
+    unsafe { core::arch::asm!("int {}", const PIC_1_OFFSET + TEST_IRQ) };
+    assert_eq!(CALLS.load(Ordering::SeqCst), 1);
+}
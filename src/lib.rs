@@ -14,12 +14,24 @@
 // Link this crate with the alloc crate
 extern crate alloc;
 
+pub mod acpi;
 pub mod allocator;
+pub mod apic;
+pub mod backtrace;
+pub mod bytes;
 pub mod gdt;
 pub mod interrupts;
 pub mod memory;
+pub mod percpu;
+pub mod ps2;
+pub mod registers;
 pub mod serial;
+pub mod smp;
+pub mod symbols;
+pub mod sync;
 pub mod task;
+pub mod thread;
+pub mod time;
 pub mod vga;
 
 use core::panic::PanicInfo;
@@ -32,9 +44,18 @@ const ISA_DEBUG_EXIT_PORT: u16 = 0xf4;
 
 /// General kernel initialisation function
 pub fn init() {
+    // Record the boot stack's bounds while the kernel is still near its top
+    backtrace::register_boot_stack();
     gdt::init();
+    percpu::init(0, gdt::bsp_tss());
     interrupts::init_idt();
-    unsafe { interrupts::PICS.lock().initialize() };
+    interrupts::init_irqs();
+
+    // The keyboard still works with the firmware's setup if the controller does not
+    // initialise, as long as there is one
+    if let Err(err) = ps2::init() {
+        println!("WARNING: PS/2 controller initialisation failed: {:?}", err);
+    }
     x86_64::instructions::interrupts::enable();
 }
 
@@ -68,10 +89,12 @@ pub fn test_runner(tests: &[&dyn Testable]) {
     exit_qemu(QemuExitCode::Success);
 }
 
-/// Prints failure information to the serial port, and exits QEMU with a failure
+/// Prints failure information and a backtrace to the serial port, and exits QEMU with a failure
 pub fn test_panic_handler(info: &PanicInfo) -> ! {
+    let backtrace = backtrace::Backtrace::capture();
     serial_println!("[failed]\n");
     serial_println!("Error: {}\n", info);
+    serial_println!("{}\n", backtrace);
 
     // Exit QEMU with a failure code
     exit_qemu(QemuExitCode::Failed);
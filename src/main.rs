@@ -22,18 +22,27 @@ use alloc::vec;
 use alloc::{boxed::Box, rc::Rc, vec::Vec};
 use bootloader::{BootInfo, entry_point};
 use core::panic::PanicInfo;
-use rust_os::task::Task;
+use rust_os::task::console::run_serial_console;
 use rust_os::task::executor::Executor;
-use rust_os::task::keyboard::print_keypresses;
+use rust_os::task::keyboard::{print_keypresses, run_keyboard_service};
 use rust_os::{allocator, memory::BootInfoFrameAllocator, println};
 use x86_64::structures::paging::Page;
 
 /// This is a custom panic handler, as we do not have access to the default
-/// one in the standard library. This panic handler just loops forever.
+/// one in the standard library. This panic handler prints the panic message, the
+/// register file and a backtrace to the VGA buffer and serial port, then loops forever.
 #[cfg(not(test))]
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
+    use rust_os::backtrace::{self, Backtrace};
+    use rust_os::registers::{self, RegisterDump};
+
+    // Capture the registers first, before printing clobbers them
+    let registers = RegisterDump::capture();
     println!("{info}");
+    rust_os::serial_println!("{}", info);
+    registers::print_dump(&registers);
+    backtrace::print_backtrace(&Backtrace::capture());
     rust_os::hlt_loop()
 }
 
@@ -61,7 +70,7 @@ entry_point!(kernel_main);
 /// Entry point for the freestanding kernel executable. It takes a BootInfo struct
 /// from the bootloader as an argument.
 fn kernel_main(boot_info: &'static BootInfo) -> ! {
-    use rust_os::memory;
+    use rust_os::{acpi, memory, smp, symbols, time};
     use x86_64::VirtAddr;
 
     // Invokes the vga module's println! macro to write "Hello world!" to the VGA text buffer
@@ -79,6 +88,13 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
     // contexts where the entirety of physical memory is mapped into virtual memory
     let mut mapper = unsafe { memory::init(phys_mem_offset) };
 
+    // Locate the kernel's symbol table so crash reports can print function names
+    unsafe { symbols::init(&boot_info.memory_map, phys_mem_offset) };
+
+    // Find the ACPI tables, then initialise the HPET and calibrate the TSC
+    acpi::init();
+    time::init();
+
     // Use BootInfoFrameAllocator which actually allocates unused physical frames, preventing the frame
     // allocation failure when the kernel tries to create page tables
     let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
@@ -98,6 +114,16 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
 
     allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
 
+    // Start the other CPUs, which wait with interrupts enabled until they are given work
+    match smp::init(&mut mapper, &mut frame_allocator, &boot_info.memory_map) {
+        Ok(online) => println!("{} of {} CPUs online", online, smp::cpu_count()),
+        Err(err) => println!(
+            "CPU startup stopped: {:?}, {} CPUs online",
+            err,
+            smp::online_cpus()
+        ),
+    }
+
     // allocate a number on the heap
     let heap_value = Box::new(41);
     println!("heap_value at {:p}", heap_value);
@@ -122,9 +148,13 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
         Rc::strong_count(&cloned_reference)
     );
 
-    // Create executor, and pass it the example_task and print_keypresses functions wrapped in Tasks for it to execute
+    // Create executor, and spawn the example_task and print_keypresses functions as Tasks for it to execute,
+    // along with the keyboard service which print_keypresses subscribes to, and the console on COM1
     let mut executor = Executor::new();
-    executor.spawn(Task::new(example_task()));
-    executor.spawn(Task::new(print_keypresses()));
+    executor.spawn(example_task());
+    executor.spawn_named("keyboard", run_keyboard_service());
+    executor.spawn(print_keypresses());
+    let spawner = executor.spawner();
+    executor.spawn_named("console", run_serial_console(spawner));
     executor.run();
 }
@@ -1,6 +1,7 @@
 //! This module contains functions which deal with paging and memory allocation
 
 use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use conquer_once::spin::OnceCell;
 use x86_64::{
     PhysAddr, VirtAddr,
     structures::paging::{
@@ -53,6 +54,10 @@ unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
     }
 }
 
+/// Virtual address at which the bootloader maps the entirety of physical memory.
+/// It is recorded by init() so drivers can access memory mapped devices.
+static PHYSICAL_MEMORY_OFFSET: OnceCell<VirtAddr> = OnceCell::uninit();
+
 /// Initialize a new OffsetPageTable.
 ///
 /// # Safety
@@ -62,12 +67,23 @@ unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
 /// `physical_memory_offset`. Also, this function must be only called once
 /// to avoid aliasing `&mut` references (which is undefined behavior).
 pub unsafe fn init(physical_memory_offset: VirtAddr) -> OffsetPageTable<'static> {
+    PHYSICAL_MEMORY_OFFSET.init_once(|| physical_memory_offset);
     unsafe {
         let level_4_table = active_level_4_table(physical_memory_offset);
         OffsetPageTable::new(level_4_table, physical_memory_offset)
     }
 }
 
+/// Returns the virtual address at which the physical address `phys` is mapped,
+/// or None if memory::init has not been called yet.
+///
+/// The bootloader maps physical memory up to the highest address in its memory map,
+/// which includes the memory mapped devices (such as the HPET and APIC) below 4 GiB.
+pub fn phys_to_virt(phys: PhysAddr) -> Option<VirtAddr> {
+    let offset = PHYSICAL_MEMORY_OFFSET.try_get().ok()?;
+    Some(*offset + phys.as_u64())
+}
+
 /// Returns a mutable reference to the active level 4 table.
 ///
 /// This function is unsafe because the caller must guarantee that the
@@ -0,0 +1,210 @@
+//! This module provides a data area for each CPU, which is found through the CPU's GS base.
+//!
+//! Each CPU's IA32_GS_BASE MSR points at its own PerCpu struct, whose first field points back
+//! at the struct, so the current CPU's data is found with a single `mov rax, gs:[0]`. The
+//! IA32_KERNEL_GS_BASE MSR is given the same address, so a `swapgs` on entry to the kernel
+//! leaves the data reachable whichever of the two bases was active.
+//!
+//! The per_cpu! macro accesses a field or method of the current CPU's data, for example
+//! `per_cpu!(cpu_id)` or `per_cpu!(run_queue())`.
+
+use crate::{
+    smp::MAX_CPUS,
+    task::{TaskId, task_local::TaskLocals},
+};
+use alloc::boxed::Box;
+use conquer_once::spin::OnceCell;
+use core::{
+    arch::asm,
+    ptr,
+    sync::atomic::{AtomicBool, AtomicPtr, AtomicU16, AtomicU64, Ordering},
+};
+use crossbeam_queue::ArrayQueue;
+use x86_64::{
+    VirtAddr,
+    registers::model_specific::{GsBase, KernelGsBase},
+    structures::tss::TaskStateSegment,
+};
+
+/// Maximum number of TaskIds in a CPU's local run queue
+pub const RUN_QUEUE_CAPACITY: usize = 100;
+
+/// Value of current_task when the CPU is not polling a Task
+const NO_TASK: u64 = u64::MAX;
+
+/// Value of apic_id before the CPU's local APIC has been enabled
+const NO_APIC_ID: u16 = u16::MAX;
+
+/// Data belonging to a single CPU.
+///
+/// It is only ever accessed through shared references, so fields which change
+/// use atomics, which also keeps them consistent when read by other CPUs.
+#[repr(C)]
+pub struct PerCpu {
+    /// Address of this struct, which must be the first field so that `gs:[0]` reads it
+    this: AtomicPtr<PerCpu>,
+
+    /// Index of this CPU, which is 0 for the bootstrap processor
+    pub cpu_id: usize,
+
+    /// The TSS loaded on this CPU
+    pub tss: &'static TaskStateSegment,
+
+    /// APIC ID of this CPU's local APIC, or NO_APIC_ID
+    apic_id: AtomicU16,
+
+    /// TaskId of the Task this CPU is polling, or NO_TASK
+    current_task: AtomicU64,
+
+    /// Task-local values of the Task this CPU is polling, or null
+    task_locals: AtomicPtr<TaskLocals>,
+
+    /// Whether this CPU is halted waiting for work, so it must be sent an IPI to run new Tasks
+    idle: AtomicBool,
+
+    /// Queue of the TaskIds of ready Tasks which this CPU will run. It is allocated on first
+    /// use, so the bootstrap processor's data can be set up before the heap is.
+    run_queue: OnceCell<ArrayQueue<TaskId>>,
+}
+
+/// The bootstrap processor's data, which is static as it is set up before the heap
+static BSP: OnceCell<PerCpu> = OnceCell::uninit();
+
+/// Every CPU's data indexed by CPU id, so CPUs can find each other's run queues
+static CPUS: [AtomicPtr<PerCpu>; MAX_CPUS] = [const { AtomicPtr::new(ptr::null_mut()) }; MAX_CPUS];
+
+impl PerCpu {
+    fn new(cpu_id: usize, tss: &'static TaskStateSegment) -> Self {
+        PerCpu {
+            this: AtomicPtr::new(ptr::null_mut()),
+            cpu_id,
+            tss,
+            apic_id: AtomicU16::new(NO_APIC_ID),
+            current_task: AtomicU64::new(NO_TASK),
+            task_locals: AtomicPtr::new(ptr::null_mut()),
+            idle: AtomicBool::new(false),
+            run_queue: OnceCell::uninit(),
+        }
+    }
+
+    /// Returns the APIC ID of this CPU's local APIC, if it has been enabled
+    pub fn apic_id(&self) -> Option<u8> {
+        u8::try_from(self.apic_id.load(Ordering::Relaxed)).ok()
+    }
+
+    /// Records the APIC ID of this CPU's local APIC, so other CPUs can send it IPIs
+    pub fn set_apic_id(&self, apic_id: u8) {
+        self.apic_id.store(u16::from(apic_id), Ordering::Relaxed);
+    }
+
+    /// Returns whether this CPU is halted waiting for work
+    pub fn is_idle(&self) -> bool {
+        self.idle.load(Ordering::SeqCst)
+    }
+
+    /// Records whether this CPU is halted waiting for work
+    pub fn set_idle(&self, idle: bool) {
+        self.idle.store(idle, Ordering::SeqCst);
+    }
+
+    /// Returns the TaskId of the Task this CPU is polling, if any
+    pub fn current_task(&self) -> Option<TaskId> {
+        match self.current_task.load(Ordering::Relaxed) {
+            NO_TASK => None,
+            id => Some(TaskId::from_u64(id)),
+        }
+    }
+
+    /// Records the Task this CPU is polling, which executors set around each poll
+    pub fn set_current_task(&self, task_id: Option<TaskId>) {
+        let id = task_id.map_or(NO_TASK, TaskId::as_u64);
+        self.current_task.store(id, Ordering::Relaxed);
+    }
+
+    /// Returns the task-local values of the Task this CPU is polling, or null
+    pub(crate) fn task_locals(&self) -> *const TaskLocals {
+        self.task_locals.load(Ordering::Relaxed)
+    }
+
+    /// Records the task-local values of the Task this CPU is polling, which
+    /// TaskLocals::enter sets around each poll
+    pub(crate) fn set_task_locals(&self, locals: *const TaskLocals) {
+        self.task_locals.store(locals.cast_mut(), Ordering::Relaxed);
+    }
+
+    /// Returns this CPU's local run queue, allocating it on first use
+    pub fn run_queue(&self) -> &ArrayQueue<TaskId> {
+        self.run_queue
+            .get_or_init(|| ArrayQueue::new(RUN_QUEUE_CAPACITY))
+    }
+
+    /// Points the current CPU's GS bases at this struct
+    fn install(&'static self) {
+        let this = ptr::from_ref(self).cast_mut();
+        self.this.store(this, Ordering::SeqCst);
+
+        let addr = VirtAddr::from_ptr(this);
+        GsBase::write(addr);
+        KernelGsBase::write(addr);
+    }
+}
+
+/// Sets up the current CPU's data, using `tss` as the TSS loaded on it.
+///
+/// The bootstrap processor (`cpu_id` 0) uses a static, so this can be called before the heap
+/// is initialised. Every other CPU's data is allocated on the heap and lives forever.
+pub fn init(cpu_id: usize, tss: &'static TaskStateSegment) {
+    let per_cpu: &'static PerCpu = if cpu_id == 0 {
+        BSP.get_or_init(|| PerCpu::new(cpu_id, tss))
+    } else {
+        Box::leak(Box::new(PerCpu::new(cpu_id, tss)))
+    };
+    per_cpu.install();
+    CPUS[cpu_id].store(ptr::from_ref(per_cpu).cast_mut(), Ordering::SeqCst);
+}
+
+/// Returns the data of the CPU with index `cpu_id`, if it has been set up
+pub fn cpu(cpu_id: usize) -> Option<&'static PerCpu> {
+    let per_cpu = CPUS.get(cpu_id)?.load(Ordering::SeqCst);
+    unsafe { per_cpu.as_ref() }
+}
+
+/// Returns the data of every CPU which has been set up
+pub fn cpus() -> impl Iterator<Item = &'static PerCpu> {
+    (0..MAX_CPUS).filter_map(cpu)
+}
+
+/// Returns the current CPU's data.
+///
+/// init must have been called on this CPU first. The returned reference stays valid if the
+/// caller later runs on another CPU, but then it refers to the CPU which called this.
+pub fn current() -> &'static PerCpu {
+    let this: *const PerCpu;
+    unsafe {
+        asm!("mov {}, gs:[0]", out(reg) this, options(nostack, readonly, preserves_flags));
+        &*this
+    }
+}
+
+/// Accesses a field or calls a method of the current CPU's PerCpu data
+#[macro_export]
+macro_rules! per_cpu {
+    ($($access:tt)+) => {
+        $crate::percpu::current().$($access)+
+    };
+}
+
+#[test_case]
+fn bsp_data_is_current() {
+    assert_eq!(per_cpu!(cpu_id), 0);
+    assert!(ptr::eq(per_cpu!(tss), crate::gdt::bsp_tss()));
+}
+
+#[test_case]
+fn current_task_round_trips() {
+    let task_id = TaskId::from_u64(7);
+    per_cpu!(set_current_task(Some(task_id)));
+    assert_eq!(per_cpu!(current_task()), Some(task_id));
+    per_cpu!(set_current_task(None));
+    assert_eq!(per_cpu!(current_task()), None);
+}
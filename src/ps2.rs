@@ -0,0 +1,397 @@
+//! This module provides a driver for the i8042 PS/2 controller, which the keyboard is
+//! connected to.
+//!
+//! init brings the controller into a known state rather than relying on the firmware's setup:
+//! it disables both ports, flushes the output buffer, runs the controller and port self-tests,
+//! detects whether there is a second port, and writes the configuration byte, keeping scancode
+//! translation on so the keyboard's scancodes arrive in set 1. It then resets the keyboard.
+//!
+//! The keyboard answers each command sent to it with an acknowledgement, which the interrupt
+//! handler would otherwise take for a scancode. Commands are therefore sent with the controller
+//! locked, which disables interrupts, and wait for the acknowledgement by polling, while the
+//! interrupt handler takes the controller lock and only reads the data port when it holds a byte.
+
+use crate::sync::IrqSafeMutex;
+use x86_64::instructions::port::Port;
+
+/// Port which reads and writes bytes from and to the devices and the controller
+const DATA_PORT: u16 = 0x60;
+
+/// Port which reads the status register and writes controller commands
+const STATUS_COMMAND_PORT: u16 = 0x64;
+
+// Bits of the status register
+const OUTPUT_FULL: u8 = 1;
+const INPUT_FULL: u8 = 1 << 1;
+
+/// Set along with OUTPUT_FULL when the byte in the output buffer came from the second port
+const SECOND_PORT_DATA: u8 = 1 << 5;
+
+// Controller commands
+const READ_CONFIG: u8 = 0x20;
+const WRITE_CONFIG: u8 = 0x60;
+const DISABLE_SECOND_PORT: u8 = 0xA7;
+const ENABLE_SECOND_PORT: u8 = 0xA8;
+const TEST_SECOND_PORT: u8 = 0xA9;
+const TEST_CONTROLLER: u8 = 0xAA;
+const TEST_FIRST_PORT: u8 = 0xAB;
+const DISABLE_FIRST_PORT: u8 = 0xAD;
+const ENABLE_FIRST_PORT: u8 = 0xAE;
+
+// Bits of the configuration byte
+const FIRST_PORT_INTERRUPT: u8 = 1;
+const SECOND_PORT_INTERRUPT: u8 = 1 << 1;
+const SECOND_PORT_CLOCK_DISABLED: u8 = 1 << 5;
+const FIRST_PORT_TRANSLATION: u8 = 1 << 6;
+
+// Responses to controller self-tests
+const CONTROLLER_TEST_PASSED: u8 = 0x55;
+const PORT_TEST_PASSED: u8 = 0x00;
+
+// Keyboard commands
+const SET_LEDS: u8 = 0xED;
+const SET_TYPEMATIC: u8 = 0xF3;
+const RESET: u8 = 0xFF;
+
+// Keyboard responses
+const ACK: u8 = 0xFA;
+const RESEND: u8 = 0xFE;
+const RESET_PASSED: u8 = 0xAA;
+
+/// Number of times a keyboard command is resent before giving up
+const MAX_RESENDS: usize = 3;
+
+/// Number of status register reads before waiting on the controller times out. Each read takes
+/// around a microsecond, so this is far longer than a working controller or keyboard takes.
+const TIMEOUT_READS: usize = 100_000;
+
+/// Number of status register reads before waiting for the keyboard to answer a command times
+/// out. The keyboard answers within a few milliseconds, and interrupts are disabled while a
+/// command waits, so this is far shorter than TIMEOUT_READS.
+const KEYBOARD_TIMEOUT_READS: usize = 10_000;
+
+/// Number of status register reads before waiting for the keyboard's self-test times out,
+/// as the self-test can take most of a second
+const RESET_TIMEOUT_READS: usize = 1_000_000;
+
+/// Errors which can occur when using the PS/2 controller
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Ps2Error {
+    /// The controller did not accept or answer a byte in time, or there is no controller
+    Timeout,
+
+    /// The controller self-test returned this instead of passing
+    ControllerTestFailed(u8),
+
+    /// The interface test of the first port returned this instead of passing
+    PortTestFailed(u8),
+
+    /// The keyboard answered a command with this instead of acknowledging it
+    NotAcknowledged(u8),
+
+    /// init has not succeeded, so there is no keyboard to send commands to
+    NoKeyboard,
+
+    /// A typematic rate above 31 was requested
+    InvalidRate(u8),
+}
+
+/// What init found
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Ps2Info {
+    /// The controller has a second port, usually for a mouse
+    pub dual_channel: bool,
+
+    /// The second port passed its interface test, so it was enabled
+    pub second_port: bool,
+
+    /// A keyboard on the first port passed its self-test
+    pub keyboard: bool,
+}
+
+/// The keyboard's lock LEDs
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Leds {
+    pub scroll_lock: bool,
+    pub num_lock: bool,
+    pub caps_lock: bool,
+}
+
+impl Leds {
+    fn bits(self) -> u8 {
+        u8::from(self.scroll_lock) | u8::from(self.num_lock) << 1 | u8::from(self.caps_lock) << 2
+    }
+}
+
+/// Time a key is held before it starts repeating
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TypematicDelay {
+    Ms250,
+    Ms500,
+    Ms750,
+    Ms1000,
+}
+
+struct Controller {
+    data: Port<u8>,
+    status_command: Port<u8>,
+
+    /// What init found, or None if it has not succeeded
+    info: Option<Ps2Info>,
+}
+
+/// The controller, which must be locked for every access to its ports. The lock disables
+/// interrupts while held, so a command's response cannot be taken by the interrupt handler.
+static CONTROLLER: IrqSafeMutex<Controller> = IrqSafeMutex::new(Controller {
+    data: Port::new(DATA_PORT),
+    status_command: Port::new(STATUS_COMMAND_PORT),
+    info: None,
+});
+
+impl Controller {
+    fn status(&mut self) -> u8 {
+        unsafe { self.status_command.read() }
+    }
+
+    /// Waits until the controller can accept a byte
+    fn wait_input_empty(&mut self) -> Result<(), Ps2Error> {
+        for _ in 0..TIMEOUT_READS {
+            if self.status() & INPUT_FULL == 0 {
+                return Ok(());
+            }
+        }
+        Err(Ps2Error::Timeout)
+    }
+
+    /// Waits for a byte from the controller or a device, and reads it
+    fn read(&mut self) -> Result<u8, Ps2Error> {
+        self.read_within(TIMEOUT_READS)
+    }
+
+    /// Waits for a byte for at most `reads` status register reads, and reads it
+    fn read_within(&mut self, reads: usize) -> Result<u8, Ps2Error> {
+        for _ in 0..reads {
+            if self.status() & OUTPUT_FULL != 0 {
+                return Ok(unsafe { self.data.read() });
+            }
+        }
+        Err(Ps2Error::Timeout)
+    }
+
+    /// Sends a command to the controller
+    fn command(&mut self, command: u8) -> Result<(), Ps2Error> {
+        self.wait_input_empty()?;
+        unsafe { self.status_command.write(command) };
+        Ok(())
+    }
+
+    /// Sends a command to the controller, then writes its argument
+    fn command_with_argument(&mut self, command: u8, argument: u8) -> Result<(), Ps2Error> {
+        self.command(command)?;
+        self.wait_input_empty()?;
+        unsafe { self.data.write(argument) };
+        Ok(())
+    }
+
+    /// Sends a command to the controller and reads its response
+    fn query(&mut self, command: u8) -> Result<u8, Ps2Error> {
+        self.command(command)?;
+        self.read()
+    }
+
+    /// Discards any bytes waiting in the output buffer
+    fn flush(&mut self) {
+        for _ in 0..TIMEOUT_READS {
+            if self.status() & OUTPUT_FULL == 0 {
+                return;
+            }
+            unsafe { self.data.read() };
+        }
+    }
+
+    /// Waits for a byte from the keyboard for at most `reads` status register reads, and
+    /// reads it. Bytes from the second port, such as mouse packets, are discarded.
+    fn read_keyboard_within(&mut self, reads: usize) -> Result<u8, Ps2Error> {
+        for _ in 0..reads {
+            let status = self.status();
+            if status & OUTPUT_FULL != 0 {
+                let byte = unsafe { self.data.read() };
+                if status & SECOND_PORT_DATA == 0 {
+                    return Ok(byte);
+                }
+            }
+        }
+        Err(Ps2Error::Timeout)
+    }
+
+    /// Sends a byte to the keyboard and waits for it to be acknowledged, resending it if the
+    /// keyboard asks. Scancodes which arrive while waiting are passed on, so no keys are lost,
+    /// except during init, when there is nothing to pass them to yet.
+    fn keyboard_write(&mut self, byte: u8) -> Result<(), Ps2Error> {
+        for _ in 0..=MAX_RESENDS {
+            self.wait_input_empty()?;
+            unsafe { self.data.write(byte) };
+            loop {
+                match self.read_keyboard_within(KEYBOARD_TIMEOUT_READS)? {
+                    ACK => return Ok(()),
+                    RESEND => break,
+                    scancode if self.info.is_some() => {
+                        crate::task::keyboard::add_scancode(scancode)
+                    }
+                    _ => {}
+                }
+            }
+        }
+        Err(Ps2Error::NotAcknowledged(RESEND))
+    }
+
+    /// Sends a command with an argument to the keyboard
+    fn keyboard_command(&mut self, command: u8, argument: u8) -> Result<(), Ps2Error> {
+        if !self.info.is_some_and(|info| info.keyboard) {
+            return Err(Ps2Error::NoKeyboard);
+        }
+        self.keyboard_write(command)?;
+        self.keyboard_write(argument)
+    }
+
+    fn init(&mut self) -> Result<Ps2Info, Ps2Error> {
+        self.info = None;
+
+        // Stop the devices sending anything while the controller is set up
+        self.command(DISABLE_FIRST_PORT)?;
+        self.command(DISABLE_SECOND_PORT)?;
+        self.flush();
+
+        // If setting up the controller fails part way, the firmware's configuration is
+        // restored and the first port enabled again, so the keyboard works as it did before
+        let original_config = self.query(READ_CONFIG)?;
+        let result = self.configure(original_config);
+        if result.is_err() {
+            let _ = self.command_with_argument(WRITE_CONFIG, original_config);
+            let _ = self.command(ENABLE_FIRST_PORT);
+        }
+        self.info = result.ok();
+        result
+    }
+
+    /// Tests the controller and its ports, enables them, and resets the keyboard, starting
+    /// from the configuration byte `config` which the firmware left
+    fn configure(&mut self, mut config: u8) -> Result<Ps2Info, Ps2Error> {
+        // Turn off interrupts until the ports are tested. The second port's clock is
+        // disabled now, so if this bit is clear there cannot be a second port.
+        let maybe_dual_channel = config & SECOND_PORT_CLOCK_DISABLED != 0;
+        config &= !(FIRST_PORT_INTERRUPT | SECOND_PORT_INTERRUPT);
+        config |= FIRST_PORT_TRANSLATION;
+        self.command_with_argument(WRITE_CONFIG, config)?;
+
+        // The self-test can reset the controller, so the configuration is written again
+        match self.query(TEST_CONTROLLER)? {
+            CONTROLLER_TEST_PASSED => self.command_with_argument(WRITE_CONFIG, config)?,
+            result => return Err(Ps2Error::ControllerTestFailed(result)),
+        }
+
+        // Enabling the second port clears its clock disabled bit if it exists
+        let dual_channel = maybe_dual_channel && {
+            self.command(ENABLE_SECOND_PORT)?;
+            let enabled = self.query(READ_CONFIG)? & SECOND_PORT_CLOCK_DISABLED == 0;
+            self.command(DISABLE_SECOND_PORT)?;
+            enabled
+        };
+
+        match self.query(TEST_FIRST_PORT)? {
+            PORT_TEST_PASSED => {}
+            result => return Err(Ps2Error::PortTestFailed(result)),
+        }
+        let second_port = dual_channel && self.query(TEST_SECOND_PORT)? == PORT_TEST_PASSED;
+
+        self.command(ENABLE_FIRST_PORT)?;
+        config |= FIRST_PORT_INTERRUPT;
+        if second_port {
+            self.command(ENABLE_SECOND_PORT)?;
+            config |= SECOND_PORT_INTERRUPT;
+        }
+
+        // The keyboard acknowledges the reset, then reports its self-test result. A missing
+        // or faulty keyboard leaves the controller usable, for a device plugged in later.
+        let reset = self.keyboard_write(RESET);
+        let keyboard = matches!(
+            reset.and_then(|()| self.read_keyboard_within(RESET_TIMEOUT_READS)),
+            Ok(RESET_PASSED)
+        );
+        self.flush();
+        self.command_with_argument(WRITE_CONFIG, config)?;
+
+        Ok(Ps2Info {
+            dual_channel,
+            second_port,
+            keyboard,
+        })
+    }
+}
+
+/// Initialises the controller and resets the keyboard, returning what was found
+pub fn init() -> Result<Ps2Info, Ps2Error> {
+    CONTROLLER.lock().init()
+}
+
+/// Returns what init found, or None if it has not succeeded
+pub fn info() -> Option<Ps2Info> {
+    CONTROLLER.lock().info
+}
+
+/// Turns the keyboard's lock LEDs on or off
+pub fn set_leds(leds: Leds) -> Result<(), Ps2Error> {
+    CONTROLLER.lock().keyboard_command(SET_LEDS, leds.bits())
+}
+
+/// Sets how long a key is held before it repeats, and how fast it then repeats, from
+/// 0 for 30 repeats per second to 31 for 2 repeats per second
+pub fn set_typematic(delay: TypematicDelay, rate: u8) -> Result<(), Ps2Error> {
+    if rate > 31 {
+        return Err(Ps2Error::InvalidRate(rate));
+    }
+    CONTROLLER
+        .lock()
+        .keyboard_command(SET_TYPEMATIC, (delay as u8) << 5 | rate)
+}
+
+/// Reads a byte the keyboard has sent, if there is one. Called by the keyboard interrupt
+/// handler, which may find the byte already taken by a command waiting for its response.
+pub(crate) fn read_keyboard_byte() -> Option<u8> {
+    let mut controller = CONTROLLER.lock();
+    let status = controller.status();
+    if status & OUTPUT_FULL == 0 {
+        return None;
+    }
+    let byte = unsafe { controller.data.read() };
+
+    // Acknowledgements which arrive after their command timed out are not scancodes
+    if status & SECOND_PORT_DATA != 0 || matches!(byte, ACK | RESEND) {
+        return None;
+    }
+    Some(byte)
+}
+
+/// Tests that init, which the test kernel runs, found QEMU's keyboard
+#[test_case]
+fn test_keyboard_found() {
+    let info = info().expect("PS/2 controller not initialised");
+    assert!(info.keyboard);
+}
+
+/// Tests that the keyboard acknowledges LED and typematic commands, and that an out of
+/// range typematic rate is rejected before anything is sent
+#[test_case]
+fn test_keyboard_commands() {
+    let leds = Leds {
+        num_lock: true,
+        ..Leds::default()
+    };
+    assert_eq!(set_leds(leds), Ok(()));
+    assert_eq!(set_leds(Leds::default()), Ok(()));
+    assert_eq!(set_typematic(TypematicDelay::Ms500, 11), Ok(()));
+    assert_eq!(
+        set_typematic(TypematicDelay::Ms500, 32),
+        Err(Ps2Error::InvalidRate(32))
+    );
+}
@@ -0,0 +1,305 @@
+//! This module provides a snapshot of the CPU's register file for use in crash reports.
+//!
+//! Registers are saved by assembly stubs rather than Rust code, so the values are not
+//! clobbered by compiler generated code before they are recorded. The snapshot can either
+//! be taken on entry to an exception handler (see interrupts.rs), or on demand by calling
+//! capture(), which is what the panic handler does.
+
+use crate::symbols::Symbolized;
+use core::{arch::naked_asm, fmt, mem::offset_of};
+
+/// Saved copy of the general purpose, control and segment registers.
+///
+/// #[repr(C)] ensures the field offsets which the assembly stubs write to
+/// are the offsets calculated by offset_of!
+#[derive(Debug, Clone, Copy, Default)]
+#[repr(C)]
+pub struct RegisterDump {
+    pub rax: u64,
+    pub rbx: u64,
+    pub rcx: u64,
+    pub rdx: u64,
+    pub rsi: u64,
+    pub rdi: u64,
+    pub rbp: u64,
+    pub rsp: u64,
+    pub r8: u64,
+    pub r9: u64,
+    pub r10: u64,
+    pub r11: u64,
+    pub r12: u64,
+    pub r13: u64,
+    pub r14: u64,
+    pub r15: u64,
+    pub rip: u64,
+    pub rflags: u64,
+    pub cr0: u64,
+    pub cr2: u64,
+    pub cr3: u64,
+    pub cr4: u64,
+    pub cs: u64,
+    pub ds: u64,
+    pub es: u64,
+    pub fs: u64,
+    pub gs: u64,
+    pub ss: u64,
+}
+
+/// Size of a RegisterDump in bytes. Exception stubs reserve this much stack
+/// space to save the registers into, so it must keep the stack 16 byte aligned.
+pub(crate) const REGISTER_DUMP_SIZE: usize = core::mem::size_of::<RegisterDump>();
+
+const _: () = assert!(REGISTER_DUMP_SIZE.is_multiple_of(16));
+
+impl RegisterDump {
+    /// Takes a snapshot of the registers as they are at the call site
+    #[inline(always)]
+    pub fn capture() -> RegisterDump {
+        let mut dump = RegisterDump::default();
+        unsafe { capture_registers(&mut dump) };
+        dump
+    }
+}
+
+/// Writes the current register values into the RegisterDump pointed to by rdi.
+///
+/// rdi itself, rip, and rsp are reported as they were at the call site, so
+/// the dump reflects the caller rather than this function.
+#[unsafe(naked)]
+unsafe extern "C" fn capture_registers(dump: *mut RegisterDump) {
+    naked_asm!(
+        "mov [rdi + {rax}], rax",
+        "mov [rdi + {rbx}], rbx",
+        "mov [rdi + {rcx}], rcx",
+        "mov [rdi + {rdx}], rdx",
+        "mov [rdi + {rsi}], rsi",
+        "mov [rdi + {rdi}], rdi",
+        "mov [rdi + {rbp}], rbp",
+        "mov [rdi + {r8}], r8",
+        "mov [rdi + {r9}], r9",
+        "mov [rdi + {r10}], r10",
+        "mov [rdi + {r11}], r11",
+        "mov [rdi + {r12}], r12",
+        "mov [rdi + {r13}], r13",
+        "mov [rdi + {r14}], r14",
+        "mov [rdi + {r15}], r15",
+        // The return address and the stack pointer before the call instruction
+        // pushed it are the caller's rip and rsp
+        "mov rax, [rsp]",
+        "mov [rdi + {rip}], rax",
+        "lea rax, [rsp + 8]",
+        "mov [rdi + {rsp}], rax",
+        "pushfq",
+        "pop rax",
+        "mov [rdi + {rflags}], rax",
+        "mov rax, cr0",
+        "mov [rdi + {cr0}], rax",
+        "mov rax, cr2",
+        "mov [rdi + {cr2}], rax",
+        "mov rax, cr3",
+        "mov [rdi + {cr3}], rax",
+        "mov rax, cr4",
+        "mov [rdi + {cr4}], rax",
+        "mov rax, cs",
+        "mov [rdi + {cs}], rax",
+        "mov rax, ds",
+        "mov [rdi + {ds}], rax",
+        "mov rax, es",
+        "mov [rdi + {es}], rax",
+        "mov rax, fs",
+        "mov [rdi + {fs}], rax",
+        "mov rax, gs",
+        "mov [rdi + {gs}], rax",
+        "mov rax, ss",
+        "mov [rdi + {ss}], rax",
+        // Restore rax so this function only clobbers what the C ABI allows
+        "mov rax, [rdi + {rax}]",
+        "ret",
+        rax = const offset_of!(RegisterDump, rax),
+        rbx = const offset_of!(RegisterDump, rbx),
+        rcx = const offset_of!(RegisterDump, rcx),
+        rdx = const offset_of!(RegisterDump, rdx),
+        rsi = const offset_of!(RegisterDump, rsi),
+        rdi = const offset_of!(RegisterDump, rdi),
+        rbp = const offset_of!(RegisterDump, rbp),
+        rsp = const offset_of!(RegisterDump, rsp),
+        r8 = const offset_of!(RegisterDump, r8),
+        r9 = const offset_of!(RegisterDump, r9),
+        r10 = const offset_of!(RegisterDump, r10),
+        r11 = const offset_of!(RegisterDump, r11),
+        r12 = const offset_of!(RegisterDump, r12),
+        r13 = const offset_of!(RegisterDump, r13),
+        r14 = const offset_of!(RegisterDump, r14),
+        r15 = const offset_of!(RegisterDump, r15),
+        rip = const offset_of!(RegisterDump, rip),
+        rflags = const offset_of!(RegisterDump, rflags),
+        cr0 = const offset_of!(RegisterDump, cr0),
+        cr2 = const offset_of!(RegisterDump, cr2),
+        cr3 = const offset_of!(RegisterDump, cr3),
+        cr4 = const offset_of!(RegisterDump, cr4),
+        cs = const offset_of!(RegisterDump, cs),
+        ds = const offset_of!(RegisterDump, ds),
+        es = const offset_of!(RegisterDump, es),
+        fs = const offset_of!(RegisterDump, fs),
+        gs = const offset_of!(RegisterDump, gs),
+        ss = const offset_of!(RegisterDump, ss),
+    );
+}
+
+/// Generates an assembly entry stub for an exception which pushes an error code.
+///
+/// The stub saves every register into a RegisterDump on the current stack, fills in
+/// rip, rsp, rflags, cs and ss from the interrupt stack frame the CPU pushed, then calls
+/// `$handler` with a reference to the dump and the error code. The handler must not return,
+/// as the stub does not restore the registers or execute iretq.
+///
+/// The CPU aligns the stack to 16 bytes before pushing the 6 quad word stack frame (including
+/// the error code), and REGISTER_DUMP_SIZE is a multiple of 16, so the stack is correctly
+/// aligned for the call.
+macro_rules! fatal_exception_stub_with_error_code {
+    ($name:ident, $handler:path) => {
+        #[unsafe(naked)]
+        extern "C" fn $name() -> ! {
+            use core::mem::offset_of;
+            use $crate::registers::{REGISTER_DUMP_SIZE, RegisterDump};
+
+            core::arch::naked_asm!(
+                "sub rsp, {size}",
+                "mov [rsp + {rax}], rax",
+                "mov [rsp + {rbx}], rbx",
+                "mov [rsp + {rcx}], rcx",
+                "mov [rsp + {rdx}], rdx",
+                "mov [rsp + {rsi}], rsi",
+                "mov [rsp + {rdi}], rdi",
+                "mov [rsp + {rbp}], rbp",
+                "mov [rsp + {r8}], r8",
+                "mov [rsp + {r9}], r9",
+                "mov [rsp + {r10}], r10",
+                "mov [rsp + {r11}], r11",
+                "mov [rsp + {r12}], r12",
+                "mov [rsp + {r13}], r13",
+                "mov [rsp + {r14}], r14",
+                "mov [rsp + {r15}], r15",
+                // Interrupt stack frame: error code, rip, cs, rflags, rsp, ss
+                "mov rax, [rsp + {size} + 8]",
+                "mov [rsp + {rip}], rax",
+                "mov rax, [rsp + {size} + 16]",
+                "mov [rsp + {cs}], rax",
+                "mov rax, [rsp + {size} + 24]",
+                "mov [rsp + {rflags}], rax",
+                "mov rax, [rsp + {size} + 32]",
+                "mov [rsp + {rsp}], rax",
+                "mov rax, [rsp + {size} + 40]",
+                "mov [rsp + {ss}], rax",
+                "mov rax, cr0",
+                "mov [rsp + {cr0}], rax",
+                "mov rax, cr2",
+                "mov [rsp + {cr2}], rax",
+                "mov rax, cr3",
+                "mov [rsp + {cr3}], rax",
+                "mov rax, cr4",
+                "mov [rsp + {cr4}], rax",
+                "mov rax, ds",
+                "mov [rsp + {ds}], rax",
+                "mov rax, es",
+                "mov [rsp + {es}], rax",
+                "mov rax, fs",
+                "mov [rsp + {fs}], rax",
+                "mov rax, gs",
+                "mov [rsp + {gs}], rax",
+                "mov rdi, rsp",
+                "mov rsi, [rsp + {size}]",
+                "call {handler}",
+                "ud2",
+                size = const REGISTER_DUMP_SIZE,
+                rax = const offset_of!(RegisterDump, rax),
+                rbx = const offset_of!(RegisterDump, rbx),
+                rcx = const offset_of!(RegisterDump, rcx),
+                rdx = const offset_of!(RegisterDump, rdx),
+                rsi = const offset_of!(RegisterDump, rsi),
+                rdi = const offset_of!(RegisterDump, rdi),
+                rbp = const offset_of!(RegisterDump, rbp),
+                rsp = const offset_of!(RegisterDump, rsp),
+                r8 = const offset_of!(RegisterDump, r8),
+                r9 = const offset_of!(RegisterDump, r9),
+                r10 = const offset_of!(RegisterDump, r10),
+                r11 = const offset_of!(RegisterDump, r11),
+                r12 = const offset_of!(RegisterDump, r12),
+                r13 = const offset_of!(RegisterDump, r13),
+                r14 = const offset_of!(RegisterDump, r14),
+                r15 = const offset_of!(RegisterDump, r15),
+                rip = const offset_of!(RegisterDump, rip),
+                rflags = const offset_of!(RegisterDump, rflags),
+                cr0 = const offset_of!(RegisterDump, cr0),
+                cr2 = const offset_of!(RegisterDump, cr2),
+                cr3 = const offset_of!(RegisterDump, cr3),
+                cr4 = const offset_of!(RegisterDump, cr4),
+                cs = const offset_of!(RegisterDump, cs),
+                ds = const offset_of!(RegisterDump, ds),
+                es = const offset_of!(RegisterDump, es),
+                fs = const offset_of!(RegisterDump, fs),
+                gs = const offset_of!(RegisterDump, gs),
+                ss = const offset_of!(RegisterDump, ss),
+                handler = sym $handler,
+            );
+        }
+    };
+}
+
+pub(crate) use fatal_exception_stub_with_error_code;
+
+impl fmt::Display for RegisterDump {
+    /// Formats the registers in rows of three, in a layout which fits on the VGA text buffer,
+    /// followed by the function containing rip
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "RAX={:016x} RBX={:016x} RCX={:016x}",
+            self.rax, self.rbx, self.rcx
+        )?;
+        writeln!(
+            f,
+            "RDX={:016x} RSI={:016x} RDI={:016x}",
+            self.rdx, self.rsi, self.rdi
+        )?;
+        writeln!(
+            f,
+            "RBP={:016x} RSP={:016x} R8 ={:016x}",
+            self.rbp, self.rsp, self.r8
+        )?;
+        writeln!(
+            f,
+            "R9 ={:016x} R10={:016x} R11={:016x}",
+            self.r9, self.r10, self.r11
+        )?;
+        writeln!(
+            f,
+            "R12={:016x} R13={:016x} R14={:016x}",
+            self.r12, self.r13, self.r14
+        )?;
+        writeln!(
+            f,
+            "R15={:016x} RIP={:016x} RFL={:016x}",
+            self.r15, self.rip, self.rflags
+        )?;
+        writeln!(
+            f,
+            "CR0={:016x} CR2={:016x} CR3={:016x}",
+            self.cr0, self.cr2, self.cr3
+        )?;
+        writeln!(f, "CR4={:016x}", self.cr4)?;
+        writeln!(
+            f,
+            "CS={:04x} DS={:04x} ES={:04x} FS={:04x} GS={:04x} SS={:04x}",
+            self.cs, self.ds, self.es, self.fs, self.gs, self.ss
+        )?;
+        write!(f, "RIP at {}", Symbolized(self.rip))
+    }
+}
+
+/// Prints the register dump to both the VGA text buffer and the serial port, so
+/// it is visible on screen, and can also be captured by the host.
+pub fn print_dump(dump: &RegisterDump) {
+    crate::println!("{}", dump);
+    crate::serial_println!("{}", dump);
+}
@@ -1,41 +1,176 @@
-//! This module provides an interface to write to the serial port.
+//! This module provides an interface to write to and read from the serial ports.
 //!
 //! The unsafe operations of writing to a raw pointer are restricted
 //! to this module, therefore callers of this module do not have
 //! to use unsafe blocks.
+//!
+//! COM1 to COM4 are probed the first time any of them is used, and those which are present
+//! are configured with the default line settings. The serial_print! macros write to the log
+//! port, and the serial console uses the console port, both of which are COM1 unless changed,
+//! so the log can be moved off the console. COM1 and COM3 raise IRQ4, and COM2 and COM4 raise
+//! IRQ3, when they receive data, and the interrupt handler passes the received bytes to the
+//! SerialStreams in task::serial.
 
+use crate::interrupts::irq;
+use crate::sync::IrqSafeMutex;
+use core::{
+    fmt::{self, Write},
+    sync::atomic::{AtomicU8, Ordering},
+};
 use lazy_static::lazy_static;
-use spin::Mutex;
-use uart_16550::SerialPort;
 
-// There are many ports used in serial communication, however the
-// SerialPort::new function can calculate them all from this
-const SERIAL_PORT_ADDR: u16 = 0x3F8;
+pub mod uart;
+
+pub use uart::{ConfigError, DataBits, LineConfig, Parity, StopBits, Uart};
+
+/// The standard PC serial ports
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ComPort {
+    Com1,
+    Com2,
+    Com3,
+    Com4,
+}
+
+impl ComPort {
+    pub const ALL: [ComPort; 4] = [ComPort::Com1, ComPort::Com2, ComPort::Com3, ComPort::Com4];
+
+    /// Returns the base I/O port of the port's UART
+    pub fn base(self) -> u16 {
+        match self {
+            ComPort::Com1 => 0x3F8,
+            ComPort::Com2 => 0x2F8,
+            ComPort::Com3 => 0x3E8,
+            ComPort::Com4 => 0x2E8,
+        }
+    }
+
+    /// Returns the IRQ line the port raises
+    pub fn irq(self) -> u8 {
+        match self {
+            ComPort::Com1 | ComPort::Com3 => irq::COM1,
+            ComPort::Com2 | ComPort::Com4 => irq::COM2,
+        }
+    }
 
-// Spinlock protected SerialPort struct which users of this module
-// should use for all writes to the serial port.
+    /// Returns the position of the port in ALL
+    pub fn index(self) -> usize {
+        self as usize
+    }
+}
+
+impl fmt::Display for ComPort {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "COM{}", self.index() + 1)
+    }
+}
+
+/// Errors which can occur when using a serial port
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SerialError {
+    /// Probing found no UART at the port
+    NotPresent(ComPort),
+
+    /// The UART could not be configured
+    Config(ConfigError),
+}
+
+impl From<ConfigError> for SerialError {
+    fn from(error: ConfigError) -> Self {
+        SerialError::Config(error)
+    }
+}
+
+// Spinlock protected UARTs of the ports which are present, indexed by ComPort::index,
+// which users of this module should use for all reads and writes. The locks disable
+// interrupts while held, so interrupt handlers can also print.
 lazy_static! {
-    pub static ref SERIAL1: Mutex<SerialPort> = {
-        let mut serial_port = unsafe { SerialPort::new(SERIAL_PORT_ADDR) };
-        serial_port.init();
-        Mutex::new(serial_port)
-    };
+    static ref PORTS: [Option<IrqSafeMutex<Uart>>; 4] = ComPort::ALL.map(|port| {
+        // Nothing else uses the standard serial ports' I/O ports
+        let mut uart = unsafe { Uart::new(port.base()) };
+        if !uart.probe() {
+            return None;
+        }
+        uart.configure(LineConfig::default())
+            .expect("default line settings are supported");
+        Some(IrqSafeMutex::new(uart))
+    });
+}
+
+/// Index of the port serial_print! writes to
+static LOG_PORT: AtomicU8 = AtomicU8::new(ComPort::Com1 as u8);
+
+/// Index of the port the serial console uses
+static CONSOLE_PORT: AtomicU8 = AtomicU8::new(ComPort::Com1 as u8);
+
+/// Returns the UART of a port, or NotPresent
+fn uart(port: ComPort) -> Result<&'static IrqSafeMutex<Uart>, SerialError> {
+    PORTS[port.index()]
+        .as_ref()
+        .ok_or(SerialError::NotPresent(port))
+}
+
+/// Returns whether probing found a UART at the port
+pub fn is_present(port: ComPort) -> bool {
+    uart(port).is_ok()
+}
+
+/// Returns the line settings of a port
+pub fn config(port: ComPort) -> Result<LineConfig, SerialError> {
+    Ok(uart(port)?.lock().config())
+}
+
+/// Sets the baud rate, parity and stop bits of a port. Bytes which have been received but
+/// not yet read are discarded.
+pub fn configure(port: ComPort, config: LineConfig) -> Result<(), SerialError> {
+    uart(port)?.lock().configure(config)?;
+    Ok(())
+}
+
+/// Returns the port serial_print! writes to
+pub fn log_port() -> ComPort {
+    ComPort::ALL[usize::from(LOG_PORT.load(Ordering::Relaxed))]
+}
+
+/// Makes serial_print! write to `port`
+pub fn set_log_port(port: ComPort) -> Result<(), SerialError> {
+    uart(port)?;
+    LOG_PORT.store(port as u8, Ordering::Relaxed);
+    Ok(())
+}
+
+/// Returns the port the serial console uses
+pub fn console_port() -> ComPort {
+    ComPort::ALL[usize::from(CONSOLE_PORT.load(Ordering::Relaxed))]
+}
+
+/// Makes serial consoles started from now on use `port`
+pub fn set_console_port(port: ComPort) -> Result<(), SerialError> {
+    uart(port)?;
+    CONSOLE_PORT.store(port as u8, Ordering::Relaxed);
+    Ok(())
+}
+
+/// Writes formatted strings to a port, doing nothing if it is not present
+pub fn write_fmt(port: ComPort, args: fmt::Arguments) -> fmt::Result {
+    match uart(port) {
+        Ok(uart) => uart.lock().write_fmt(args),
+        Err(_) => Ok(()),
+    }
 }
 
-/// Print formatted strings to serial port
+/// Reads a received byte from a port, if it is present and has received one.
+///
+/// The UARTs' receive interrupts are enabled when they are probed, so this probes them if
+/// nothing has been printed yet, which lets the interrupt handler take the first byte.
+pub(crate) fn try_receive(port: ComPort) -> Option<u8> {
+    uart(port).ok()?.lock().try_receive()
+}
+
+/// Print formatted strings to the log port
 #[doc(hidden)]
 pub fn _print(args: ::core::fmt::Arguments) {
-    use core::fmt::Write;
-    use x86_64::instructions::interrupts;
-
-    // Disable interrupts to avoid the interrupt handler and _print function
-    // deadlocking over the serial port lock
-    interrupts::without_interrupts(|| {
-        SERIAL1
-            .lock()
-            .write_fmt(args)
-            .expect("Printing to serial failed");
-    });
+    write_fmt(log_port(), args).expect("Printing to serial failed");
 }
 
 /// Prints to the host through the serial interface.
@@ -54,3 +189,48 @@ macro_rules! serial_println {
     ($fmt:expr, $($arg:tt)*) => ($crate::serial_print!(
         concat!($fmt, "\n"), $($arg)*));
 }
+
+/// Tests that COM1, which QEMU provides, is found by probing
+#[test_case]
+fn test_com1_present() {
+    assert!(is_present(ComPort::Com1));
+}
+
+/// Tests that the divisor is only found for baud rates which divide the UART's clock
+#[test_case]
+fn test_baud_rate_divisor() {
+    let config = |baud_rate| LineConfig {
+        baud_rate,
+        ..LineConfig::default()
+    };
+    assert_eq!(config(115_200).divisor(), Some(1));
+    assert_eq!(config(9600).divisor(), Some(12));
+    assert_eq!(config(50).divisor(), Some(2304));
+    assert_eq!(config(1_000_000).divisor(), None);
+    assert_eq!(config(7).divisor(), None);
+    assert_eq!(config(0).divisor(), None);
+}
+
+/// Tests that a port cannot be used for the log or configured unless it is present, and
+/// that an unsupported baud rate leaves the line settings unchanged
+#[test_case]
+fn test_port_errors() {
+    // QEMU only provides COM1 unless more serial devices are added
+    assert_eq!(
+        set_log_port(ComPort::Com4),
+        Err(SerialError::NotPresent(ComPort::Com4))
+    );
+    assert_eq!(log_port(), ComPort::Com1);
+
+    let unsupported = LineConfig {
+        baud_rate: 1_000_000,
+        ..LineConfig::default()
+    };
+    assert_eq!(
+        configure(ComPort::Com1, unsupported),
+        Err(SerialError::Config(ConfigError::UnsupportedBaudRate(
+            1_000_000
+        )))
+    );
+    assert_eq!(config(ComPort::Com1), Ok(LineConfig::default()));
+}
@@ -0,0 +1,288 @@
+//! This module provides a driver for 16550 UARTs, the chips behind the PC's serial ports.
+//!
+//! Each UART is a block of eight I/O ports starting at its base address. A UART which is
+//! absent reads back as all ones, so probe checks for one by writing to its scratch register
+//! and sending a byte to itself in loopback mode before it is used.
+
+use core::fmt;
+use x86_64::instructions::port::Port;
+
+/// Frequency the UART's baud rate divisor divides, which is the fastest baud rate
+pub const MAX_BAUD_RATE: u32 = 115_200;
+
+// Offsets of the UART's registers from its base address
+const DATA: u16 = 0;
+const INTERRUPT_ENABLE: u16 = 1;
+const FIFO_CONTROL: u16 = 2;
+const LINE_CONTROL: u16 = 3;
+const MODEM_CONTROL: u16 = 4;
+const LINE_STATUS: u16 = 5;
+const SCRATCH: u16 = 7;
+
+// While the divisor latch access bit of the line control register is set, the data and
+// interrupt enable registers hold the low and high bytes of the baud rate divisor
+const DIVISOR_LOW: u16 = 0;
+const DIVISOR_HIGH: u16 = 1;
+const DIVISOR_LATCH_ACCESS: u8 = 1 << 7;
+
+// Bits of the line status register
+const DATA_READY: u8 = 1;
+const TRANSMIT_EMPTY: u8 = 1 << 5;
+
+// Bits of the modem control register. OUT2 connects the UART's interrupt to the PIC.
+const DATA_TERMINAL_READY: u8 = 1;
+const REQUEST_TO_SEND: u8 = 1 << 1;
+const OUT1: u8 = 1 << 2;
+const OUT2: u8 = 1 << 3;
+const LOOPBACK: u8 = 1 << 4;
+
+/// Interrupt enable register bit for received data
+const RECEIVED_DATA_INTERRUPT: u8 = 1;
+
+/// Byte probe sends to the UART itself in loopback mode
+const LOOPBACK_TEST_BYTE: u8 = 0xAE;
+
+/// Number of times probe reads the line status while waiting for the loopback test byte
+const LOOPBACK_TEST_READS: usize = 1000;
+
+/// Enables and clears the FIFOs, with an interrupt once 14 bytes have been received
+const FIFO_ENABLE_AND_CLEAR: u8 = 0xC7;
+
+/// Number of data bits in each character
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DataBits {
+    Five,
+    Six,
+    Seven,
+    Eight,
+}
+
+/// Parity bit sent after the data bits of each character
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Parity {
+    None,
+    Odd,
+    Even,
+
+    /// The parity bit is always 1
+    Mark,
+
+    /// The parity bit is always 0
+    Space,
+}
+
+/// Number of stop bits after each character. With five data bits, Two means one and a half.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopBits {
+    One,
+    Two,
+}
+
+/// Baud rate and character format of a serial line
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LineConfig {
+    /// Bits per second, which must divide MAX_BAUD_RATE exactly
+    pub baud_rate: u32,
+    pub data_bits: DataBits,
+    pub parity: Parity,
+    pub stop_bits: StopBits,
+}
+
+/// 38400 baud with 8 data bits, no parity and 1 stop bit
+const DEFAULT_CONFIG: LineConfig = LineConfig {
+    baud_rate: 38_400,
+    data_bits: DataBits::Eight,
+    parity: Parity::None,
+    stop_bits: StopBits::One,
+};
+
+impl Default for LineConfig {
+    /// 38400 baud with 8 data bits, no parity and 1 stop bit
+    fn default() -> Self {
+        DEFAULT_CONFIG
+    }
+}
+
+impl fmt::Display for LineConfig {
+    /// Formats the configuration in the usual short form, such as 38400 8N1
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let data_bits = match self.data_bits {
+            DataBits::Five => 5,
+            DataBits::Six => 6,
+            DataBits::Seven => 7,
+            DataBits::Eight => 8,
+        };
+        let parity = match self.parity {
+            Parity::None => 'N',
+            Parity::Odd => 'O',
+            Parity::Even => 'E',
+            Parity::Mark => 'M',
+            Parity::Space => 'S',
+        };
+        let stop_bits = match (self.stop_bits, self.data_bits) {
+            (StopBits::One, _) => "1",
+            (StopBits::Two, DataBits::Five) => "1.5",
+            (StopBits::Two, _) => "2",
+        };
+        write!(f, "{} {}{}{}", self.baud_rate, data_bits, parity, stop_bits)
+    }
+}
+
+impl LineConfig {
+    /// Returns the baud rate divisor, or None if the UART cannot run at the baud rate
+    pub fn divisor(&self) -> Option<u16> {
+        if self.baud_rate == 0 || !MAX_BAUD_RATE.is_multiple_of(self.baud_rate) {
+            return None;
+        }
+        u16::try_from(MAX_BAUD_RATE / self.baud_rate).ok()
+    }
+
+    /// Returns the value of the line control register for the character format
+    fn line_control(&self) -> u8 {
+        let data_bits = match self.data_bits {
+            DataBits::Five => 0b00,
+            DataBits::Six => 0b01,
+            DataBits::Seven => 0b10,
+            DataBits::Eight => 0b11,
+        };
+        let stop_bits = match self.stop_bits {
+            StopBits::One => 0,
+            StopBits::Two => 1 << 2,
+        };
+        let parity = match self.parity {
+            Parity::None => 0b000,
+            Parity::Odd => 0b001,
+            Parity::Even => 0b011,
+            Parity::Mark => 0b101,
+            Parity::Space => 0b111,
+        };
+        data_bits | stop_bits | parity << 3
+    }
+}
+
+/// Errors which can occur when configuring a UART
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConfigError {
+    /// The baud rate does not divide MAX_BAUD_RATE exactly
+    UnsupportedBaudRate(u32),
+}
+
+/// A 16550 UART
+pub struct Uart {
+    base: u16,
+    config: LineConfig,
+}
+
+impl Uart {
+    /// Creates a driver for the UART at the `base` I/O port, without touching the hardware.
+    ///
+    /// # Safety
+    ///
+    /// The caller must ensure the eight ports from `base` are not used by another device,
+    /// as probe and configure write to them.
+    pub const unsafe fn new(base: u16) -> Self {
+        Uart {
+            base,
+            config: DEFAULT_CONFIG,
+        }
+    }
+
+    /// Returns the base I/O port
+    pub fn base(&self) -> u16 {
+        self.base
+    }
+
+    /// Returns the configuration last set by configure
+    pub fn config(&self) -> LineConfig {
+        self.config
+    }
+
+    /// Checks that a working UART is present, by writing to and reading back its scratch
+    /// register, then sending a byte to itself in loopback mode.
+    ///
+    /// This disables the UART's interrupts and leaves it in loopback mode if it is present,
+    /// so configure must be called before it is used.
+    pub fn probe(&mut self) -> bool {
+        self.write(INTERRUPT_ENABLE, 0);
+        for pattern in [0x55, 0xAA] {
+            self.write(SCRATCH, pattern);
+            if self.read(SCRATCH) != pattern {
+                return false;
+            }
+        }
+
+        // The byte is still sent at the baud rate in loopback mode, so use the fastest one
+        self.set_line(1, DEFAULT_CONFIG.line_control());
+        self.write(MODEM_CONTROL, LOOPBACK | OUT2 | OUT1 | REQUEST_TO_SEND);
+        self.write(FIFO_CONTROL, FIFO_ENABLE_AND_CLEAR);
+        self.write(DATA, LOOPBACK_TEST_BYTE);
+
+        // Reading a port takes around a microsecond, so this waits far longer than the
+        // byte takes to arrive at 115200 baud
+        for _ in 0..LOOPBACK_TEST_READS {
+            if self.read(LINE_STATUS) & DATA_READY != 0 {
+                return self.read(DATA) == LOOPBACK_TEST_BYTE;
+            }
+        }
+        false
+    }
+
+    /// Sets the baud rate and character format, clears the FIFOs, and enables the
+    /// received data interrupt
+    pub fn configure(&mut self, config: LineConfig) -> Result<(), ConfigError> {
+        let divisor = config
+            .divisor()
+            .ok_or(ConfigError::UnsupportedBaudRate(config.baud_rate))?;
+
+        self.write(INTERRUPT_ENABLE, 0);
+        self.set_line(divisor, config.line_control());
+        self.write(FIFO_CONTROL, FIFO_ENABLE_AND_CLEAR);
+        self.write(MODEM_CONTROL, OUT2 | REQUEST_TO_SEND | DATA_TERMINAL_READY);
+        self.write(INTERRUPT_ENABLE, RECEIVED_DATA_INTERRUPT);
+        self.config = config;
+        Ok(())
+    }
+
+    /// Sends a byte, waiting until the UART can accept it
+    pub fn send(&mut self, byte: u8) {
+        while self.read(LINE_STATUS) & TRANSMIT_EMPTY == 0 {
+            core::hint::spin_loop();
+        }
+        self.write(DATA, byte);
+    }
+
+    /// Reads a received byte, if there is one
+    pub fn try_receive(&mut self) -> Option<u8> {
+        if self.read(LINE_STATUS) & DATA_READY == 0 {
+            return None;
+        }
+        Some(self.read(DATA))
+    }
+
+    /// Writes the baud rate divisor and the line control register
+    fn set_line(&mut self, divisor: u16, line_control: u8) {
+        let [divisor_low, divisor_high] = divisor.to_le_bytes();
+        self.write(LINE_CONTROL, DIVISOR_LATCH_ACCESS);
+        self.write(DIVISOR_LOW, divisor_low);
+        self.write(DIVISOR_HIGH, divisor_high);
+        self.write(LINE_CONTROL, line_control);
+    }
+
+    fn read(&self, register: u16) -> u8 {
+        // The caller of new guaranteed the UART's ports are not used by anything else
+        unsafe { Port::new(self.base + register).read() }
+    }
+
+    fn write(&mut self, register: u16, value: u8) {
+        unsafe { Port::new(self.base + register).write(value) }
+    }
+}
+
+impl fmt::Write for Uart {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for byte in s.bytes() {
+            self.send(byte);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,420 @@
+//! This module starts the application processors (APs), which are all CPUs other than the
+//! bootstrap processor (BSP) that the firmware started the kernel on.
+//!
+//! The CPUs are enumerated from the ACPI MADT. Each AP is started by sending it an INIT IPI
+//! followed by two startup IPIs (SIPIs), which start it in 16 bit real mode at a trampoline
+//! copied into the first MiB of memory. The trampoline switches to long mode using the BSP's
+//! page tables, then calls ap_main on a stack mapped for that AP. Each AP loads its own GDT and
+//! TSS, sets up its per-CPU data, loads the shared IDT, then runs the SMP executor's Tasks.
+
+use crate::backtrace::{self, StackBounds};
+use crate::task::smp_executor;
+use crate::{apic, gdt, interrupts, memory::phys_to_virt, percpu, time};
+use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use core::arch::global_asm;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use x86_64::registers::control::{Cr0, Cr3, Cr4};
+use x86_64::registers::model_specific::Efer;
+use x86_64::structures::paging::{
+    FrameAllocator, Mapper, Page, PageTableFlags, PhysFrame, Size4KiB, Translate,
+    mapper::MapToError,
+};
+use x86_64::{PhysAddr, VirtAddr};
+
+/// Maximum number of CPUs the kernel will start
+pub const MAX_CPUS: usize = 16;
+
+/// Physical address the trampoline is copied to. It must be page aligned and below 1 MiB, as
+/// a SIPI's vector is the page number to start at. It lies in memory that held the bootloader's
+/// code, which the memory map marks as Bootloader rather than Usable, so the frame allocator
+/// never hands it out, and it is no longer used once the kernel is running.
+const TRAMPOLINE_ADDR: u64 = 0x8000;
+
+/// Offset of the first MADT entry, after the table header, local APIC address and flags
+const MADT_ENTRIES_OFFSET: usize = 44;
+
+/// MADT entry type describing a processor's local APIC
+const MADT_LOCAL_APIC: u8 = 0;
+
+/// Flags of a MADT local APIC entry which mean the processor can be started
+const MADT_LOCAL_APIC_ENABLED: u32 = 1 << 0;
+const MADT_LOCAL_APIC_ONLINE_CAPABLE: u32 = 1 << 1;
+
+/// Virtual address of the region each AP's stacks are mapped in
+const AP_STACKS_START: u64 = 0x_5555_0000_0000;
+
+/// Number of pages in each AP's kernel stack and double fault stack
+const AP_STACK_PAGES: u64 = 16;
+const AP_DOUBLE_FAULT_STACK_PAGES: u64 = 5;
+
+/// Pages of the stack region used by each AP: both stacks, each below an unmapped guard page
+const AP_STACK_SLOT_PAGES: u64 = 1 + AP_STACK_PAGES + 1 + AP_DOUBLE_FAULT_STACK_PAGES;
+
+/// How long to wait after an INIT IPI, between SIPIs, and for an AP to report that it started
+const INIT_DELAY_NANOS: u64 = 10_000_000;
+const SIPI_DELAY_NANOS: u64 = 200_000;
+const AP_START_TIMEOUT_NANOS: u64 = 100_000_000;
+
+/// Number of CPUs listed in the MADT, including the BSP
+static CPU_COUNT: AtomicUsize = AtomicUsize::new(1);
+
+/// Number of CPUs which are running, including the BSP
+static ONLINE_CPUS: AtomicUsize = AtomicUsize::new(1);
+
+/// Set by an AP once it no longer needs the trampoline, so the next AP can be started
+static AP_STARTED: AtomicBool = AtomicBool::new(false);
+
+/// Errors which prevent the APs from being started
+#[derive(Debug)]
+pub enum SmpError {
+    /// There is no MADT, so the system has a single CPU
+    NoMadt,
+
+    /// The trampoline's page is not free, or cannot be identity mapped
+    TrampolineUnavailable,
+
+    /// The level 4 page table is above 4 GiB, so cannot be loaded in 32 bit mode by the trampoline
+    PageTableAbove4GiB,
+
+    /// Mapping an AP's stacks failed
+    StackMapping(MapToError<Size4KiB>),
+
+    /// The AP with this local APIC ID did not report that it was running in time. The APs
+    /// started before it stay online.
+    ApTimeout(u8),
+}
+
+// The trampoline, which is copied to TRAMPOLINE_ADDR before each AP is started.
+//
+// It starts in 16 bit real mode, loads a temporary GDT, and enters 32 bit protected mode.
+// It then loads the BSP's CR4, CR3 and EFER, and enables paging with the BSP's CR0, which
+// enters long mode. Finally it loads the AP's stack and calls the entry point with the CPU
+// index. Addresses are calculated relative to TRAMPOLINE_ADDR, as that is where the code runs.
+global_asm!(
+    r#"
+    .pushsection .rodata.ap_trampoline, "a"
+    .set TRAMPOLINE_BASE, {trampoline_addr}
+    .global ap_trampoline_start
+    .global ap_trampoline_data
+    .global ap_trampoline_end
+
+    .code16
+ap_trampoline_start:
+    cli
+    cld
+    xor %ax, %ax
+    mov %ax, %ds
+    lgdtl (ap_gdt_pointer - ap_trampoline_start + TRAMPOLINE_BASE)
+    mov %cr0, %eax
+    or $1, %eax
+    mov %eax, %cr0
+    ljmpl $0x08, $(ap_protected_mode - ap_trampoline_start + TRAMPOLINE_BASE)
+
+    .code32
+ap_protected_mode:
+    mov $0x10, %ax
+    mov %ax, %ds
+    mov %ax, %es
+    mov %ax, %ss
+    mov (ap_cr4 - ap_trampoline_start + TRAMPOLINE_BASE), %eax
+    mov %eax, %cr4
+    mov (ap_cr3 - ap_trampoline_start + TRAMPOLINE_BASE), %eax
+    mov %eax, %cr3
+    mov $0xc0000080, %ecx
+    mov (ap_efer - ap_trampoline_start + TRAMPOLINE_BASE), %eax
+    xor %edx, %edx
+    wrmsr
+    mov (ap_cr0 - ap_trampoline_start + TRAMPOLINE_BASE), %eax
+    mov %eax, %cr0
+    ljmpl $0x18, $(ap_long_mode - ap_trampoline_start + TRAMPOLINE_BASE)
+
+    .code64
+ap_long_mode:
+    xor %ax, %ax
+    mov %ax, %ds
+    mov %ax, %es
+    mov %ax, %ss
+    mov %ax, %fs
+    mov %ax, %gs
+    mov (ap_stack_top - ap_trampoline_start + TRAMPOLINE_BASE), %rsp
+    mov (ap_cpu_index - ap_trampoline_start + TRAMPOLINE_BASE), %rdi
+    mov (ap_entry - ap_trampoline_start + TRAMPOLINE_BASE), %rax
+    // A null frame pointer ends the AP's backtraces
+    xor %rbp, %rbp
+    call *%rax
+    ud2
+
+    .balign 8
+ap_gdt:
+    .quad 0
+    .quad 0x00cf9a000000ffff
+    .quad 0x00cf92000000ffff
+    .quad 0x00af9a000000ffff
+ap_gdt_end:
+ap_gdt_pointer:
+    .word ap_gdt_end - ap_gdt - 1
+    .long ap_gdt - ap_trampoline_start + TRAMPOLINE_BASE
+
+    .balign 8
+ap_trampoline_data:
+ap_cr3: .quad 0
+ap_cr4: .quad 0
+ap_cr0: .quad 0
+ap_efer: .quad 0
+ap_stack_top: .quad 0
+ap_entry: .quad 0
+ap_cpu_index: .quad 0
+ap_trampoline_end:
+
+    .code64
+    .popsection
+    "#,
+    trampoline_addr = const TRAMPOLINE_ADDR,
+    options(att_syntax)
+);
+
+unsafe extern "C" {
+    static ap_trampoline_start: u8;
+    static ap_trampoline_data: u8;
+    static ap_trampoline_end: u8;
+}
+
+/// Values the trampoline loads, in the order they are laid out at ap_trampoline_data
+#[repr(C)]
+struct TrampolineData {
+    cr3: u64,
+    cr4: u64,
+    cr0: u64,
+    efer: u64,
+    stack_top: u64,
+    entry: u64,
+    cpu_index: u64,
+}
+
+/// Returns the number of CPUs listed in the MADT, including the BSP
+pub fn cpu_count() -> usize {
+    CPU_COUNT.load(Ordering::SeqCst)
+}
+
+/// Returns the number of CPUs which are running, including the BSP
+pub fn online_cpus() -> usize {
+    ONLINE_CPUS.load(Ordering::SeqCst)
+}
+
+/// Returns the APIC IDs of the processors in the MADT which can be started, and how many there are
+fn enumerate_cpus() -> Option<([u8; MAX_CPUS], usize)> {
+    use crate::{acpi, bytes};
+
+    let madt = acpi::find_table(b"APIC")?;
+    let mut apic_ids = [0; MAX_CPUS];
+    let mut count = 0;
+
+    let mut offset = MADT_ENTRIES_OFFSET;
+    while let (Some(entry_type), Some(length)) = (
+        bytes::read::<u8>(madt, offset),
+        bytes::read::<u8>(madt, offset + 1),
+    ) {
+        if length < 2 {
+            break;
+        }
+        if entry_type == MADT_LOCAL_APIC
+            && let (Some(apic_id), Some(flags)) = (
+                bytes::read::<u8>(madt, offset + 3),
+                bytes::read::<u32>(madt, offset + 4),
+            )
+            && flags & (MADT_LOCAL_APIC_ENABLED | MADT_LOCAL_APIC_ONLINE_CAPABLE) != 0
+            && count < MAX_CPUS
+        {
+            apic_ids[count] = apic_id;
+            count += 1;
+        }
+        offset += usize::from(length);
+    }
+
+    Some((apic_ids, count))
+}
+
+/// Busy waits for at least `nanos` nanoseconds
+fn delay(nanos: u64) {
+    match time::clock() {
+        Some(clock) => {
+            let start = clock.now();
+            while clock.now() - start < nanos {
+                core::hint::spin_loop();
+            }
+        }
+        // Without a clock source, the PIT's fixed calibration interval is the only delay available
+        None => time::pit::wait_for_calibration(),
+    }
+}
+
+/// Returns the bounds of the kernel stack and double fault stack of the AP with index `cpu_index`
+fn ap_stack_bounds(cpu_index: usize) -> (StackBounds, StackBounds) {
+    let slot = VirtAddr::new(AP_STACKS_START + cpu_index as u64 * AP_STACK_SLOT_PAGES * 4096);
+    let stack_bottom = slot + 4096u64;
+    let double_fault_stack_bottom = stack_bottom + (AP_STACK_PAGES + 1) * 4096;
+    (
+        StackBounds {
+            bottom: stack_bottom,
+            top: stack_bottom + AP_STACK_PAGES * 4096,
+        },
+        StackBounds {
+            bottom: double_fault_stack_bottom,
+            top: double_fault_stack_bottom + AP_DOUBLE_FAULT_STACK_PAGES * 4096,
+        },
+    )
+}
+
+/// Maps the pages from `bounds.bottom` to `bounds.top` to newly allocated frames
+fn map_stack(
+    bounds: StackBounds,
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<(), MapToError<Size4KiB>> {
+    let pages = Page::range(
+        Page::containing_address(bounds.bottom),
+        Page::containing_address(bounds.top),
+    );
+    for page in pages {
+        let frame = frame_allocator
+            .allocate_frame()
+            .ok_or(MapToError::FrameAllocationFailed)?;
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+        unsafe { mapper.map_to(page, frame, flags, frame_allocator)?.flush() };
+    }
+    Ok(())
+}
+
+/// Checks the trampoline's frame is not in use, and identity maps it so the trampoline
+/// keeps running at the same address when it enables paging
+fn map_trampoline(
+    mapper: &mut (impl Mapper<Size4KiB> + Translate),
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+    memory_map: &MemoryMap,
+) -> Result<(), SmpError> {
+    let free = memory_map.iter().any(|region| {
+        region.region_type == MemoryRegionType::Bootloader
+            && region.range.start_addr() <= TRAMPOLINE_ADDR
+            && TRAMPOLINE_ADDR + 4096 <= region.range.end_addr()
+    });
+    if !free {
+        return Err(SmpError::TrampolineUnavailable);
+    }
+
+    let addr = PhysAddr::new(TRAMPOLINE_ADDR);
+    match mapper.translate_addr(VirtAddr::new(TRAMPOLINE_ADDR)) {
+        Some(phys) if phys == addr => Ok(()),
+        Some(_) => Err(SmpError::TrampolineUnavailable),
+        None => {
+            let frame = PhysFrame::<Size4KiB>::containing_address(addr);
+            let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+            unsafe { mapper.identity_map(frame, flags, frame_allocator) }
+                .map_err(|_| SmpError::TrampolineUnavailable)?
+                .flush();
+            Ok(())
+        }
+    }
+}
+
+/// Starts every AP listed in the MADT, returning the number of CPUs which are then running.
+///
+/// Stops at the first AP which does not start in time, as it may still start later and would
+/// then use the trampoline data being filled in for the next AP.
+///
+/// acpi::init, time::init and allocator::init_heap must be called first.
+pub fn init(
+    mapper: &mut (impl Mapper<Size4KiB> + Translate),
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+    memory_map: &MemoryMap,
+) -> Result<usize, SmpError> {
+    let local_apic = apic::init().ok_or(SmpError::NoMadt)?;
+    percpu::current().set_apic_id(local_apic.id());
+    let (apic_ids, count) = enumerate_cpus().ok_or(SmpError::NoMadt)?;
+    CPU_COUNT.store(count.max(1), Ordering::SeqCst);
+
+    let (level_4_frame, cr3_flags) = Cr3::read();
+    let cr3 = level_4_frame.start_address().as_u64() | cr3_flags.bits();
+    if cr3 > u64::from(u32::MAX) {
+        return Err(SmpError::PageTableAbove4GiB);
+    }
+
+    map_trampoline(mapper, frame_allocator, memory_map)?;
+
+    // Copy the trampoline to its page, then fill in the values which are the same for every AP
+    let trampoline = phys_to_virt(PhysAddr::new(TRAMPOLINE_ADDR))
+        .ok_or(SmpError::TrampolineUnavailable)?
+        .as_mut_ptr::<u8>();
+    let data = unsafe {
+        let start = &raw const ap_trampoline_start;
+        let data = &raw const ap_trampoline_data;
+        let end = &raw const ap_trampoline_end;
+        assert!(
+            end.offset_from(start) <= 4096,
+            "trampoline must fit in one page"
+        );
+        core::ptr::copy_nonoverlapping(start, trampoline, end.offset_from(start) as usize);
+        &mut *(trampoline.offset(data.offset_from(start)) as *mut TrampolineData)
+    };
+    data.cr3 = cr3;
+    data.cr4 = Cr4::read_raw();
+    data.cr0 = Cr0::read_raw();
+    data.efer = Efer::read_raw();
+    data.entry = ap_main as *const () as u64;
+
+    let bsp_id = local_apic.id();
+    let aps = apic_ids[..count].iter().filter(|&&id| id != bsp_id);
+    for (cpu_index, &apic_id) in (1..).zip(aps) {
+        let (stack, double_fault_stack) = ap_stack_bounds(cpu_index);
+        map_stack(stack, mapper, frame_allocator).map_err(SmpError::StackMapping)?;
+        map_stack(double_fault_stack, mapper, frame_allocator).map_err(SmpError::StackMapping)?;
+        backtrace::register_stack(stack);
+
+        data.stack_top = stack.top.as_u64();
+        data.cpu_index = cpu_index as u64;
+        AP_STARTED.store(false, Ordering::SeqCst);
+
+        // The second SIPI is only needed if the AP missed the first
+        local_apic.send_init(apic_id);
+        delay(INIT_DELAY_NANOS);
+        local_apic.send_startup(apic_id, (TRAMPOLINE_ADDR >> 12) as u8);
+        delay(SIPI_DELAY_NANOS);
+        if !AP_STARTED.load(Ordering::SeqCst) {
+            local_apic.send_startup(apic_id, (TRAMPOLINE_ADDR >> 12) as u8);
+        }
+        if !wait_for_ap() {
+            return Err(SmpError::ApTimeout(apic_id));
+        }
+    }
+
+    Ok(online_cpus())
+}
+
+/// Waits for the AP being started to report that it is running, returning false on timeout
+#[must_use]
+fn wait_for_ap() -> bool {
+    for _ in 0..AP_START_TIMEOUT_NANOS / INIT_DELAY_NANOS {
+        if AP_STARTED.load(Ordering::SeqCst) {
+            return true;
+        }
+        delay(INIT_DELAY_NANOS);
+    }
+    AP_STARTED.load(Ordering::SeqCst)
+}
+
+/// Entry point of an AP, which the trampoline calls on the AP's own stack
+extern "C" fn ap_main(cpu_index: u64) -> ! {
+    let (_, double_fault_stack) = ap_stack_bounds(cpu_index as usize);
+    let tss = gdt::init_ap(double_fault_stack);
+    percpu::init(cpu_index as usize, tss);
+    interrupts::init_idt();
+    if let Some(local_apic) = apic::local_apic() {
+        local_apic.enable();
+        percpu::current().set_apic_id(local_apic.id());
+    }
+
+    ONLINE_CPUS.fetch_add(1, Ordering::SeqCst);
+    AP_STARTED.store(true, Ordering::SeqCst);
+
+    // Run Send Tasks, halting until an IPI wakes the AP whenever there are none
+    smp_executor::run()
+}
@@ -0,0 +1,189 @@
+//! This module resolves code addresses to the name of the function containing them, so
+//! crash reports can print `function+offset` rather than raw addresses.
+//!
+//! The bootloader copies the whole kernel ELF file into physical memory before loading its
+//! segments, and only strips debug information from it, so the ELF symbol table (.symtab)
+//! and its string table remain available. This module locates them through the physical
+//! memory mapping and searches them in place, so resolving addresses does not allocate and
+//! can be done from the panic handler.
+
+use crate::bytes::read;
+use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use conquer_once::spin::OnceCell;
+use core::{fmt, mem};
+use x86_64::VirtAddr;
+
+/// Magic bytes at the start of every ELF file
+const ELF_MAGIC: &[u8; 4] = b"\x7fELF";
+
+/// Section header type of the symbol table
+const SHT_SYMTAB: u32 = 2;
+
+/// Symbol type of a function
+const STT_FUNC: u8 = 2;
+
+/// Offsets of the section header table fields in the ELF64 file header
+const E_SHOFF: usize = 0x28;
+const E_SHENTSIZE: usize = 0x3a;
+const E_SHNUM: usize = 0x3c;
+
+/// ELF64 section header
+#[derive(Clone, Copy)]
+#[repr(C)]
+struct SectionHeader {
+    name: u32,
+    section_type: u32,
+    flags: u64,
+    addr: u64,
+    offset: u64,
+    size: u64,
+    link: u32,
+    info: u32,
+    addralign: u64,
+    entsize: u64,
+}
+
+/// ELF64 symbol table entry
+#[derive(Clone, Copy)]
+#[repr(C)]
+struct ElfSymbol {
+    name: u32,
+    info: u8,
+    other: u8,
+    shndx: u16,
+    value: u64,
+    size: u64,
+}
+
+/// Location of the symbol and string tables within the kernel ELF file
+struct SymbolTable {
+    elf: &'static [u8],
+    symtab_offset: usize,
+    symbol_count: usize,
+    strtab_offset: usize,
+    strtab_size: usize,
+}
+
+/// The kernel's symbol table, which is found once by init()
+static SYMBOL_TABLE: OnceCell<SymbolTable> = OnceCell::uninit();
+
+impl SymbolTable {
+    /// Parses the section headers of `elf` to find the symbol table and its string table
+    fn parse(elf: &'static [u8]) -> Option<SymbolTable> {
+        if elf.get(..4)? != ELF_MAGIC {
+            return None;
+        }
+
+        let shoff = usize::try_from(read::<u64>(elf, E_SHOFF)?).ok()?;
+        let shentsize = usize::from(read::<u16>(elf, E_SHENTSIZE)?);
+        let shnum = usize::from(read::<u16>(elf, E_SHNUM)?);
+
+        let section = |index: usize| read::<SectionHeader>(elf, shoff + index * shentsize);
+        let symtab = (0..shnum)
+            .filter_map(section)
+            .find(|header| header.section_type == SHT_SYMTAB)?;
+        let strtab = section(usize::try_from(symtab.link).ok()?)?;
+
+        let table = SymbolTable {
+            elf,
+            symtab_offset: usize::try_from(symtab.offset).ok()?,
+            symbol_count: usize::try_from(symtab.size).ok()? / mem::size_of::<ElfSymbol>(),
+            strtab_offset: usize::try_from(strtab.offset).ok()?,
+            strtab_size: usize::try_from(strtab.size).ok()?,
+        };
+
+        // Reject tables which extend beyond the end of the file
+        let symtab_end = table.symtab_offset + table.symbol_count * mem::size_of::<ElfSymbol>();
+        let strtab_end = table.strtab_offset + table.strtab_size;
+        (symtab_end <= elf.len() && strtab_end <= elf.len()).then_some(table)
+    }
+
+    /// Returns the symbol table entry at `index`
+    fn symbol(&self, index: usize) -> Option<ElfSymbol> {
+        read(
+            self.elf,
+            self.symtab_offset + index * mem::size_of::<ElfSymbol>(),
+        )
+    }
+
+    /// Returns the null terminated string at `offset` in the string table
+    fn name(&self, offset: u32) -> Option<&'static str> {
+        let strtab = &self.elf[self.strtab_offset..self.strtab_offset + self.strtab_size];
+        let bytes = strtab.get(usize::try_from(offset).ok()?..)?;
+        let len = bytes.iter().position(|&byte| byte == 0)?;
+        core::str::from_utf8(&bytes[..len]).ok()
+    }
+
+    /// Finds the function whose address range contains `addr`
+    fn lookup(&self, addr: u64) -> Option<Symbol> {
+        (0..self.symbol_count)
+            .filter_map(|index| self.symbol(index))
+            .filter(|symbol| symbol.info & 0xf == STT_FUNC)
+            .find(|symbol| addr >= symbol.value && addr - symbol.value < symbol.size.max(1))
+            .and_then(|symbol| {
+                Some(Symbol {
+                    name: self.name(symbol.name)?,
+                    offset: addr - symbol.value,
+                })
+            })
+    }
+}
+
+/// Locates the kernel's symbol table so that addresses can be resolved.
+///
+/// If the kernel ELF file cannot be found in memory, addresses are printed without symbols.
+///
+/// # Safety
+///
+/// This function is unsafe because the caller must guarantee that the complete physical
+/// memory is mapped to virtual memory at the passed `physical_memory_offset`, and that
+/// `memory_map` is the one passed by the bootloader.
+pub unsafe fn init(memory_map: &'static MemoryMap, physical_memory_offset: VirtAddr) {
+    // The bootloader marks both the ELF file and the loaded segments as Kernel, so the
+    // ELF file is the Kernel region which starts with the ELF magic bytes
+    let table = memory_map
+        .iter()
+        .filter(|region| region.region_type == MemoryRegionType::Kernel)
+        .find_map(|region| {
+            let start = physical_memory_offset + region.range.start_addr();
+            let len = region.range.end_addr() - region.range.start_addr();
+            let elf = unsafe { core::slice::from_raw_parts(start.as_ptr(), len as usize) };
+            SymbolTable::parse(elf)
+        });
+
+    if let Some(table) = table {
+        SYMBOL_TABLE.init_once(|| table);
+    }
+}
+
+/// A function symbol and the offset of an address from its start
+#[derive(Debug, Clone, Copy)]
+pub struct Symbol {
+    pub name: &'static str,
+    pub offset: u64,
+}
+
+/// Resolves `addr` to the function containing it, if the symbol table has been loaded
+pub fn resolve(addr: u64) -> Option<Symbol> {
+    SYMBOL_TABLE.try_get().ok()?.lookup(addr)
+}
+
+/// Formats an address followed by `function+offset` if the address can be resolved
+#[derive(Debug, Clone, Copy)]
+pub struct Symbolized(pub u64);
+
+impl fmt::Display for Symbolized {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:#018x}", self.0)?;
+        if let Some(symbol) = resolve(self.0) {
+            // The alternate format omits the hash suffix of the mangled name
+            write!(
+                f,
+                " {:#}+{:#x}",
+                rustc_demangle::demangle(symbol.name),
+                symbol.offset
+            )?;
+        }
+        Ok(())
+    }
+}
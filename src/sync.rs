@@ -0,0 +1,203 @@
+//! This module provides spinlocks which are safe to share with interrupt handlers.
+//!
+//! If an interrupt handler takes a spinlock which the code it interrupted is holding, the
+//! handler spins forever, as the holder cannot run until the handler returns. These locks
+//! avoid that by disabling interrupts while they are held. Whether interrupts were enabled is
+//! saved when the lock is taken, and restored when the guard is dropped, so the locks can be
+//! taken in interrupt handlers and in code which has already disabled interrupts.
+
+use core::{
+    mem::ManuallyDrop,
+    ops::{Deref, DerefMut},
+};
+use spin::{Mutex, MutexGuard, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use x86_64::instructions::interrupts;
+
+/// Disables interrupts, returning whether they were enabled
+fn save_and_disable() -> bool {
+    let were_enabled = interrupts::are_enabled();
+    interrupts::disable();
+    were_enabled
+}
+
+/// Re-enables interrupts if they were enabled before save_and_disable
+fn restore(were_enabled: bool) {
+    if were_enabled {
+        interrupts::enable();
+    }
+}
+
+/// A spin::Mutex which disables interrupts while it is locked
+pub struct IrqSafeMutex<T> {
+    inner: Mutex<T>,
+}
+
+/// Guard of an IrqSafeMutex, which unlocks it then restores interrupts when dropped
+pub struct IrqSafeMutexGuard<'a, T> {
+    guard: ManuallyDrop<MutexGuard<'a, T>>,
+    were_enabled: bool,
+}
+
+impl<T> IrqSafeMutex<T> {
+    pub const fn new(value: T) -> Self {
+        IrqSafeMutex {
+            inner: Mutex::new(value),
+        }
+    }
+
+    /// Disables interrupts, then spins until the lock is acquired
+    pub fn lock(&self) -> IrqSafeMutexGuard<'_, T> {
+        let were_enabled = save_and_disable();
+        IrqSafeMutexGuard {
+            guard: ManuallyDrop::new(self.inner.lock()),
+            were_enabled,
+        }
+    }
+
+    /// Acquires the lock with interrupts disabled if it is free, otherwise
+    /// restores interrupts and returns None
+    pub fn try_lock(&self) -> Option<IrqSafeMutexGuard<'_, T>> {
+        let were_enabled = save_and_disable();
+        match self.inner.try_lock() {
+            Some(guard) => Some(IrqSafeMutexGuard {
+                guard: ManuallyDrop::new(guard),
+                were_enabled,
+            }),
+            None => {
+                restore(were_enabled);
+                None
+            }
+        }
+    }
+}
+
+impl<T> Deref for IrqSafeMutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<T> DerefMut for IrqSafeMutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+impl<T> Drop for IrqSafeMutexGuard<'_, T> {
+    fn drop(&mut self) {
+        // Unlock before enabling interrupts, so a handler cannot find the lock held
+        unsafe { ManuallyDrop::drop(&mut self.guard) };
+        restore(self.were_enabled);
+    }
+}
+
+/// A spin::RwLock which disables interrupts while it is locked
+pub struct IrqSafeRwLock<T> {
+    inner: RwLock<T>,
+}
+
+/// Shared guard of an IrqSafeRwLock, which unlocks it then restores interrupts when dropped
+pub struct IrqSafeRwLockReadGuard<'a, T> {
+    guard: ManuallyDrop<RwLockReadGuard<'a, T>>,
+    were_enabled: bool,
+}
+
+/// Exclusive guard of an IrqSafeRwLock, which unlocks it then restores interrupts when dropped
+pub struct IrqSafeRwLockWriteGuard<'a, T> {
+    guard: ManuallyDrop<RwLockWriteGuard<'a, T>>,
+    were_enabled: bool,
+}
+
+impl<T> IrqSafeRwLock<T> {
+    pub const fn new(value: T) -> Self {
+        IrqSafeRwLock {
+            inner: RwLock::new(value),
+        }
+    }
+
+    /// Disables interrupts, then spins until shared access is acquired
+    pub fn read(&self) -> IrqSafeRwLockReadGuard<'_, T> {
+        let were_enabled = save_and_disable();
+        IrqSafeRwLockReadGuard {
+            guard: ManuallyDrop::new(self.inner.read()),
+            were_enabled,
+        }
+    }
+
+    /// Disables interrupts, then spins until exclusive access is acquired
+    pub fn write(&self) -> IrqSafeRwLockWriteGuard<'_, T> {
+        let were_enabled = save_and_disable();
+        IrqSafeRwLockWriteGuard {
+            guard: ManuallyDrop::new(self.inner.write()),
+            were_enabled,
+        }
+    }
+}
+
+impl<T> Deref for IrqSafeRwLockReadGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<T> Drop for IrqSafeRwLockReadGuard<'_, T> {
+    fn drop(&mut self) {
+        unsafe { ManuallyDrop::drop(&mut self.guard) };
+        restore(self.were_enabled);
+    }
+}
+
+impl<T> Deref for IrqSafeRwLockWriteGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<T> DerefMut for IrqSafeRwLockWriteGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+impl<T> Drop for IrqSafeRwLockWriteGuard<'_, T> {
+    fn drop(&mut self) {
+        unsafe { ManuallyDrop::drop(&mut self.guard) };
+        restore(self.were_enabled);
+    }
+}
+
+#[test_case]
+fn mutex_disables_interrupts_while_locked() {
+    let mutex = IrqSafeMutex::new(0);
+    assert!(interrupts::are_enabled());
+    {
+        let mut guard = mutex.lock();
+        assert!(!interrupts::are_enabled());
+        *guard += 1;
+    }
+    assert!(interrupts::are_enabled());
+    assert_eq!(*mutex.lock(), 1);
+}
+
+#[test_case]
+fn nested_locks_restore_outer_state() {
+    let outer = IrqSafeMutex::new(());
+    let inner = IrqSafeRwLock::new(());
+    {
+        let _outer = outer.lock();
+        {
+            let _inner = inner.write();
+        }
+        // The inner guard saw interrupts already disabled, so it leaves them disabled
+        assert!(!interrupts::are_enabled());
+        let _read = inner.read();
+        assert!(outer.try_lock().is_none());
+    }
+    assert!(interrupts::are_enabled());
+}
@@ -1,7 +1,7 @@
 //! This module provides a thin wrapper around a Future which is the basis of a cooperative
 //! multitasking mechanism which this kernel provides.
 
-use alloc::boxed::Box;
+use alloc::{boxed::Box, string::String};
 use core::{
     future::Future,
     pin::Pin,
@@ -9,13 +9,28 @@ use core::{
     task::{Context, Poll},
 };
 
+pub mod combinator;
+pub mod console;
 pub mod executor;
+pub mod irq_channel;
+pub mod join_handle;
 pub mod keyboard;
+pub mod scope;
+pub mod serial;
 pub mod simple_executor;
+pub mod smp_executor;
+pub mod sync;
+pub mod task_local;
+pub mod test_executor;
+pub mod timer;
+
+pub use join_handle::{JoinError, JoinHandle};
+pub use scope::{Scope, scope};
+use task_local::TaskLocals;
 
 /// Identifier for Task instances
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
-struct TaskId(u64);
+pub struct TaskId(u64);
 
 impl TaskId {
     fn new() -> Self {
@@ -25,12 +40,25 @@ impl TaskId {
         // Atomically fetch and add NEXT_ID to get a guaranteed unique ID
         TaskId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
     }
+
+    /// Returns the raw ID, so it can be stored in an atomic
+    pub(crate) fn as_u64(self) -> u64 {
+        self.0
+    }
+
+    /// Recreates a TaskId from a raw ID returned by as_u64
+    pub(crate) fn from_u64(id: u64) -> Self {
+        TaskId(id)
+    }
 }
 
 /// A Task is a thin wrapper around a Future
 pub struct Task {
     id: TaskId,
 
+    /// Name of the Task, which the Executor lists alongside its statistics
+    name: Option<String>,
+
     /// The Task has a reference to a Future which has no
     /// return value (it is just executed for its side effects)
     ///
@@ -41,19 +69,50 @@ pub struct Task {
     /// struct in the state of the Future, undefined behaviour will not be incurred
     /// by copying it around in memory as it is 'Pinned' to a single location.
     future: Pin<Box<dyn Future<Output = ()>>>,
+
+    /// The Task's values of task-locals
+    locals: TaskLocals,
 }
 
 impl Task {
-    /// Creates a new Task by passing it an async function
-    pub fn new(future: impl Future<Output = ()> + 'static) -> Task {
+    /// Creates a new Task by passing it an async function. Its output is discarded,
+    /// use joinable to create a Task whose output can be awaited.
+    pub fn new(future: impl Future + 'static) -> Task {
         Task {
             id: TaskId::new(),
-            future: Box::pin(future),
+            name: None,
+            future: Box::pin(async {
+                future.await;
+            }),
+            locals: TaskLocals::new(),
         }
     }
 
-    /// Invokes the poll method of the Task's Future
+    /// Creates a new Task, and a JoinHandle through which its output can be
+    /// awaited or the Task cancelled
+    pub fn joinable<F>(future: F) -> (Task, JoinHandle<F::Output>)
+    where
+        F: Future + 'static,
+    {
+        let id = TaskId::new();
+        let (future, handle) = join_handle::joinable(id, future);
+        let task = Task {
+            id,
+            name: None,
+            future: Box::pin(future),
+            locals: TaskLocals::new(),
+        };
+        (task, handle)
+    }
+
+    /// Gives the Task a name
+    pub fn named(mut self, name: impl Into<String>) -> Task {
+        self.name = Some(name.into());
+        self
+    }
+
+    /// Invokes the poll method of the Task's Future, with the Task's task-locals current
     fn poll(&mut self, context: &mut Context) -> Poll<()> {
-        self.future.as_mut().poll(context)
+        self.locals.enter(|| self.future.as_mut().poll(context))
     }
 }
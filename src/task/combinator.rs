@@ -0,0 +1,346 @@
+//! This module provides combinators for waiting on several Futures at once.
+//!
+//! The join! macro and join_all wait for every Future, and the select! macro waits for the
+//! first. A FutureSet holds any number of Futures and yields their outputs as they complete,
+//! giving each Future its own Waker, so only those which have been woken are polled again.
+
+use crate::sync::IrqSafeMutex;
+use alloc::{boxed::Box, collections::VecDeque, sync::Arc, task::Wake, vec::Vec};
+use core::{
+    future::Future,
+    mem,
+    pin::Pin,
+    sync::atomic::{AtomicBool, Ordering},
+    task::{Context, Poll, Waker},
+};
+use futures_util::{Stream, task::AtomicWaker};
+
+/// Waits for every Future, returning a tuple of their outputs. This must be used in an
+/// async block or function, as it awaits the Futures.
+///
+/// ```ignore
+/// let (scancode, ()) = join!(scancodes.next(), sleep(1_000_000));
+/// ```
+#[macro_export]
+macro_rules! join {
+    // Each Future is paired with a name for its slot, so at most eight can be joined
+    (@munch [$(($name:ident $future:expr))+] [$($names:ident)*]) => {{
+        $(
+            let mut $name = ::core::pin::pin!($crate::task::combinator::MaybeDone::new($future));
+        )+
+        ::core::future::poll_fn(|cx| {
+            let mut done = true;
+            $(
+                done &= $name.as_mut().poll_done(cx);
+            )+
+            if done {
+                ::core::task::Poll::Ready(($($name.as_mut().take_output(),)+))
+            } else {
+                ::core::task::Poll::Pending
+            }
+        })
+        .await
+    }};
+    (@munch [$($done:tt)*] [$name:ident $($names:ident)*] $future:expr $(, $rest:expr)*) => {
+        $crate::join!(@munch [$($done)* ($name $future)] [$($names)*] $($rest),*)
+    };
+    ($($future:expr),+ $(,)?) => {
+        $crate::join!(@munch [] [f0 f1 f2 f3 f4 f5 f6 f7] $($future),+)
+    };
+}
+
+/// Waits for the first of several Futures to complete, then drops the others and runs the
+/// handler of the one which completed. This must be used in an async block or function.
+///
+/// The Futures are polled in the order they are written, so an earlier branch wins if
+/// several are ready. Each pattern must match any output of its Future.
+///
+/// ```ignore
+/// select! {
+///     scancode = scancodes.next() => handle(scancode),
+///     () = sleep(1_000_000_000) => println!("no key pressed"),
+/// }
+/// ```
+#[macro_export]
+macro_rules! select {
+    // Each branch is paired with names for its Future and output, so at most eight can be selected
+    (@munch [$(($name:ident $output:ident $pattern:pat, $future:expr, $handler:expr))+] [$($names:tt)*]) => {{
+        $(
+            let mut $output = ::core::option::Option::None;
+        )+
+
+        // The Futures are dropped at the end of this block, before the handler runs
+        {
+            $(
+                let mut $name = ::core::pin::pin!($future);
+            )+
+            ::core::future::poll_fn(|cx| {
+                $(
+                    if let ::core::task::Poll::Ready(output) =
+                        ::core::future::Future::poll($name.as_mut(), cx)
+                    {
+                        $output = ::core::option::Option::Some(output);
+                        return ::core::task::Poll::Ready(());
+                    }
+                )+
+                ::core::task::Poll::Pending
+            })
+            .await;
+        }
+
+        $(
+            if let ::core::option::Option::Some($pattern) = $output {
+                $handler
+            } else
+        )+
+        {
+            ::core::unreachable!("select! completed without an output")
+        }
+    }};
+    (
+        @munch [$($done:tt)*] [($name:ident $output:ident) $($names:tt)*]
+        $pattern:pat = $future:expr => $handler:expr, $($rest:tt)*
+    ) => {
+        $crate::select!(
+            @munch [$($done)* ($name $output $pattern, $future, $handler)] [$($names)*]
+            $($rest)*
+        )
+    };
+    ($($pattern:pat = $future:expr => $handler:expr),+ $(,)?) => {
+        $crate::select!(
+            @munch [] [(f0 o0) (f1 o1) (f2 o2) (f3 o3) (f4 o4) (f5 o5) (f6 o6) (f7 o7)]
+            $($pattern = $future => $handler,)+
+        )
+    };
+}
+
+/// A Future which keeps its output once it has completed, until it is taken.
+/// This is used by join! and join_all, and is not intended to be used directly.
+#[doc(hidden)]
+pub enum MaybeDone<F: Future> {
+    Future(F),
+    Done(F::Output),
+    Taken,
+}
+
+impl<F: Future> MaybeDone<F> {
+    pub fn new(future: F) -> Self {
+        MaybeDone::Future(future)
+    }
+
+    /// Polls the Future if it has not completed, returning whether it has
+    pub fn poll_done(self: Pin<&mut Self>, cx: &mut Context) -> bool {
+        // The Future is never moved out, only replaced by its output once it has completed
+        let this = unsafe { self.get_unchecked_mut() };
+        let output = match this {
+            MaybeDone::Future(future) => match unsafe { Pin::new_unchecked(future) }.poll(cx) {
+                Poll::Ready(output) => output,
+                Poll::Pending => return false,
+            },
+            MaybeDone::Done(_) | MaybeDone::Taken => return true,
+        };
+        *this = MaybeDone::Done(output);
+        true
+    }
+
+    /// Takes the output, panicking if the Future has not completed or the output was taken
+    pub fn take_output(self: Pin<&mut Self>) -> F::Output {
+        // Only the output is moved out, which is not pinned
+        let this = unsafe { self.get_unchecked_mut() };
+        match mem::replace(this, MaybeDone::Taken) {
+            MaybeDone::Done(output) => output,
+            _ => panic!("MaybeDone output taken before completion, or twice"),
+        }
+    }
+}
+
+/// Returns a Future which waits for every Future, and outputs their outputs in the same order
+pub fn join_all<I>(futures: I) -> JoinAll<I::Item>
+where
+    I: IntoIterator,
+    I::Item: Future,
+{
+    JoinAll {
+        futures: Box::into_pin(futures.into_iter().map(MaybeDone::new).collect()),
+    }
+}
+
+/// Future returned by join_all
+pub struct JoinAll<F: Future> {
+    futures: Pin<Box<[MaybeDone<F>]>>,
+}
+
+impl<F: Future> Future for JoinAll<F> {
+    type Output = Vec<F::Output>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Vec<F::Output>> {
+        let mut done = true;
+        for future in pinned_elements(self.futures.as_mut()) {
+            done &= future.poll_done(cx);
+        }
+        if done {
+            let outputs = pinned_elements(self.futures.as_mut())
+                .map(MaybeDone::take_output)
+                .collect();
+            Poll::Ready(outputs)
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+/// Returns pinned references to the elements of a pinned slice
+fn pinned_elements<T>(slice: Pin<&mut [T]>) -> impl Iterator<Item = Pin<&mut T>> {
+    // The elements are pinned along with the slice, and none of them are moved
+    unsafe { slice.get_unchecked_mut() }
+        .iter_mut()
+        .map(|element| unsafe { Pin::new_unchecked(element) })
+}
+
+/// The indices of the Futures in a FutureSet which have been woken, and the
+/// Waker of the Task polling the set
+struct ReadyQueue {
+    indices: IrqSafeMutex<VecDeque<usize>>,
+    parent: AtomicWaker,
+}
+
+/// Waker of a Future in a FutureSet, which queues its index and wakes the Task polling the set
+struct EntryWaker {
+    index: usize,
+
+    /// Whether the index is on the ReadyQueue, so repeated wakes only queue it once
+    queued: AtomicBool,
+    ready: Arc<ReadyQueue>,
+}
+
+struct Entry<F> {
+    future: Pin<Box<F>>,
+    waker: Arc<EntryWaker>,
+}
+
+/// A set of Futures which is a Stream of their outputs, in the order they complete.
+///
+/// Each Future gets its own Waker, so polling the set only polls the Futures which have been
+/// woken, however many there are. The Stream ends once the set is empty, and more Futures can
+/// be pushed at any time.
+pub struct FutureSet<F> {
+    /// Futures indexed by the index their Wakers queue, with None for free slots
+    entries: Vec<Option<Entry<F>>>,
+
+    /// Indices of free slots in entries
+    free: Vec<usize>,
+    ready: Arc<ReadyQueue>,
+    len: usize,
+}
+
+impl<F: Future> FutureSet<F> {
+    pub fn new() -> Self {
+        FutureSet {
+            entries: Vec::new(),
+            free: Vec::new(),
+            ready: Arc::new(ReadyQueue {
+                indices: IrqSafeMutex::new(VecDeque::new()),
+                parent: AtomicWaker::new(),
+            }),
+            len: 0,
+        }
+    }
+
+    /// Adds a Future, which is first polled the next time the set is
+    pub fn push(&mut self, future: F) {
+        let index = self.free.pop().unwrap_or(self.entries.len());
+        let waker = Arc::new(EntryWaker {
+            index,
+            queued: AtomicBool::new(false),
+            ready: self.ready.clone(),
+        });
+        waker.wake_by_ref();
+        let entry = Some(Entry {
+            future: Box::pin(future),
+            waker,
+        });
+        if index == self.entries.len() {
+            self.entries.push(entry);
+        } else {
+            self.entries[index] = entry;
+        }
+        self.len += 1;
+    }
+
+    /// Returns the number of Futures which have not completed
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Drops every Future, cancelling them
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.free.clear();
+        self.ready.indices.lock().clear();
+        self.len = 0;
+    }
+}
+
+impl<F: Future> Default for FutureSet<F> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<F: Future> Stream for FutureSet<F> {
+    type Item = F::Output;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<F::Output>> {
+        self.ready.parent.register(cx.waker());
+
+        // Poll at most as many Futures as were queued, so a Future which keeps waking
+        // itself cannot keep the set from returning
+        let queued = self.ready.indices.lock().len();
+        for _ in 0..queued {
+            let Some(index) = self.ready.indices.lock().pop_front() else {
+                break;
+            };
+            let Some(entry) = self.entries.get_mut(index).and_then(Option::as_mut) else {
+                continue; // the Future has completed, or the set was cleared
+            };
+
+            // Clear the flag before polling, so a wake during the poll queues it again
+            entry.waker.queued.store(false, Ordering::SeqCst);
+            let waker = Waker::from(entry.waker.clone());
+            if let Poll::Ready(output) =
+                entry.future.as_mut().poll(&mut Context::from_waker(&waker))
+            {
+                self.entries[index] = None;
+                self.free.push(index);
+                self.len -= 1;
+                return Poll::Ready(Some(output));
+            }
+        }
+
+        if self.len == 0 {
+            Poll::Ready(None)
+        } else {
+            if !self.ready.indices.lock().is_empty() {
+                cx.waker().wake_by_ref();
+            }
+            Poll::Pending
+        }
+    }
+}
+
+impl Wake for EntryWaker {
+    fn wake(self: Arc<Self>) {
+        self.wake_by_ref();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        if !self.queued.swap(true, Ordering::SeqCst) {
+            self.ready.indices.lock().push_back(self.index);
+        }
+        self.ready.parent.wake();
+    }
+}
@@ -0,0 +1,285 @@
+//! This module provides an interactive console, which runs as a Task reading commands from
+//! the console serial port (COM1 unless changed), so the kernel can be driven headlessly,
+//! for example from QEMU's `-serial stdio` in automated tests.
+//!
+//! The Console itself only edits lines and runs commands, writing its output to any
+//! fmt::Write, so it can be driven by other inputs and outputs too.
+
+use super::keyboard::{self, Layout};
+use super::{executor::Spawner, serial::SerialStream};
+use crate::serial::{self, ComPort};
+use crate::{QemuExitCode, exit_qemu, smp, time};
+use alloc::string::String;
+use core::fmt::{self, Write};
+use futures_util::StreamExt;
+
+/// Printed before each line of input
+pub const PROMPT: &str = "> ";
+
+/// Maximum length of a line, beyond which input is ignored until the line ends
+const MAX_LINE: usize = 256;
+
+/// A console command, which is called with the rest of the line as its arguments
+struct Command {
+    name: &'static str,
+    help: &'static str,
+    run: fn(&mut CommandContext, &str) -> fmt::Result,
+}
+
+/// What a command has access to while it runs
+struct CommandContext<'a> {
+    out: &'a mut dyn Write,
+    spawner: Option<&'a Spawner>,
+}
+
+/// Commands in the order help lists them
+const COMMANDS: &[Command] = &[
+    Command {
+        name: "help",
+        help: "list the commands",
+        run: help,
+    },
+    Command {
+        name: "echo",
+        help: "print the arguments",
+        run: echo,
+    },
+    Command {
+        name: "uptime",
+        help: "print the time since boot",
+        run: uptime,
+    },
+    Command {
+        name: "cpus",
+        help: "print the number of CPUs online",
+        run: cpus,
+    },
+    Command {
+        name: "layout",
+        help: "print the keyboard layout, or change it to the one named",
+        run: layout,
+    },
+    Command {
+        name: "serial",
+        help: "list the serial ports and their line settings",
+        run: serial_ports,
+    },
+    Command {
+        name: "tasks",
+        help: "list the tasks on the executor",
+        run: tasks,
+    },
+    Command {
+        name: "exit",
+        help: "exit QEMU, with a failure code if the argument is \"failed\"",
+        run: exit,
+    },
+];
+
+/// A line editor and command interpreter which writes to `out`
+pub struct Console<W> {
+    out: W,
+
+    /// The line being typed
+    line: String,
+
+    /// Whether the last byte ended a line with a carriage return, so a line feed
+    /// following it does not end another line
+    after_cr: bool,
+
+    /// Spawner of the Executor whose Tasks the tasks command lists
+    spawner: Option<Spawner>,
+}
+
+impl<W: Write> Console<W> {
+    pub fn new(out: W) -> Self {
+        Console {
+            out,
+            line: String::new(),
+            after_cr: false,
+            spawner: None,
+        }
+    }
+
+    /// Lets the tasks command list the Tasks of the Executor `spawner` spawns onto
+    pub fn with_spawner(mut self, spawner: Spawner) -> Self {
+        self.spawner = Some(spawner);
+        self
+    }
+
+    /// Returns the output
+    pub fn output(&self) -> &W {
+        &self.out
+    }
+
+    /// Prints the prompt
+    pub fn prompt(&mut self) -> fmt::Result {
+        self.out.write_str(PROMPT)
+    }
+
+    /// Handles a byte of input, echoing it, and runs the line once it ends with a
+    /// carriage return or line feed.
+    ///
+    /// Backspace and delete remove the last character, and Ctrl-C discards the line.
+    pub fn input(&mut self, byte: u8) -> fmt::Result {
+        let after_cr = core::mem::replace(&mut self.after_cr, byte == b'\r');
+        match byte {
+            b'\n' if after_cr => Ok(()),
+            b'\r' | b'\n' => {
+                self.out.write_str("\n")?;
+                let line = core::mem::take(&mut self.line);
+                self.execute(&line)?;
+                self.prompt()
+            }
+            0x08 | 0x7f => match self.line.pop() {
+                Some(_) => self.out.write_str("\x08 \x08"),
+                None => Ok(()),
+            },
+            0x03 => {
+                self.line.clear();
+                self.out.write_str("^C\n")?;
+                self.prompt()
+            }
+            b' '..=b'~' if self.line.len() < MAX_LINE => {
+                self.line.push(char::from(byte));
+                self.out.write_char(char::from(byte))
+            }
+
+            // Other control characters, bytes beyond ASCII and input beyond MAX_LINE
+            _ => Ok(()),
+        }
+    }
+
+    /// Runs a line of input
+    pub fn execute(&mut self, line: &str) -> fmt::Result {
+        let line = line.trim();
+        if line.is_empty() {
+            return Ok(());
+        }
+        let (name, args) = line.split_once(' ').unwrap_or((line, ""));
+        let mut context = CommandContext {
+            out: &mut self.out,
+            spawner: self.spawner.as_ref(),
+        };
+        match COMMANDS.iter().find(|command| command.name == name) {
+            Some(command) => (command.run)(&mut context, args.trim()),
+            None => writeln!(
+                context.out,
+                "unknown command: {} (type help for a list)",
+                name
+            ),
+        }
+    }
+}
+
+/// Output of a console on a serial port
+pub struct SerialOutput(pub ComPort);
+
+impl Write for SerialOutput {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        serial::write_fmt(self.0, format_args!("{}", s))
+    }
+}
+
+/// Runs a Console on the console port until the SerialStream ends. Only one can run on
+/// each port, as there is only one SerialStream of each.
+pub async fn run_serial_console(spawner: Spawner) {
+    let port = serial::console_port();
+    let mut bytes = SerialStream::for_port(port);
+    let mut console = Console::new(SerialOutput(port)).with_spawner(spawner);
+    console.prompt().expect("Printing to serial failed");
+    while let Some(byte) = bytes.next().await {
+        console.input(byte).expect("Printing to serial failed");
+    }
+}
+
+fn help(context: &mut CommandContext, _args: &str) -> fmt::Result {
+    for command in COMMANDS {
+        writeln!(context.out, "{:<8} {}", command.name, command.help)?;
+    }
+    Ok(())
+}
+
+fn echo(context: &mut CommandContext, args: &str) -> fmt::Result {
+    writeln!(context.out, "{}", args)
+}
+
+fn uptime(context: &mut CommandContext, _args: &str) -> fmt::Result {
+    let millis = time::now() / 1_000_000;
+    writeln!(context.out, "{}.{:03} s", millis / 1000, millis % 1000)
+}
+
+fn cpus(context: &mut CommandContext, _args: &str) -> fmt::Result {
+    writeln!(
+        context.out,
+        "{} of {} CPUs online",
+        smp::online_cpus(),
+        smp::cpu_count()
+    )
+}
+
+fn layout(context: &mut CommandContext, args: &str) -> fmt::Result {
+    if !args.is_empty() {
+        match Layout::from_name(args) {
+            Some(layout) => keyboard::set_layout(layout),
+            None => write!(context.out, "unknown layout: {}; ", args)?,
+        }
+    }
+    write!(context.out, "layout {} (available:", keyboard::layout())?;
+    for layout in Layout::ALL {
+        write!(context.out, " {}", layout)?;
+    }
+    writeln!(context.out, ")")
+}
+
+fn serial_ports(context: &mut CommandContext, _args: &str) -> fmt::Result {
+    for port in ComPort::ALL {
+        write!(context.out, "{} {:#x} ", port, port.base())?;
+        match serial::config(port) {
+            Ok(config) => write!(context.out, "{}", config)?,
+            Err(_) => write!(context.out, "not present")?,
+        }
+        if port == serial::log_port() {
+            write!(context.out, " (log)")?;
+        }
+        if port == serial::console_port() {
+            write!(context.out, " (console)")?;
+        }
+        writeln!(context.out)?;
+    }
+    Ok(())
+}
+
+fn tasks(context: &mut CommandContext, _args: &str) -> fmt::Result {
+    let Some(spawner) = context.spawner else {
+        return writeln!(context.out, "no executor attached");
+    };
+    writeln!(
+        context.out,
+        "{:>5} {:<16} {:>8} {:>12}",
+        "id", "name", "polls", "longest us"
+    )?;
+    for task in spawner.tasks() {
+        writeln!(
+            context.out,
+            "{:>5} {:<16} {:>8} {:>12}",
+            task.id.as_u64(),
+            task.name.as_deref().unwrap_or("-"),
+            task.polls,
+            task.longest_poll / 1000
+        )?;
+    }
+    Ok(())
+}
+
+fn exit(context: &mut CommandContext, args: &str) -> fmt::Result {
+    let code = match args {
+        "" => QemuExitCode::Success,
+        "failed" => QemuExitCode::Failed,
+        _ => return writeln!(context.out, "usage: exit [failed]"),
+    };
+    exit_qemu(code);
+
+    // Writing the exit code only returns if QEMU has no isa-debug-exit device
+    writeln!(context.out, "no isa-debug-exit device to exit through")
+}
@@ -1,11 +1,81 @@
 //! This module maintains a queue of TaskIds and processes the corresponding Tasks.
 //! It makes use of Waker notifications and the halt instruction to sleep while there
 //! are no ready Tasks, which is more efficient than polling the queue of TaskIds.
+//!
+//! The queue grows as needed, so any number of Tasks can be ready at once. Each Task has a
+//! flag recording whether its TaskId is on the queue, so waking a Task which is already
+//! queued does nothing, and a Task is queued at most once however often it is woken.
+//!
+//! The Executor records statistics about each Task's polls and wakes, which tasks() lists.
+//! A poll which takes longer than the poll budget prints a warning, as a Future which blocks
+//! in poll holds up every other Task.
+//!
+//! run never returns, and halts while no Task is ready. run_until_idle polls every ready Task,
+//! including those spawned through Spawners, and returns once none are ready.
 
-use super::{Task, TaskId};
-use alloc::{collections::BTreeMap, sync::Arc, task::Wake};
-use core::task::{Context, Poll, Waker};
-use crossbeam_queue::ArrayQueue;
+use super::{JoinHandle, Task, TaskId, join_handle, task_local::TaskLocals};
+use crate::sync::IrqSafeMutex;
+use crate::{println, time};
+use alloc::{
+    boxed::Box,
+    collections::{BTreeMap, VecDeque},
+    string::String,
+    sync::Arc,
+    task::Wake,
+    vec::Vec,
+};
+use core::{
+    future::Future,
+    pin::Pin,
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+    task::{Context, Poll, Waker},
+};
+
+/// Default poll budget in nanoseconds, over which a single poll prints a warning
+pub const DEFAULT_POLL_BUDGET: u64 = 10_000_000;
+
+/// Queue of the TaskIds of ready Tasks.
+///
+/// The allocator disables interrupts while it is locked, so the queue can grow when a Task is
+/// woken from an interrupt handler without risking a deadlock.
+type RunQueue = IrqSafeMutex<VecDeque<TaskId>>;
+
+/// A Task spawned through a Spawner, whose TaskWaker the Spawner has already added
+/// to the waker_cache
+struct SpawnedTask {
+    id: TaskId,
+    future: Pin<Box<dyn Future<Output = ()> + Send>>,
+}
+
+/// Map of the TaskWakers of Tasks, indexed by the TaskId of the corresponding Task
+type WakerCache = IrqSafeMutex<BTreeMap<TaskId, Arc<TaskWaker>>>;
+
+/// Information about a Task, as returned by tasks()
+#[derive(Debug, Clone)]
+pub struct TaskInfo {
+    pub id: TaskId,
+    pub name: Option<String>,
+
+    /// Whether the Task has been woken and is waiting to be polled
+    pub ready: bool,
+
+    /// Number of times the Task has been polled
+    pub polls: u64,
+
+    /// Nanoseconds spent polling the Task in total
+    pub poll_time: u64,
+
+    /// Nanoseconds spent in the Task's longest poll
+    pub longest_poll: u64,
+
+    /// Number of times the Task's Waker has been woken, including wakes
+    /// while it was already ready
+    pub wakes: u64,
+}
+
+/// Queue of Tasks spawned through Spawners, which the Executor moves into its tasks map
+/// when it next runs. The Futures are Send, so Spawners can be shared with interrupt handlers.
+type SpawnQueue = IrqSafeMutex<VecDeque<SpawnedTask>>;
 
 /// Executor maintains a queue of the TaskIds of ready Tasks, and maps of all
 /// spawned Tasks' Waker and Task structs.
@@ -18,30 +88,81 @@ pub struct Executor {
     ///
     /// The queue is wrapped in an atomic reference counter to enable shared ownership between
     /// Executors and Wakers
-    task_queue: Arc<ArrayQueue<TaskId>>,
+    task_queue: Arc<RunQueue>,
+
+    /// BTreeMap of the TaskWakers of Tasks, which hold their statistics, indexed by the
+    /// TaskId of the corresponding Task. It is shared with Spawners so they can list Tasks.
+    waker_cache: Arc<WakerCache>,
 
-    /// BTreeMap of the Wakers of Tasks, indexed by the TaskId of the corresponding Task
-    waker_cache: BTreeMap<TaskId, Waker>,
+    /// Tasks spawned through Spawners which have not yet been added to the tasks map
+    spawned: Arc<SpawnQueue>,
+
+    /// Nanoseconds a single poll may take before a warning is printed, or None for no limit
+    poll_budget: Option<u64>,
 }
 
 impl Executor {
     pub fn new() -> Self {
         Executor {
             tasks: BTreeMap::new(),
+            task_queue: Arc::new(IrqSafeMutex::new(VecDeque::new())),
+            waker_cache: Arc::new(IrqSafeMutex::new(BTreeMap::new())),
+            spawned: Arc::new(IrqSafeMutex::new(VecDeque::new())),
+            poll_budget: Some(DEFAULT_POLL_BUDGET),
+        }
+    }
 
-            // Task queue has capacity bounded at 100 to avoid any allocations, which could lead to a deadlock
-            task_queue: Arc::new(ArrayQueue::new(100)),
-            waker_cache: BTreeMap::new(),
+    /// Returns a Spawner, through which running Tasks and interrupt handlers
+    /// can spawn Tasks onto this Executor
+    pub fn spawner(&self) -> Spawner {
+        Spawner {
+            spawned: self.spawned.clone(),
+            task_queue: self.task_queue.clone(),
+            waker_cache: self.waker_cache.clone(),
         }
     }
 
+    /// Sets the nanoseconds a single poll may take before a warning is printed,
+    /// or None to never warn
+    pub fn set_poll_budget(&mut self, nanos: Option<u64>) {
+        self.poll_budget = nanos;
+    }
+
+    /// Returns information about every Task which has not completed
+    pub fn tasks(&self) -> Vec<TaskInfo> {
+        list_tasks(&self.waker_cache)
+    }
+
+    /// Spawns a Future as a Task, returning a JoinHandle through which its output
+    /// can be awaited or the Task cancelled
+    pub fn spawn<F>(&mut self, future: F) -> JoinHandle<F::Output>
+    where
+        F: Future + 'static,
+    {
+        let (task, handle) = Task::joinable(future);
+        self.spawn_task(task);
+        handle
+    }
+
+    /// Spawns a Future as a Task with a name, which tasks() lists and warnings print
+    pub fn spawn_named<F>(&mut self, name: impl Into<String>, future: F) -> JoinHandle<F::Output>
+    where
+        F: Future + 'static,
+    {
+        let (task, handle) = Task::joinable(future);
+        self.spawn_task(task.named(name));
+        handle
+    }
+
     /// Spawns a Task by adding it to the tasks map and pushing the TaskId to the task_queue
-    pub fn spawn(&mut self, task: Task) {
+    pub fn spawn_task(&mut self, mut task: Task) {
         let task_id = task.id;
+        let task_waker = TaskWaker::new(task_id, task.name.take(), self.task_queue.clone());
         if self.tasks.insert(task.id, task).is_some() {
             panic!("task with same ID already in tasks");
         }
-        self.task_queue.push(task_id).expect("queue full");
+        self.waker_cache.lock().insert(task_id, task_waker.clone());
+        task_waker.schedule();
     }
 
     /// Process the TaskIds on the task_queue
@@ -51,29 +172,60 @@ impl Executor {
             tasks,
             task_queue,
             waker_cache,
+            spawned,
+            poll_budget,
         } = self;
 
         // Get the next TaskId from the task_queue
-        while let Some(task_id) = task_queue.pop() {
-            // Get the corresponding Task from the tasks map
-            let task = match tasks.get_mut(&task_id) {
-                Some(task) => task,
-                None => continue, // task no longer exists
+        // The lock is released before polling, as the Task may wake itself
+        while let Some(task_id) = task_queue.lock().pop_front() {
+            // A TaskId which is not in the tasks map may belong to a Task spawned through
+            // a Spawner, so move those into the map before looking again
+            if !tasks.contains_key(&task_id) {
+                for SpawnedTask { id, future } in spawned.lock().drain(..) {
+                    let task = Task {
+                        id,
+                        name: None,
+                        future,
+                        locals: TaskLocals::new(),
+                    };
+                    tasks.insert(id, task);
+                }
+            }
+
+            // Get the corresponding Task and TaskWaker
+            let task_waker = waker_cache.lock().get(&task_id).cloned();
+            let (Some(task), Some(task_waker)) = (tasks.get_mut(&task_id), task_waker) else {
+                continue; // task no longer exists
             };
 
-            // Get the corresponding Waker (create one if it does not exist)
-            let waker = waker_cache
-                .entry(task_id)
-                .or_insert_with(|| TaskWaker::new_waker(task_id, task_queue.clone()));
+            // Clear the flag before polling, so a wake during the poll queues the Task again
+            task_waker.scheduled.store(false, Ordering::SeqCst);
+            let waker = Waker::from(task_waker.clone());
+            let mut context = Context::from_waker(&waker);
 
-            let mut context = Context::from_waker(waker);
+            // Poll the task, recording it as this CPU's current Task while it runs
+            crate::per_cpu!(set_current_task(Some(task_id)));
+            let start = time::now();
+            let poll = task.poll(&mut context);
+            let elapsed = time::now().saturating_sub(start);
+            crate::per_cpu!(set_current_task(None));
 
-            // Poll the task
-            match task.poll(&mut context) {
+            task_waker.record_poll(elapsed);
+            if poll_budget.is_some_and(|budget| elapsed > budget) {
+                println!(
+                    "WARNING: task {:?} ({}) took {} us to poll, which may be blocking other tasks",
+                    task_id,
+                    task_waker.name.as_deref().unwrap_or("unnamed"),
+                    elapsed / 1000
+                );
+            }
+
+            match poll {
                 Poll::Ready(()) => {
                     // task done -> remove it and its cached Waker
                     tasks.remove(&task_id);
-                    waker_cache.remove(&task_id);
+                    waker_cache.lock().remove(&task_id);
                 }
 
                 // If the Task is not complete, do not readd its TaskId to the task_queue as it is not ready,
@@ -84,6 +236,15 @@ impl Executor {
         }
     }
 
+    /// Processes Tasks until none are ready, then returns instead of sleeping, so the
+    /// caller can check on the Tasks between interrupts.
+    ///
+    /// This includes Tasks spawned through Spawners, whether before the call or by the Tasks
+    /// it polls. Tasks only woken by a later interrupt are left for the next call.
+    pub fn run_until_idle(&mut self) {
+        self.run_ready_tasks();
+    }
+
     /// Loop which processes Tasks, and sleeps once there are no ready Tasks, until the next interrupt
     ///
     /// Interrupt handlers are the source of ready Tasks so sleeping until the next interrupt is more
@@ -104,7 +265,7 @@ impl Executor {
         // Disable interrupts while checking the task_queue to prevent racing with
         // interrupt handlers which add TaskIds to the task_queue
         interrupts::disable();
-        if self.task_queue.is_empty() {
+        if self.task_queue.lock().is_empty() {
             // If the task queue is empty, re-enable interrupts and sleep until the next interrupt
             enable_and_hlt();
         } else {
@@ -120,27 +281,147 @@ impl Default for Executor {
     }
 }
 
-/// The TaskWaker's job is to push its TaskId to the Executor's task_queue
+/// Handle which spawns Tasks onto an Executor without borrowing it, so running Tasks can
+/// spawn other Tasks.
+///
+/// Spawners are cheap to clone, and as the Futures they spawn must be Send, a Spawner can be
+/// stored in a static and used from interrupt handlers. Futures which are not Send can only
+/// be spawned through Executor::spawn.
+#[derive(Clone)]
+pub struct Spawner {
+    /// The Executor's queue of Tasks spawned through Spawners
+    spawned: Arc<SpawnQueue>,
+
+    /// The Executor's queue of ready TaskIds, which a spawned Task's TaskId is pushed onto
+    task_queue: Arc<RunQueue>,
+
+    /// The Executor's map of TaskWakers, which a spawned Task's TaskWaker is added to
+    waker_cache: Arc<WakerCache>,
+}
+
+impl Spawner {
+    /// Spawns a Future as a Task, returning a JoinHandle through which its output
+    /// can be awaited or the Task cancelled
+    pub fn spawn<F>(&self, future: F) -> JoinHandle<F::Output>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send,
+    {
+        self.spawn_with(None, future)
+    }
+
+    /// Spawns a Future as a Task with a name, which tasks() lists and warnings print
+    pub fn spawn_named<F>(&self, name: impl Into<String>, future: F) -> JoinHandle<F::Output>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send,
+    {
+        self.spawn_with(Some(name.into()), future)
+    }
+
+    /// Returns information about every Task on the Executor which has not completed
+    pub fn tasks(&self) -> Vec<TaskInfo> {
+        list_tasks(&self.waker_cache)
+    }
+
+    fn spawn_with<F>(&self, name: Option<String>, future: F) -> JoinHandle<F::Output>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send,
+    {
+        let id = TaskId::new();
+        let (future, handle) = join_handle::joinable(id, future);
+
+        // The Task must be queued before its TaskId, so the Executor finds it
+        let task_waker = TaskWaker::new(id, name, self.task_queue.clone());
+        self.spawned.lock().push_back(SpawnedTask {
+            id,
+            future: Box::pin(future),
+        });
+        self.waker_cache.lock().insert(id, task_waker.clone());
+        task_waker.schedule();
+        handle
+    }
+}
+
+/// Collects the statistics held by the TaskWakers in a waker_cache
+fn list_tasks(waker_cache: &WakerCache) -> Vec<TaskInfo> {
+    waker_cache
+        .lock()
+        .values()
+        .map(|task_waker| task_waker.info())
+        .collect()
+}
+
+/// The TaskWaker's job is to push its TaskId to the Executor's task_queue. As there is one
+/// per Task, shared by the Executor and the Task's Wakers, it also holds the Task's statistics.
 struct TaskWaker {
     /// TaskId of the Task this TaskWaker is associated with
     task_id: TaskId,
 
+    /// Name of the Task, if it was given one
+    name: Option<String>,
+
     /// Reference to the Executor's task_queue
-    task_queue: Arc<ArrayQueue<TaskId>>,
+    task_queue: Arc<RunQueue>,
+
+    /// Whether the TaskId is on the task_queue, so repeated wakes only queue it once
+    scheduled: AtomicBool,
+
+    // Statistics, which only the Executor's CPU updates except for wakes
+    polls: AtomicU64,
+    poll_time: AtomicU64,
+    longest_poll: AtomicU64,
+    wakes: AtomicU64,
 }
 
 impl TaskWaker {
-    /// Wake the TaskWaker's Task by pushing its TaskId to the Executor's task_queue
+    /// Wake the TaskWaker's Task by pushing its TaskId to the Executor's task_queue,
+    /// unless it is already there
     fn wake_task(&self) {
-        self.task_queue.push(self.task_id).expect("task_queue full");
+        self.wakes.fetch_add(1, Ordering::Relaxed);
+        self.schedule();
     }
 
-    /// Creates a new Waker from the TaskWaker created with the task_id and task_queue arguments
-    fn new_waker(task_id: TaskId, task_queue: Arc<ArrayQueue<TaskId>>) -> Waker {
-        Waker::from(Arc::new(TaskWaker {
+    /// Pushes the Task's TaskId to the Executor's task_queue unless it is already there,
+    /// without counting a wake, for queueing newly spawned Tasks
+    fn schedule(&self) {
+        if !self.scheduled.swap(true, Ordering::SeqCst) {
+            self.task_queue.lock().push_back(self.task_id);
+        }
+    }
+
+    /// Creates a TaskWaker with the task_id, name and task_queue arguments, whose Task is not queued
+    fn new(task_id: TaskId, name: Option<String>, task_queue: Arc<RunQueue>) -> Arc<TaskWaker> {
+        Arc::new(TaskWaker {
             task_id,
+            name,
             task_queue,
-        }))
+            scheduled: AtomicBool::new(false),
+            polls: AtomicU64::new(0),
+            poll_time: AtomicU64::new(0),
+            longest_poll: AtomicU64::new(0),
+            wakes: AtomicU64::new(0),
+        })
+    }
+
+    /// Adds a poll which took `nanos` nanoseconds to the Task's statistics
+    fn record_poll(&self, nanos: u64) {
+        self.polls.fetch_add(1, Ordering::Relaxed);
+        self.poll_time.fetch_add(nanos, Ordering::Relaxed);
+        self.longest_poll.fetch_max(nanos, Ordering::Relaxed);
+    }
+
+    fn info(&self) -> TaskInfo {
+        TaskInfo {
+            id: self.task_id,
+            name: self.name.clone(),
+            ready: self.scheduled.load(Ordering::SeqCst),
+            polls: self.polls.load(Ordering::Relaxed),
+            poll_time: self.poll_time.load(Ordering::Relaxed),
+            longest_poll: self.longest_poll.load(Ordering::Relaxed),
+            wakes: self.wakes.load(Ordering::Relaxed),
+        }
     }
 }
 
@@ -0,0 +1,140 @@
+//! This module provides a channel which carries events from an interrupt handler to a Task.
+//!
+//! The interrupt handler pushes events onto a bounded queue which does not block or allocate
+//! on push/pop operations, and wakes the consuming Task through an AtomicWaker. The consuming
+//! Task receives the events through a Stream, so any driver can turn its interrupts into an
+//! async stream by declaring a static IrqChannel.
+
+use conquer_once::spin::OnceCell;
+use core::{
+    pin::Pin,
+    sync::atomic::{AtomicUsize, Ordering},
+    task::{Context, Poll},
+};
+use crossbeam_queue::ArrayQueue;
+use futures_util::{Stream, task::AtomicWaker};
+
+/// Reasons why an event could not be pushed onto an IrqChannel.
+/// The event which could not be pushed is returned to the caller.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PushError<T> {
+    /// The queue was full, so the event was dropped
+    Full(T),
+
+    /// The receiving Stream has not been created yet, so there is no queue
+    Uninitialized(T),
+}
+
+/// A bounded single consumer channel from interrupt handlers to a Task.
+///
+/// It is intended to be declared as a static, so it can be shared between the interrupt
+/// handler and the Task. The queue is allocated when the Stream is created by stream(),
+/// so push() never allocates.
+pub struct IrqChannel<T> {
+    /// Queue of events, which is wrapped in a OnceCell to allow a safe, one time initialisation
+    queue: OnceCell<ArrayQueue<T>>,
+
+    /// Maximum number of events the queue holds
+    capacity: usize,
+
+    /// Waker of the Task consuming the Stream. Since it uses atomic
+    /// operations, it is safe to have it in a static variable
+    waker: AtomicWaker,
+
+    /// Number of events dropped because the queue was full or uninitialised
+    dropped: AtomicUsize,
+}
+
+impl<T> IrqChannel<T> {
+    /// Creates a channel whose queue holds at most `capacity` events
+    pub const fn new(capacity: usize) -> Self {
+        IrqChannel {
+            queue: OnceCell::uninit(),
+            capacity,
+            waker: AtomicWaker::new(),
+            dropped: AtomicUsize::new(0),
+        }
+    }
+
+    /// Initialises the queue and returns the Stream of events pushed onto the channel.
+    ///
+    /// There can only be one consumer, so this panics if it is called more than once.
+    pub fn stream(&'static self) -> IrqStream<T> {
+        self.queue
+            .try_init_once(|| ArrayQueue::new(self.capacity))
+            .expect("IrqChannel::stream should only be called once");
+        IrqStream { channel: self }
+    }
+
+    /// Pushes an event onto the channel and wakes the consuming Task.
+    ///
+    /// This is called by interrupt handlers, so it must not block or allocate.
+    pub fn push(&self, event: T) -> Result<(), PushError<T>> {
+        let result = match self.queue.try_get() {
+            Ok(queue) => queue.push(event).map_err(PushError::Full),
+            Err(_) => Err(PushError::Uninitialized(event)),
+        };
+
+        match result {
+            // Notify the executor that an event has entered the queue, so it polls the consuming Task
+            Ok(()) => self.waker.wake(),
+            Err(_) => {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        result
+    }
+
+    /// Returns the number of events dropped because the queue was full or uninitialised
+    pub fn dropped(&self) -> usize {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Returns the maximum number of events the queue holds
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+}
+
+/// Stream of the events pushed onto an IrqChannel
+pub struct IrqStream<T: 'static> {
+    channel: &'static IrqChannel<T>,
+}
+
+impl<T> IrqStream<T> {
+    /// Returns the channel this Stream receives from
+    pub fn channel(&self) -> &'static IrqChannel<T> {
+        self.channel
+    }
+}
+
+impl<T> Stream for IrqStream<T> {
+    type Item = T;
+
+    /// Poll the queue for any new events
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<T>> {
+        let channel = self.channel;
+        let queue = channel
+            .queue
+            .try_get()
+            .expect("IrqChannel queue not initialized");
+
+        // fast path
+        if let Some(event) = queue.pop() {
+            return Poll::Ready(Some(event));
+        }
+
+        // Register the Waker in case this returns Poll::Pending, so the interrupt
+        // handler can wake the executor when an event is later added to the queue.
+        channel.waker.register(cx.waker());
+        match queue.pop() {
+            Some(event) => {
+                // Discard the waker if an event has since entered the queue,
+                // as this call will not return Poll::Pending
+                channel.waker.take();
+                Poll::Ready(Some(event))
+            }
+            None => Poll::Pending,
+        }
+    }
+}
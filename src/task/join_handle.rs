@@ -0,0 +1,199 @@
+//! This module provides JoinHandles, through which the spawner of a Task can await its
+//! output or cancel it.
+//!
+//! The Future of a Task is wrapped in a Joinable, which stores the output for the JoinHandle
+//! when the Future completes. Aborting sets a flag in their shared state and wakes the Task,
+//! and the Joinable then drops the Future instead of polling it. This works the same way on
+//! every executor, as they only ever see a Future which completes.
+
+use super::TaskId;
+use crate::sync::IrqSafeMutex;
+use alloc::sync::Arc;
+use core::{
+    fmt,
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll, Waker},
+};
+
+/// Reasons why a Task did not produce an output
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JoinError {
+    /// The Task was aborted before it completed
+    Cancelled,
+}
+
+impl fmt::Display for JoinError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            JoinError::Cancelled => write!(f, "task was cancelled"),
+        }
+    }
+}
+
+/// State shared between a Joinable and its JoinHandle
+struct State<T> {
+    /// The output of the Task, once it has completed or been cancelled
+    result: Option<Result<T, JoinError>>,
+
+    /// Whether result has been set. It stays set after the JoinHandle takes the result.
+    complete: bool,
+
+    /// Whether the Task has been aborted
+    aborted: bool,
+
+    /// Waker of the Task, so aborting can wake it to drop its Future
+    task_waker: Option<Waker>,
+
+    /// Waker of the Task awaiting the JoinHandle
+    join_waker: Option<Waker>,
+}
+
+/// Handle to a spawned Task, which is a Future of the Task's output.
+///
+/// Dropping a JoinHandle detaches it, so the Task keeps running, unless abort_on_drop has
+/// been called, in which case the Task is cancelled.
+pub struct JoinHandle<T> {
+    id: TaskId,
+    state: Arc<IrqSafeMutex<State<T>>>,
+    abort_on_drop: bool,
+}
+
+/// Future which runs a Task's Future, storing its output for the JoinHandle
+pub(crate) struct Joinable<F: Future> {
+    /// The Task's Future, which is dropped in place once it completes or is cancelled
+    future: Option<F>,
+    state: Arc<IrqSafeMutex<State<F::Output>>>,
+}
+
+/// Wraps a Future so its output can be awaited through the returned JoinHandle
+pub(crate) fn joinable<F: Future>(id: TaskId, future: F) -> (Joinable<F>, JoinHandle<F::Output>) {
+    let state = Arc::new(IrqSafeMutex::new(State {
+        result: None,
+        complete: false,
+        aborted: false,
+        task_waker: None,
+        join_waker: None,
+    }));
+    let joinable = Joinable {
+        future: Some(future),
+        state: state.clone(),
+    };
+    let handle = JoinHandle {
+        id,
+        state,
+        abort_on_drop: false,
+    };
+    (joinable, handle)
+}
+
+impl<F: Future> Joinable<F> {
+    /// Stores the result and wakes the Task awaiting the JoinHandle
+    fn complete(&self, result: Result<F::Output, JoinError>) {
+        let join_waker = {
+            let mut state = self.state.lock();
+            state.result = Some(result);
+            state.complete = true;
+            state.task_waker = None;
+            state.join_waker.take()
+        };
+        if let Some(waker) = join_waker {
+            waker.wake();
+        }
+    }
+}
+
+impl<F: Future> Future for Joinable<F> {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        // The inner Future is never moved out of the Joinable, only dropped in place
+        let this = unsafe { self.get_unchecked_mut() };
+        let mut future = unsafe { Pin::new_unchecked(&mut this.future) };
+
+        let Some(inner) = future.as_mut().as_pin_mut() else {
+            return Poll::Ready(());
+        };
+        let aborted = {
+            let mut state = this.state.lock();
+            if !state.aborted {
+                state.task_waker = Some(cx.waker().clone());
+            }
+            state.aborted
+        };
+
+        // The Future is dropped before the result is stored, so its Drop has run by
+        // the time the JoinHandle sees the Task complete
+        if aborted {
+            future.set(None);
+            this.complete(Err(JoinError::Cancelled));
+            return Poll::Ready(());
+        }
+        match inner.poll(cx) {
+            Poll::Ready(output) => {
+                future.set(None);
+                this.complete(Ok(output));
+                Poll::Ready(())
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+impl<T> JoinHandle<T> {
+    /// Returns the TaskId of the Task
+    pub fn id(&self) -> TaskId {
+        self.id
+    }
+
+    /// Cancels the Task, which drops its Future the next time the executor polls it.
+    /// This does nothing if the Task has already completed.
+    pub fn abort(&self) {
+        let task_waker = {
+            let mut state = self.state.lock();
+            if state.complete {
+                return;
+            }
+            state.aborted = true;
+            state.task_waker.take()
+        };
+        if let Some(waker) = task_waker {
+            waker.wake();
+        }
+    }
+
+    /// Returns whether the Task has completed or been cancelled
+    pub fn is_finished(&self) -> bool {
+        self.state.lock().complete
+    }
+
+    /// Makes dropping this JoinHandle abort the Task, rather than detach from it
+    pub fn abort_on_drop(mut self) -> Self {
+        self.abort_on_drop = true;
+        self
+    }
+}
+
+impl<T> Future for JoinHandle<T> {
+    type Output = Result<T, JoinError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<T, JoinError>> {
+        let mut state = self.state.lock();
+        if let Some(result) = state.result.take() {
+            Poll::Ready(result)
+        } else if state.complete {
+            panic!("JoinHandle polled after completion");
+        } else {
+            state.join_waker = Some(cx.waker().clone());
+            Poll::Pending
+        }
+    }
+}
+
+impl<T> Drop for JoinHandle<T> {
+    fn drop(&mut self) {
+        if self.abort_on_drop {
+            self.abort();
+        }
+    }
+}
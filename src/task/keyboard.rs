@@ -1,44 +1,46 @@
-//! This module provides support for asynchronously processing key presses by reading them from a
-//! queue which does not block or allocate on push/pop operations. It makes use of Waker notifications
-//! so the executor does not have to continuously poll the Task. Once the key has been read, it gets printed
+//! This module provides support for asynchronously processing key presses by reading them from an
+//! IrqChannel which the keyboard interrupt handler pushes scancodes onto. It makes use of Waker
+//! notifications so the executor does not have to continuously poll the Task.
+//!
+//! The keyboard service Task decodes the scancodes into KeyEvents in the current layout, which
+//! can be changed at any time, and sends each KeyEvent to every subscriber. It also keeps the
+//! keyboard's lock LEDs in step with the lock keys. One subscriber prints the typed characters
 //! to the VGA buffer.
 
+use super::irq_channel::{IrqChannel, IrqStream, PushError};
+use super::sync::mpsc::{self, Receiver, Sender, TrySendError};
+use crate::ps2::{self, Leds};
 use crate::{print, println};
-use conquer_once::spin::OnceCell;
+use alloc::vec::Vec;
 use core::{
     pin::Pin,
     task::{Context, Poll},
 };
-use crossbeam_queue::ArrayQueue;
-use futures_util::{Stream, StreamExt, task::AtomicWaker};
-use pc_keyboard::{DecodedKey, HandleControl, Keyboard, ScancodeSet1, layouts};
+use futures_util::{Stream, StreamExt};
+use spin::Mutex;
 
-/// Queue for scancodes which does not block or allocate on push/pop operations
-///
-/// It is wrapped in a OnceCell to allow a safe, one time initialisation
-static SCANCODE_QUEUE: OnceCell<ArrayQueue<u8>> = OnceCell::uninit();
+pub mod decoder;
 
-/// Global waker which will notify the executor when the print_keypresses Task can make progress.
-///
-/// Since it uses atomic operations, it is safe to have it as a static variable
-static WAKER: AtomicWaker = AtomicWaker::new();
+pub use decoder::{KeyCode, KeyDecoder, KeyEvent, KeyState, Layout, Modifiers};
+
+/// Channel of scancodes from the keyboard interrupt handler, with a bounded
+/// capacity of 100 (to prevent any allocations when pushing)
+static SCANCODES: IrqChannel<u8> = IrqChannel::new(100);
 
 /// Struct which implements the Stream trait for asynchronously returning keypresses
 /// from the queue.
 pub struct ScancodeStream {
-    /// Private member whose sole purpose is to prevent other modules from instantiating it
-    /// without using the new() method.
-    _private: (),
+    /// Stream of the scancode channel. It is private so other modules
+    /// must instantiate ScancodeStream with the new() method.
+    inner: IrqStream<u8>,
 }
 
 impl ScancodeStream {
-    /// Initialise the scancode queue with a bounded capacity of 100 (to prevent any allocations)
-    /// and return an instance of the ScancodeStream struct
+    /// Initialise the scancode queue and return an instance of the ScancodeStream struct
     pub fn new() -> Self {
-        SCANCODE_QUEUE
-            .try_init_once(|| ArrayQueue::new(100))
-            .expect("ScancodeStream::new should only be called once");
-        ScancodeStream { _private: () }
+        ScancodeStream {
+            inner: SCANCODES.stream(),
+        }
     }
 }
 
@@ -53,29 +55,8 @@ impl Stream for ScancodeStream {
     type Item = u8;
 
     /// Poll the queue for any recent keypresses
-    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<u8>> {
-        let queue = SCANCODE_QUEUE
-            .try_get()
-            .expect("scancode queue not initialized");
-
-        // fast path
-        if let Some(scancode) = queue.pop() {
-            return Poll::Ready(Some(scancode));
-        }
-
-        // Register the Waker in case this returns Poll::Pending, and we
-        // want to obtain a handle with which WAKER can wake up the executor
-        // when a key is later added to the queue.
-        WAKER.register(cx.waker());
-        match queue.pop() {
-            Some(scancode) => {
-                // Discard the waker if a key press has since entered the queue,
-                // as this call will not return Poll::Pending
-                WAKER.take();
-                Poll::Ready(Some(scancode))
-            }
-            None => Poll::Pending,
-        }
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<u8>> {
+        Pin::new(&mut self.inner).poll_next(cx)
     }
 }
 
@@ -83,40 +64,94 @@ impl Stream for ScancodeStream {
 ///
 /// Must not block or allocate as doing so could cause a deadlock.
 pub(crate) fn add_scancode(scancode: u8) {
-    if let Ok(queue) = SCANCODE_QUEUE.try_get() {
-        if queue.push(scancode).is_err() {
-            println!("WARNING: scancode queue full; dropping keyboard input");
-        } else {
-            // Notify the executor that a key press has entered the queue, so poll the print_keypresses Task
-            WAKER.wake();
+    match SCANCODES.push(scancode) {
+        Ok(()) => {}
+        Err(PushError::Full(_)) => {
+            println!("WARNING: scancode queue full; dropping keyboard input")
         }
-    } else {
-        println!("WARNING: scancode queue uninitialized");
+        Err(PushError::Uninitialized(_)) => println!("WARNING: scancode queue uninitialized"),
     }
 }
 
-/// Takes keypresses from the queue and prints them to the VGA buffer
-pub async fn print_keypresses() {
-    let mut scancodes = ScancodeStream::new();
+/// Number of KeyEvents a subscriber can fall behind by, after which further KeyEvents are
+/// dropped for it until it catches up
+const SUBSCRIBER_CAPACITY: usize = 64;
+
+/// Senders of the channels of every subscriber
+static SUBSCRIBERS: Mutex<Vec<Sender<KeyEvent>>> = Mutex::new(Vec::new());
+
+/// Layout the keyboard service decodes scancodes with
+static LAYOUT: Mutex<Layout> = Mutex::new(Layout::Us104);
+
+/// Returns a channel which receives every KeyEvent the keyboard service decodes from now on
+pub fn subscribe() -> Receiver<KeyEvent> {
+    let (sender, receiver) = mpsc::channel(SUBSCRIBER_CAPACITY);
+    SUBSCRIBERS.lock().push(sender);
+    receiver
+}
+
+/// Returns the layout the keyboard service decodes scancodes with
+pub fn layout() -> Layout {
+    *LAYOUT.lock()
+}
 
-    // Keyboard representation which is instantiated with scancode set 1,
-    // US layout, and its behaviour of handling 'ctrl' combinations like normal keys
-    let mut keyboard = Keyboard::new(
-        ScancodeSet1::new(),
-        layouts::Us104Key,
-        HandleControl::Ignore,
-    );
+/// Changes the layout the keyboard service decodes scancodes with, from the next scancode
+pub fn set_layout(layout: Layout) {
+    *LAYOUT.lock() = layout;
+}
+
+/// Sends a KeyEvent to every subscriber, and forgets those whose Receiver has been dropped
+fn publish(event: KeyEvent) {
+    SUBSCRIBERS
+        .lock()
+        .retain(|sender| !matches!(sender.try_send(event), Err(TrySendError::Closed(_))));
+}
+
+/// Returns the lock LEDs which show the lock keys' state
+fn leds(modifiers: Modifiers) -> Leds {
+    Leds {
+        scroll_lock: modifiers.scroll_lock,
+        num_lock: modifiers.num_lock,
+        caps_lock: modifiers.caps_lock,
+    }
+}
+
+/// Takes scancodes from the queue, decodes them into KeyEvents and sends them to the
+/// subscribers. Only one can run, as there is only one ScancodeStream.
+pub async fn run_keyboard_service() {
+    let mut scancodes = ScancodeStream::new();
+    let mut decoder = KeyDecoder::new(layout());
 
-    // Convert scancode to an Option<KeyEvent> which represents the key in question, and if it was a key up or down event.
-    // Then convert the key into a character, and print it
+    // Setting the LEDs waits for the keyboard with interrupts disabled, which takes a few
+    // milliseconds, so it is only done when they change. Once it fails, for example as there
+    // is no PS/2 keyboard, it is not tried again, so a keyboard which does not answer does
+    // not hold up every lock key press until the command times out.
+    let mut shown = leds(decoder.modifiers());
+    let mut leds_work = ps2::set_leds(shown).is_ok();
     while let Some(scancode) = scancodes.next().await {
-        if let Ok(Some(key_event)) = keyboard.add_byte(scancode)
-            && let Some(key) = keyboard.process_keyevent(key_event)
-        {
-            match key {
-                DecodedKey::Unicode(character) => print!("{}", character),
-                DecodedKey::RawKey(key) => print!("{:?}", key),
+        let layout = layout();
+        if decoder.layout() != layout {
+            decoder.set_layout(layout);
+        }
+        if let Some(event) = decoder.add_scancode(scancode) {
+            publish(event);
+            if leds_work && leds(event.modifiers) != shown {
+                shown = leds(event.modifiers);
+                leds_work = ps2::set_leds(shown).is_ok();
             }
         }
     }
 }
+
+/// Subscribes to the keyboard service and prints the characters typed to the VGA buffer
+pub async fn print_keypresses() {
+    let mut events = subscribe();
+    while let Some(event) = events.recv().await {
+        // Control characters, such as those typed with Ctrl, are not printable, except newlines
+        if let Some(character) = event.char
+            && (!character.is_control() || character == '\n')
+        {
+            print!("{}", character);
+        }
+    }
+}
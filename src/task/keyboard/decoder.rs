@@ -0,0 +1,302 @@
+//! This module turns scancodes into key events, which record the key, whether it was pressed
+//! or released, the state of the modifier keys, and the character it typed in the current
+//! layout, if any.
+//!
+//! Letters typed while Ctrl is held are delivered as the control characters U+0001 to
+//! U+001A, so Ctrl-C types U+0003 whatever the layout.
+
+use core::fmt;
+use pc_keyboard::{
+    DecodedKey, EventDecoder, HandleControl, ScancodeSet, ScancodeSet1, layouts::AnyLayout,
+};
+
+pub use pc_keyboard::{KeyCode, KeyState};
+
+/// The keyboard layouts provided by pc_keyboard
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Layout {
+    Us104,
+    Uk105,
+    Azerty,
+    De105,
+    Dvorak104,
+    DvorakProgrammer104,
+    Colemak,
+    Jis109,
+}
+
+impl Layout {
+    pub const ALL: [Layout; 8] = [
+        Layout::Us104,
+        Layout::Uk105,
+        Layout::Azerty,
+        Layout::De105,
+        Layout::Dvorak104,
+        Layout::DvorakProgrammer104,
+        Layout::Colemak,
+        Layout::Jis109,
+    ];
+
+    /// Returns the short name, by which from_name finds the layout
+    pub fn name(self) -> &'static str {
+        match self {
+            Layout::Us104 => "us",
+            Layout::Uk105 => "uk",
+            Layout::Azerty => "azerty",
+            Layout::De105 => "de",
+            Layout::Dvorak104 => "dvorak",
+            Layout::DvorakProgrammer104 => "dvp",
+            Layout::Colemak => "colemak",
+            Layout::Jis109 => "jis",
+        }
+    }
+
+    /// Returns the layout with the short name `name`
+    pub fn from_name(name: &str) -> Option<Layout> {
+        Layout::ALL.into_iter().find(|layout| layout.name() == name)
+    }
+
+    fn to_any(self) -> AnyLayout {
+        use pc_keyboard::layouts;
+
+        match self {
+            Layout::Us104 => AnyLayout::Us104Key(layouts::Us104Key),
+            Layout::Uk105 => AnyLayout::Uk105Key(layouts::Uk105Key),
+            Layout::Azerty => AnyLayout::Azerty(layouts::Azerty),
+            Layout::De105 => AnyLayout::De105Key(layouts::De105Key),
+            Layout::Dvorak104 => AnyLayout::Dvorak104Key(layouts::Dvorak104Key),
+            Layout::DvorakProgrammer104 => AnyLayout::DVP104Key(layouts::DVP104Key),
+            Layout::Colemak => AnyLayout::Colemak(layouts::Colemak),
+            Layout::Jis109 => AnyLayout::Jis109Key(layouts::Jis109Key),
+        }
+    }
+}
+
+impl fmt::Display for Layout {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// State of the modifier keys when a key event happened
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Modifiers {
+    /// Either Shift key is held
+    pub shift: bool,
+
+    /// Either Ctrl key is held
+    pub ctrl: bool,
+
+    /// The left Alt key is held
+    pub alt: bool,
+
+    /// The right Alt key, AltGr, is held
+    pub alt_gr: bool,
+
+    /// Caps Lock is on
+    pub caps_lock: bool,
+
+    /// Num Lock is on
+    pub num_lock: bool,
+
+    /// Scroll Lock is on
+    pub scroll_lock: bool,
+}
+
+/// A key being pressed or released
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyEvent {
+    pub code: KeyCode,
+    pub state: KeyState,
+
+    /// Modifiers after the event, so pressing Shift reports shift as held
+    pub modifiers: Modifiers,
+
+    /// The character typed, for presses of keys which type one in the current layout
+    pub char: Option<char>,
+}
+
+/// Which modifier keys are held, tracking each side separately so releasing one Shift
+/// key while the other is held does not release Shift
+#[derive(Debug, Default)]
+struct HeldModifiers {
+    left_shift: bool,
+    right_shift: bool,
+    left_ctrl: bool,
+    right_ctrl: bool,
+    alt: bool,
+    alt_gr: bool,
+}
+
+/// Decodes scancode set 1, as the PS/2 controller translates keyboard input to, into KeyEvents
+pub struct KeyDecoder {
+    scancodes: ScancodeSet1,
+
+    /// Decodes the character of each press, and tracks the lock keys
+    characters: EventDecoder<AnyLayout>,
+    layout: Layout,
+    held: HeldModifiers,
+    caps_lock: bool,
+    num_lock: bool,
+    scroll_lock: bool,
+}
+
+impl KeyDecoder {
+    pub fn new(layout: Layout) -> Self {
+        KeyDecoder {
+            scancodes: ScancodeSet1::new(),
+            characters: EventDecoder::new(layout.to_any(), HandleControl::MapLettersToUnicode),
+            layout,
+            held: HeldModifiers::default(),
+            caps_lock: false,
+
+            // The keyboard starts with Num Lock on, as pc_keyboard assumes
+            num_lock: true,
+            scroll_lock: false,
+        }
+    }
+
+    pub fn layout(&self) -> Layout {
+        self.layout
+    }
+
+    /// Changes the layout, which applies from the next key press
+    pub fn set_layout(&mut self, layout: Layout) {
+        self.characters.change_layout(layout.to_any());
+        self.layout = layout;
+    }
+
+    /// Returns the state of the modifier keys
+    pub fn modifiers(&self) -> Modifiers {
+        Modifiers {
+            shift: self.held.left_shift || self.held.right_shift,
+            ctrl: self.held.left_ctrl || self.held.right_ctrl,
+            alt: self.held.alt,
+            alt_gr: self.held.alt_gr,
+            caps_lock: self.caps_lock,
+            num_lock: self.num_lock,
+            scroll_lock: self.scroll_lock,
+        }
+    }
+
+    /// Decodes a scancode, returning a KeyEvent once it completes a key's sequence of
+    /// scancodes. Scancodes which are not part of any sequence are ignored.
+    pub fn add_scancode(&mut self, scancode: u8) -> Option<KeyEvent> {
+        let event = self.scancodes.advance_state(scancode).ok()??;
+        let (code, state) = (event.code, event.state);
+        let pressed = state != KeyState::Up;
+        match code {
+            KeyCode::LShift => self.held.left_shift = pressed,
+            KeyCode::RShift => self.held.right_shift = pressed,
+            KeyCode::LControl => self.held.left_ctrl = pressed,
+            KeyCode::RControl => self.held.right_ctrl = pressed,
+            KeyCode::LAlt => self.held.alt = pressed,
+            KeyCode::RAltGr => self.held.alt_gr = pressed,
+
+            // pc_keyboard does not track Scroll Lock
+            KeyCode::ScrollLock if state == KeyState::Down => self.scroll_lock = !self.scroll_lock,
+            _ => {}
+        }
+
+        // The EventDecoder sees every event, so its own modifier state stays in step
+        let char = match self.characters.process_keyevent(event) {
+            Some(DecodedKey::Unicode(char)) => Some(char),
+            Some(DecodedKey::RawKey(KeyCode::CapsLock)) => {
+                self.caps_lock = !self.caps_lock;
+                None
+            }
+            Some(DecodedKey::RawKey(KeyCode::NumpadLock)) => {
+                self.num_lock = !self.num_lock;
+                None
+            }
+            Some(DecodedKey::RawKey(_)) | None => None,
+        };
+
+        Some(KeyEvent {
+            code,
+            state,
+            modifiers: self.modifiers(),
+            char,
+        })
+    }
+}
+
+/// Feeds scancodes to a KeyDecoder, returning the character typed by the last of them
+#[cfg(test)]
+fn typed(decoder: &mut KeyDecoder, scancodes: &[u8]) -> Option<char> {
+    scancodes
+        .iter()
+        .filter_map(|&scancode| decoder.add_scancode(scancode))
+        .last()
+        .and_then(|event| event.char)
+}
+
+/// Tests that a key press and release are decoded with the modifiers held at the time
+#[test_case]
+fn test_decode_press_and_release() {
+    let mut decoder = KeyDecoder::new(Layout::Us104);
+
+    // Left Shift, then A, then release both
+    decoder.add_scancode(0x2A);
+    let press = decoder.add_scancode(0x1E).unwrap();
+    assert_eq!(press.code, KeyCode::A);
+    assert_eq!(press.state, KeyState::Down);
+    assert_eq!(press.char, Some('A'));
+    assert!(press.modifiers.shift);
+
+    let release = decoder.add_scancode(0x9E).unwrap();
+    assert_eq!(release.state, KeyState::Up);
+    assert_eq!(release.char, None);
+    decoder.add_scancode(0xAA);
+    assert!(!decoder.modifiers().shift);
+}
+
+/// Tests that Ctrl combinations are delivered as control characters
+#[test_case]
+fn test_ctrl_combinations() {
+    let mut decoder = KeyDecoder::new(Layout::Us104);
+    assert_eq!(typed(&mut decoder, &[0x1D, 0x2E]), Some('\u{3}'));
+    assert!(decoder.modifiers().ctrl);
+    assert_eq!(typed(&mut decoder, &[0x9D, 0xAE, 0x2E]), Some('c'));
+}
+
+/// Tests that the same keys type different characters in different layouts
+#[test_case]
+fn test_layouts() {
+    // The key right of Tab, the key right of Caps Lock, and Shift-2
+    let keys: [&[u8]; 3] = [&[0x10], &[0x1F], &[0x2A, 0x03]];
+    let cases = [
+        (Layout::Us104, ['q', 's', '@']),
+        (Layout::Uk105, ['q', 's', '"']),
+        (Layout::Azerty, ['a', 's', '2']),
+        (Layout::Dvorak104, ['\'', 'o', '@']),
+    ];
+    let mut decoder = KeyDecoder::new(Layout::Us104);
+    for (layout, expected) in cases {
+        decoder.set_layout(layout);
+        for (scancodes, expected) in keys.iter().zip(expected) {
+            assert_eq!(typed(&mut decoder, scancodes), Some(expected));
+            decoder.add_scancode(0xAA);
+        }
+    }
+
+    // German swaps Y and Z
+    decoder.set_layout(Layout::De105);
+    assert_eq!(typed(&mut decoder, &[0x15]), Some('z'));
+    assert_eq!(Layout::from_name("de"), Some(Layout::De105));
+}
+
+/// Tests that the lock keys toggle on each press
+#[test_case]
+fn test_lock_keys() {
+    let mut decoder = KeyDecoder::new(Layout::Us104);
+    assert!(decoder.modifiers().num_lock);
+
+    // Caps Lock, Num Lock and Scroll Lock, pressed and released
+    for scancode in [0x3A, 0xBA, 0x45, 0xC5, 0x46, 0xC6] {
+        decoder.add_scancode(scancode);
+    }
+    let modifiers = decoder.modifiers();
+    assert!(modifiers.caps_lock && !modifiers.num_lock && modifiers.scroll_lock);
+    assert_eq!(typed(&mut decoder, &[0x1E]), Some('A'));
+}
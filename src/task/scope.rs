@@ -0,0 +1,115 @@
+//! This module provides scopes, which spawn a group of child Tasks and only complete once
+//! every child has, so no child outlives the code which started it.
+//!
+//! Each child is a Task of its own, spawned through a Spawn implementation such as the
+//! Executor's Spawner or the SMP executor's SmpSpawner, so children run independently of each
+//! other and of the Task awaiting the scope, and have their own task-locals. As they are
+//! Tasks, children cannot borrow from the Task which spawned them. The scope holds a
+//! JoinHandle of each child, and awaits them all. If a child fails, the scope aborts the
+//! others and returns the error. Dropping a scope aborts its children.
+
+use super::{
+    JoinHandle,
+    combinator::FutureSet,
+    executor::Spawner,
+    smp_executor::{self, SmpSpawner},
+};
+use core::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+};
+use futures_util::Stream;
+
+/// An executor which a Scope can spawn its children onto
+pub trait Spawn: Clone {
+    /// Spawns a Future as a Task, returning its JoinHandle
+    fn spawn<F>(&self, future: F) -> JoinHandle<F::Output>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send;
+}
+
+impl Spawn for Spawner {
+    fn spawn<F>(&self, future: F) -> JoinHandle<F::Output>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send,
+    {
+        Spawner::spawn(self, future)
+    }
+}
+
+impl Spawn for SmpSpawner {
+    fn spawn<F>(&self, future: F) -> JoinHandle<F::Output>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send,
+    {
+        smp_executor::spawn(future)
+    }
+}
+
+/// Creates a scope which spawns its children through `spawner`, and calls `f` to spawn
+/// them. The scope is a Future which completes once every child has, or as soon as one fails.
+///
+/// ```ignore
+/// scope(&spawner, |s| {
+///     s.spawn(serve(first_device.clone()));
+///     s.spawn(serve(second_device.clone()));
+/// })
+/// .await?;
+/// ```
+pub fn scope<S: Spawn, E: Send + 'static>(
+    spawner: &S,
+    f: impl FnOnce(&mut Scope<S, E>),
+) -> Scope<S, E> {
+    let mut scope = Scope {
+        spawner: spawner.clone(),
+        children: FutureSet::new(),
+    };
+    f(&mut scope);
+    scope
+}
+
+/// A group of child Tasks, which is a Future of their combined result
+pub struct Scope<S, E> {
+    spawner: S,
+
+    /// JoinHandles of the children which have not completed, which abort them when dropped
+    children: FutureSet<JoinHandle<Result<(), E>>>,
+}
+
+impl<S: Spawn, E: Send + 'static> Scope<S, E> {
+    /// Spawns a child Task, which runs until it completes or the Scope aborts it
+    pub fn spawn(&mut self, future: impl Future<Output = Result<(), E>> + Send + 'static) {
+        let handle = self.spawner.spawn(future).abort_on_drop();
+        self.children.push(handle);
+    }
+
+    /// Returns the number of children which have not completed
+    pub fn running(&self) -> usize {
+        self.children.len()
+    }
+}
+
+impl<S: Unpin, E> Future for Scope<S, E> {
+    type Output = Result<(), E>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), E>> {
+        loop {
+            match Pin::new(&mut self.children).poll_next(cx) {
+                // Only the Scope holds the children's JoinHandles, so they are never
+                // cancelled while it awaits them
+                Poll::Ready(Some(Ok(Ok(())) | Err(_))) => {}
+                Poll::Ready(Some(Ok(Err(error)))) => {
+                    // Abort the other children before returning the error
+                    self.children.clear();
+                    return Poll::Ready(Err(error));
+                }
+                Poll::Ready(None) => return Poll::Ready(Ok(())),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
@@ -0,0 +1,74 @@
+//! This module provides support for asynchronously reading the bytes received on the serial
+//! ports, from IrqChannels which the serial interrupt handler pushes them onto. Like the
+//! ScancodeStream, the SerialStream uses Waker notifications so the executor does not have
+//! to continuously poll the Task reading it.
+
+use super::irq_channel::{IrqChannel, IrqStream, PushError};
+use crate::serial::{self, ComPort};
+use core::{
+    pin::Pin,
+    task::{Context, Poll},
+};
+use futures_util::Stream;
+
+/// Channels of bytes from the serial interrupt handler, indexed by ComPort::index, each with
+/// a bounded capacity of 256 (to prevent any allocations when pushing), so a pasted line
+/// is not cut short
+static RECEIVED: [IrqChannel<u8>; 4] = [const { IrqChannel::new(256) }; 4];
+
+/// Struct which implements the Stream trait for asynchronously returning the bytes
+/// received on a serial port
+pub struct SerialStream {
+    /// Stream of the received byte channel. It is private so other modules
+    /// must instantiate SerialStream with the new() or for_port() methods.
+    inner: IrqStream<u8>,
+}
+
+impl SerialStream {
+    /// Initialise the received byte queue of the console port and return a SerialStream of it
+    pub fn new() -> Self {
+        Self::for_port(serial::console_port())
+    }
+
+    /// Initialise the received byte queue of `port` and return a SerialStream of it.
+    ///
+    /// There can only be one SerialStream of each port, so this panics if one has already
+    /// been created. Bytes received before this is called are dropped.
+    pub fn for_port(port: ComPort) -> Self {
+        SerialStream {
+            inner: RECEIVED[port.index()].stream(),
+        }
+    }
+}
+
+impl Default for SerialStream {
+    fn default() -> SerialStream {
+        Self::new()
+    }
+}
+
+impl Stream for SerialStream {
+    /// This Stream returns received bytes
+    type Item = u8;
+
+    /// Poll the queue for any recently received bytes
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<u8>> {
+        Pin::new(&mut self.inner).poll_next(cx)
+    }
+}
+
+/// Called by the serial interrupt handler
+///
+/// Must not block or allocate as doing so could cause a deadlock.
+pub(crate) fn add_received_byte(port: ComPort, byte: u8) {
+    match RECEIVED[port.index()].push(byte) {
+        Ok(()) => {}
+        Err(PushError::Full(_)) => {
+            crate::println!("WARNING: {} input queue full; dropping serial input", port)
+        }
+
+        // Nothing reads a port until a SerialStream of it is created, so this is not worth a
+        // warning. The channel still counts the byte as dropped.
+        Err(PushError::Uninitialized(_)) => {}
+    }
+}
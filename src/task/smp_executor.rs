@@ -0,0 +1,267 @@
+//! This module provides an executor for Send Tasks which runs on every CPU.
+//!
+//! Each CPU has its own run queue of TaskIds in its per-CPU data. A CPU polls the Tasks on its
+//! own queue first, then those on the global injector queue, and once both are empty it
+//! steals ready Tasks from the other CPUs' queues. A Task is woken onto the queue of the CPU
+//! which last polled it, which may be another CPU than the one calling wake. The per-CPU
+//! queues have a fixed capacity, so a Task which fits on none of them goes onto the injector,
+//! which grows as needed. When a CPU has nothing to run it halts, and is woken by an IPI once
+//! there is work for it.
+//!
+//! A Task woken while it is being polled is not queued until the poll has finished, so a CPU
+//! never picks up a Task another CPU is still polling.
+
+use super::{JoinHandle, TaskId, join_handle, task_local::TaskLocals};
+use crate::{
+    apic, per_cpu,
+    percpu::{self, PerCpu},
+};
+use alloc::{
+    boxed::Box,
+    collections::{BTreeMap, VecDeque},
+    sync::Arc,
+    task::Wake,
+};
+use core::{
+    future::Future,
+    pin::Pin,
+    sync::atomic::{AtomicU8, AtomicUsize, Ordering},
+    task::{Context, Poll, Waker},
+};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+type SendFuture = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+// Scheduling states of a SendTask
+
+/// Neither queued nor being polled
+const IDLE: u8 = 0;
+
+/// On a run queue
+const QUEUED: u8 = 1;
+
+/// Being polled by a CPU
+const POLLING: u8 = 2;
+
+/// Being polled, and woken since the poll started, so it is queued again once the poll ends
+const POLLING_WOKEN: u8 = 3;
+
+/// A Task which can be polled by any CPU
+struct SendTask {
+    id: TaskId,
+
+    /// The Task's Future and task-local values, which are None once it has completed.
+    ///
+    /// The state ensures only one CPU polls the Future at a time, so the lock is never
+    /// contended.
+    future: Mutex<Option<(SendFuture, TaskLocals)>>,
+
+    /// One of IDLE, QUEUED, POLLING and POLLING_WOKEN. Repeated wakes only queue the Task
+    /// once, and a wake during a poll is deferred until the poll has finished.
+    state: AtomicU8,
+
+    /// Index of the CPU which last polled the Task, whose queue it is woken onto
+    cpu: AtomicUsize,
+}
+
+/// Every spawned Task which has not completed, indexed by TaskId
+static TASKS: Mutex<BTreeMap<TaskId, Arc<SendTask>>> = Mutex::new(BTreeMap::new());
+
+/// TaskIds of ready Tasks which did not fit on any CPU's run queue, which every CPU takes from
+static INJECTOR: Mutex<VecDeque<TaskId>> = Mutex::new(VecDeque::new());
+
+/// Spawns a Task onto the current CPU's run queue, from which any CPU running the executor
+/// may steal it.
+///
+/// Interrupts are disabled while the Task is added, so this can be called from interrupt
+/// handlers as well as from Tasks. The returned JoinHandle can be used to await the
+/// Task's output, or to cancel it.
+pub fn spawn<F>(future: F) -> JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send,
+{
+    let id = TaskId::new();
+    let (future, handle) = join_handle::joinable(id, future);
+    let task = Arc::new(SendTask {
+        id,
+        future: Mutex::new(Some((Box::pin(future), TaskLocals::new()))),
+        state: AtomicU8::new(IDLE),
+        cpu: AtomicUsize::new(per_cpu!(cpu_id)),
+    });
+    interrupts::without_interrupts(|| TASKS.lock().insert(task.id, task.clone()));
+    schedule(&task);
+    handle
+}
+
+/// Handle which spawns Tasks onto the SMP executor, for code such as scope which is given
+/// where to spawn Tasks rather than calling spawn itself
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SmpSpawner;
+
+/// Queues a Task unless it is already queued. If it is being polled, it is queued by the
+/// CPU polling it once the poll has finished.
+fn schedule(task: &SendTask) {
+    let mut state = task.state.load(Ordering::SeqCst);
+    loop {
+        let next = match state {
+            IDLE => QUEUED,
+            POLLING => POLLING_WOKEN,
+            _ => return,
+        };
+        match task
+            .state
+            .compare_exchange(state, next, Ordering::SeqCst, Ordering::SeqCst)
+        {
+            Ok(_) if next == QUEUED => return enqueue(task),
+            Ok(_) => return,
+            Err(current) => state = current,
+        }
+    }
+}
+
+/// Pushes a Task's TaskId onto the run queue of the CPU which last polled it, then wakes a
+/// CPU to run it. If that queue is full the other CPUs' queues are tried, then the injector.
+fn enqueue(task: &SendTask) {
+    let preferred = task.cpu.load(Ordering::Relaxed);
+    let target = percpu::cpu(preferred)
+        .into_iter()
+        .chain(percpu::cpus().filter(|cpu| cpu.cpu_id != preferred))
+        .find(|cpu| cpu.run_queue().push(task.id).is_ok());
+    match target {
+        Some(target) => wake_cpu(target),
+        None => {
+            interrupts::without_interrupts(|| INJECTOR.lock().push_back(task.id));
+            if let Some(preferred) = percpu::cpu(preferred) {
+                wake_cpu(preferred);
+            }
+        }
+    }
+}
+
+/// Sends an IPI to `target` if it is idle. If `target` is busy, an idle CPU is woken
+/// instead, so it can steal the Task.
+fn wake_cpu(target: &PerCpu) {
+    let Some(local_apic) = apic::local_apic() else {
+        return;
+    };
+
+    let current = per_cpu!(cpu_id);
+    let idle = if target.is_idle() {
+        Some(target)
+    } else {
+        percpu::cpus().find(|cpu| cpu.cpu_id != current && cpu.is_idle())
+    };
+    if let Some(apic_id) = idle.and_then(PerCpu::apic_id) {
+        local_apic.send_ipi(apic_id, apic::WAKE_VECTOR);
+    }
+}
+
+/// Pops a TaskId from the current CPU's run queue or the injector, or steals one from
+/// another CPU's run queue
+fn next_task(current: &PerCpu) -> Option<TaskId> {
+    current
+        .run_queue()
+        .pop()
+        .or_else(|| interrupts::without_interrupts(|| INJECTOR.lock().pop_front()))
+        .or_else(|| {
+            percpu::cpus()
+                .filter(|cpu| cpu.cpu_id != current.cpu_id)
+                .find_map(|cpu| cpu.run_queue().pop())
+        })
+}
+
+/// Returns whether any CPU's run queue or the injector holds a TaskId
+fn has_ready_tasks() -> bool {
+    percpu::cpus().any(|cpu| !cpu.run_queue().is_empty())
+        || interrupts::without_interrupts(|| !INJECTOR.lock().is_empty())
+}
+
+/// Polls the Task with `task_id` on the current CPU
+fn run_task(current: &PerCpu, task_id: TaskId) {
+    let Some(task) = interrupts::without_interrupts(|| TASKS.lock().get(&task_id).cloned()) else {
+        return; // task no longer exists
+    };
+
+    // Only the CPU which took the Task off a queue polls it, so the state is QUEUED
+    task.state.store(POLLING, Ordering::SeqCst);
+    task.cpu.store(current.cpu_id, Ordering::Relaxed);
+
+    let mut future = task.future.lock();
+    let Some((inner, locals)) = future.as_mut() else {
+        return; // task already completed
+    };
+
+    let waker = Waker::from(Arc::new(TaskWaker { task: task.clone() }));
+    let mut context = Context::from_waker(&waker);
+
+    current.set_current_task(Some(task_id));
+    let poll = locals.enter(|| inner.as_mut().poll(&mut context));
+    current.set_current_task(None);
+
+    if let Poll::Ready(()) = poll {
+        // task done -> drop its Future and remove it. The state stays POLLING or
+        // POLLING_WOKEN, so later wakes do not queue it.
+        *future = None;
+        interrupts::without_interrupts(|| TASKS.lock().remove(&task_id));
+        return;
+    }
+    drop(future);
+
+    // Queue the Task again if it was woken during the poll
+    if task
+        .state
+        .compare_exchange(POLLING, IDLE, Ordering::SeqCst, Ordering::SeqCst)
+        .is_err()
+    {
+        task.state.store(QUEUED, Ordering::SeqCst);
+        enqueue(&task);
+    }
+}
+
+/// Runs Tasks on the current CPU forever, halting while no CPU has ready Tasks.
+///
+/// This is called by every CPU which should run Tasks, after its per-CPU data and local APIC
+/// have been set up.
+pub fn run() -> ! {
+    let current = percpu::current();
+    loop {
+        while let Some(task_id) = next_task(current) {
+            run_task(current, task_id);
+        }
+        sleep_if_idle(current);
+    }
+}
+
+/// If no CPU has ready Tasks, halts until the next interrupt, such as a wake IPI
+fn sleep_if_idle(current: &PerCpu) {
+    // Mark the CPU idle before checking the queues, so a CPU which queues a Task after the
+    // check sees the flag and sends an IPI. Interrupts are disabled so that IPI is held
+    // pending until the hlt, instead of being handled between the check and the hlt.
+    interrupts::disable();
+    current.set_idle(true);
+    if has_ready_tasks() {
+        interrupts::enable();
+    } else {
+        interrupts::enable_and_hlt();
+    }
+    current.set_idle(false);
+}
+
+/// The TaskWaker's job is to queue its Task on the CPU which last polled it
+struct TaskWaker {
+    task: Arc<SendTask>,
+}
+
+impl Wake for TaskWaker {
+    /// Wake the TaskWaker's Task by queuing it
+    fn wake(self: Arc<Self>) {
+        schedule(&self.task);
+    }
+
+    /// Wake the TaskWaker's Task by queuing it
+    fn wake_by_ref(self: &Arc<Self>) {
+        schedule(&self.task);
+    }
+}
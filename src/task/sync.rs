@@ -0,0 +1,19 @@
+//! This module provides synchronization primitives for Tasks.
+//!
+//! Unlike the spinlocks in crate::sync, a Task which has to wait for one of these returns
+//! Poll::Pending and is woken through its Waker, so the executor can run other Tasks
+//! meanwhile. Their state is guarded by interrupt-safe spinlocks, so they can also be
+//! signalled from interrupt handlers, for example through Notify or an unbounded channel.
+
+pub mod mpsc;
+pub mod oneshot;
+
+mod mutex;
+mod notify;
+mod rwlock;
+mod semaphore;
+
+pub use mutex::{Mutex, MutexGuard};
+pub use notify::{Notified, Notify};
+pub use rwlock::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+pub use semaphore::{Acquire, Semaphore, SemaphorePermit};
@@ -0,0 +1,350 @@
+//! This module provides multi-producer, single-consumer channels between Tasks.
+//!
+//! A bounded channel, created by channel(), holds a fixed number of values, and senders wait
+//! while it is full. An unbounded channel, created by unbounded(), grows as needed, so sending
+//! never waits, and can be done from code which is not async. Both have the same Receiver,
+//! which is also a Stream of the values sent.
+
+use crate::sync::IrqSafeMutex;
+use alloc::{collections::VecDeque, sync::Arc, vec::Vec};
+use core::{
+    fmt,
+    future::poll_fn,
+    pin::Pin,
+    task::{Context, Poll, Waker},
+};
+use futures_util::Stream;
+
+/// Error returned when a value is sent after the Receiver has been dropped.
+/// The value which could not be sent is returned to the caller.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SendError<T>(pub T);
+
+/// Reasons why try_send could not send a value. The value is returned to the caller.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrySendError<T> {
+    /// The channel is full
+    Full(T),
+
+    /// The Receiver has been dropped
+    Closed(T),
+}
+
+/// Reasons why try_recv could not receive a value
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TryRecvError {
+    /// The channel is empty, but senders remain
+    Empty,
+
+    /// The channel is empty, and every sender has been dropped
+    Disconnected,
+}
+
+impl<T> fmt::Display for SendError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "sending on a closed channel")
+    }
+}
+
+struct State<T> {
+    queue: VecDeque<T>,
+
+    /// Maximum number of values in the queue, or None for an unbounded channel
+    capacity: Option<usize>,
+
+    /// Number of Senders or UnboundedSenders which have not been dropped
+    senders: usize,
+
+    /// Whether the Receiver has been dropped
+    closed: bool,
+
+    /// Waker of the Task waiting to receive
+    receiver: Option<Waker>,
+
+    /// Tasks waiting for space in a full channel
+    senders_waiting: Vec<Waiter>,
+
+    /// Identifier given to the next send which waits
+    next_id: u64,
+}
+
+/// A send waiting for space in a full channel
+struct Waiter {
+    id: u64,
+    waker: Waker,
+}
+
+/// State shared between the senders and Receiver of a channel
+struct Chan<T> {
+    state: IrqSafeMutex<State<T>>,
+}
+
+impl<T> Chan<T> {
+    fn new(capacity: Option<usize>) -> Arc<Self> {
+        Arc::new(Chan {
+            state: IrqSafeMutex::new(State {
+                queue: VecDeque::new(),
+                capacity,
+                senders: 1,
+                closed: false,
+                receiver: None,
+                senders_waiting: Vec::new(),
+                next_id: 0,
+            }),
+        })
+    }
+
+    /// Pushes a value if there is space, waking the Receiver
+    fn try_send(&self, value: T) -> Result<(), TrySendError<T>> {
+        let receiver = {
+            let mut state = self.state.lock();
+            if state.closed {
+                return Err(TrySendError::Closed(value));
+            }
+            if state
+                .capacity
+                .is_some_and(|capacity| state.queue.len() >= capacity)
+            {
+                return Err(TrySendError::Full(value));
+            }
+            state.queue.push_back(value);
+            state.receiver.take()
+        };
+        if let Some(waker) = receiver {
+            waker.wake();
+        }
+        Ok(())
+    }
+
+    /// Pops a value, waking the Tasks waiting for space
+    fn try_recv(&self) -> Result<T, TryRecvError> {
+        let (value, senders_waiting) = {
+            let mut state = self.state.lock();
+            match state.queue.pop_front() {
+                Some(value) => (value, core::mem::take(&mut state.senders_waiting)),
+                None if state.senders == 0 => return Err(TryRecvError::Disconnected),
+                None => return Err(TryRecvError::Empty),
+            }
+        };
+
+        // Every waiting sender is woken, as a woken send may be dropped before it is polled
+        for waiter in senders_waiting {
+            waiter.waker.wake();
+        }
+        Ok(value)
+    }
+
+    fn poll_recv(&self, cx: &mut Context) -> Poll<Option<T>> {
+        match self.try_recv() {
+            Ok(value) => Poll::Ready(Some(value)),
+            Err(TryRecvError::Disconnected) => Poll::Ready(None),
+            Err(TryRecvError::Empty) => {
+                // Check again after registering, in case a value was sent in between
+                self.state.lock().receiver = Some(cx.waker().clone());
+                match self.try_recv() {
+                    Ok(value) => Poll::Ready(Some(value)),
+                    Err(TryRecvError::Disconnected) => Poll::Ready(None),
+                    Err(TryRecvError::Empty) => Poll::Pending,
+                }
+            }
+        }
+    }
+
+    fn add_sender(self: &Arc<Self>) -> Arc<Self> {
+        self.state.lock().senders += 1;
+        self.clone()
+    }
+
+    /// Wakes the Receiver when the last sender is dropped, so it sees the channel disconnect
+    fn drop_sender(&self) {
+        let receiver = {
+            let mut state = self.state.lock();
+            state.senders -= 1;
+            if state.senders == 0 {
+                state.receiver.take()
+            } else {
+                None
+            }
+        };
+        if let Some(waker) = receiver {
+            waker.wake();
+        }
+    }
+}
+
+/// Sending half of a bounded channel, which can be cloned to send from several Tasks
+pub struct Sender<T> {
+    chan: Arc<Chan<T>>,
+}
+
+/// Sending half of an unbounded channel, which can be cloned to send from several Tasks
+pub struct UnboundedSender<T> {
+    chan: Arc<Chan<T>>,
+}
+
+/// Receiving half of a channel
+pub struct Receiver<T> {
+    chan: Arc<Chan<T>>,
+}
+
+/// Creates a bounded channel which holds at most `capacity` values
+pub fn channel<T>(capacity: usize) -> (Sender<T>, Receiver<T>) {
+    assert!(capacity > 0, "channel capacity must be at least 1");
+    let chan = Chan::new(Some(capacity));
+    (Sender { chan: chan.clone() }, Receiver { chan })
+}
+
+/// Creates an unbounded channel
+pub fn unbounded<T>() -> (UnboundedSender<T>, Receiver<T>) {
+    let chan = Chan::new(None);
+    (UnboundedSender { chan: chan.clone() }, Receiver { chan })
+}
+
+impl<T> Sender<T> {
+    /// Sends a value, waiting while the channel is full
+    pub async fn send(&self, value: T) -> Result<(), SendError<T>> {
+        let mut value = Some(value);
+        let mut waiter = SendWaiter {
+            chan: &self.chan,
+            id: None,
+        };
+        poll_fn(|cx| {
+            let Some(unsent) = value.take() else {
+                panic!("send polled after completion");
+            };
+            match self.chan.try_send(unsent) {
+                Ok(()) => Poll::Ready(Ok(())),
+                Err(TrySendError::Closed(unsent)) => Poll::Ready(Err(SendError(unsent))),
+                Err(TrySendError::Full(unsent)) => {
+                    // Try again after registering, in case space was made in between
+                    waiter.register(cx.waker());
+                    match self.chan.try_send(unsent) {
+                        Ok(()) => Poll::Ready(Ok(())),
+                        Err(TrySendError::Closed(unsent)) => Poll::Ready(Err(SendError(unsent))),
+                        Err(TrySendError::Full(unsent)) => {
+                            value = Some(unsent);
+                            Poll::Pending
+                        }
+                    }
+                }
+            }
+        })
+        .await
+    }
+
+    /// Sends a value if the channel has space
+    pub fn try_send(&self, value: T) -> Result<(), TrySendError<T>> {
+        self.chan.try_send(value)
+    }
+}
+
+/// A Sender::send future's place among the senders waiting for space. Each future has at
+/// most one Waiter, which is removed when the future is dropped.
+struct SendWaiter<'a, T> {
+    chan: &'a Chan<T>,
+
+    /// Identifier of the Waiter, once the send has started waiting
+    id: Option<u64>,
+}
+
+impl<T> SendWaiter<'_, T> {
+    /// Stores `waker` in this send's Waiter, adding one if it has none, or if the Receiver
+    /// has taken it to wake the send
+    fn register(&mut self, waker: &Waker) {
+        let mut state = self.chan.state.lock();
+        let id = *self.id.get_or_insert_with(|| {
+            let id = state.next_id;
+            state.next_id += 1;
+            id
+        });
+        match state.senders_waiting.iter_mut().find(|w| w.id == id) {
+            Some(waiter) => waiter.waker.clone_from(waker),
+            None => state.senders_waiting.push(Waiter {
+                id,
+                waker: waker.clone(),
+            }),
+        }
+    }
+}
+
+impl<T> Drop for SendWaiter<'_, T> {
+    fn drop(&mut self) {
+        if let Some(id) = self.id {
+            let mut state = self.chan.state.lock();
+            state.senders_waiting.retain(|waiter| waiter.id != id);
+        }
+    }
+}
+
+impl<T> UnboundedSender<T> {
+    /// Sends a value, which never waits. This can be called from interrupt handlers,
+    /// as long as the heap is not exhausted.
+    pub fn send(&self, value: T) -> Result<(), SendError<T>> {
+        self.chan.try_send(value).map_err(|error| match error {
+            TrySendError::Full(value) | TrySendError::Closed(value) => SendError(value),
+        })
+    }
+}
+
+impl<T> Receiver<T> {
+    /// Waits for the next value, returning None once the channel is empty
+    /// and every sender has been dropped
+    pub async fn recv(&mut self) -> Option<T> {
+        poll_fn(|cx| self.chan.poll_recv(cx)).await
+    }
+
+    /// Receives a value if one is waiting
+    pub fn try_recv(&mut self) -> Result<T, TryRecvError> {
+        self.chan.try_recv()
+    }
+}
+
+impl<T> Stream for Receiver<T> {
+    type Item = T;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<T>> {
+        self.chan.poll_recv(cx)
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        Sender {
+            chan: self.chan.add_sender(),
+        }
+    }
+}
+
+impl<T> Clone for UnboundedSender<T> {
+    fn clone(&self) -> Self {
+        UnboundedSender {
+            chan: self.chan.add_sender(),
+        }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        self.chan.drop_sender();
+    }
+}
+
+impl<T> Drop for UnboundedSender<T> {
+    fn drop(&mut self) {
+        self.chan.drop_sender();
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    /// Closes the channel, waking waiting senders so they see it is closed
+    fn drop(&mut self) {
+        let senders_waiting = {
+            let mut state = self.chan.state.lock();
+            state.closed = true;
+            core::mem::take(&mut state.senders_waiting)
+        };
+        for waiter in senders_waiting {
+            waiter.waker.wake();
+        }
+    }
+}
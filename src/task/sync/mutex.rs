@@ -0,0 +1,89 @@
+//! This module provides an async mutex, which Tasks wait for without blocking the executor.
+
+use super::Semaphore;
+use core::{
+    cell::UnsafeCell,
+    marker::PhantomData,
+    ops::{Deref, DerefMut},
+};
+
+/// An async mutual exclusion lock.
+///
+/// Unlike a spinlock, a Task waiting for the lock returns Poll::Pending, so the executor can
+/// run other Tasks meanwhile. The guard can be held across await points. Tasks acquire the
+/// lock in the order they started waiting for it.
+pub struct Mutex<T> {
+    semaphore: Semaphore,
+    value: UnsafeCell<T>,
+}
+
+// The semaphore ensures only one guard, and so only one reference to the value, exists at once
+unsafe impl<T: Send> Send for Mutex<T> {}
+unsafe impl<T: Send> Sync for Mutex<T> {}
+
+/// Guard giving access to the value of a locked Mutex, which unlocks it when dropped
+pub struct MutexGuard<'a, T> {
+    mutex: &'a Mutex<T>,
+
+    /// The guard gives mutable access to the value, so it is only Sync if T is
+    _value: PhantomData<&'a mut T>,
+}
+
+impl<T> Mutex<T> {
+    pub const fn new(value: T) -> Self {
+        Mutex {
+            semaphore: Semaphore::new(1),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    /// Waits until the lock is acquired
+    pub async fn lock(&self) -> MutexGuard<'_, T> {
+        self.semaphore.acquire().await.forget();
+        MutexGuard {
+            mutex: self,
+            _value: PhantomData,
+        }
+    }
+
+    /// Acquires the lock if it is free and no Task is waiting for it
+    pub fn try_lock(&self) -> Option<MutexGuard<'_, T>> {
+        self.semaphore.try_acquire().map(|permit| {
+            permit.forget();
+            MutexGuard {
+                mutex: self,
+                _value: PhantomData,
+            }
+        })
+    }
+
+    /// Returns a mutable reference to the value, which needs no locking as the
+    /// Mutex is borrowed mutably
+    pub fn get_mut(&mut self) -> &mut T {
+        self.value.get_mut()
+    }
+
+    pub fn into_inner(self) -> T {
+        self.value.into_inner()
+    }
+}
+
+impl<T> Deref for MutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.value.get() }
+    }
+}
+
+impl<T> DerefMut for MutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.value.get() }
+    }
+}
+
+impl<T> Drop for MutexGuard<'_, T> {
+    fn drop(&mut self) {
+        self.mutex.semaphore.add_permits(1);
+    }
+}
@@ -0,0 +1,157 @@
+//! This module provides an async event which Tasks wait on until another Task or an
+//! interrupt handler notifies them.
+
+use crate::sync::IrqSafeMutex;
+use alloc::collections::{BTreeMap, VecDeque};
+use core::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll, Waker},
+};
+
+/// How a waiting Task was notified
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Notification {
+    One,
+    All,
+}
+
+struct State {
+    /// Whether notify_one was called while no Task was waiting, in which
+    /// case the next Task to wait completes immediately
+    permit: bool,
+
+    /// Tasks waiting to be notified, in the order they started waiting
+    waiters: VecDeque<(u64, Waker)>,
+
+    /// Tasks which have been notified but not yet polled since
+    notified: BTreeMap<u64, Notification>,
+
+    /// Identifier given to the next Task which waits
+    next_id: u64,
+}
+
+/// An async event.
+///
+/// notify_one wakes the Task which has been waiting longest, or if none is waiting, stores
+/// a permit which completes the next wait immediately, so a notification sent just before a
+/// Task starts waiting is not lost. notify_waiters wakes every Task which is waiting.
+pub struct Notify {
+    state: IrqSafeMutex<State>,
+}
+
+impl Notify {
+    pub const fn new() -> Self {
+        Notify {
+            state: IrqSafeMutex::new(State {
+                permit: false,
+                waiters: VecDeque::new(),
+                notified: BTreeMap::new(),
+                next_id: 0,
+            }),
+        }
+    }
+
+    /// Returns a Future which completes once this is notified
+    pub fn notified(&self) -> Notified<'_> {
+        Notified {
+            notify: self,
+            id: None,
+        }
+    }
+
+    /// Wakes the Task which has been waiting longest, or stores a permit if none is waiting
+    pub fn notify_one(&self) {
+        let waker = {
+            let mut state = self.state.lock();
+            match state.waiters.pop_front() {
+                Some((id, waker)) => {
+                    state.notified.insert(id, Notification::One);
+                    Some(waker)
+                }
+                None => {
+                    state.permit = true;
+                    None
+                }
+            }
+        };
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+
+    /// Wakes every Task which is waiting, without storing a permit
+    pub fn notify_waiters(&self) {
+        let waiters = {
+            let mut state = self.state.lock();
+            let waiters = core::mem::take(&mut state.waiters);
+            for (id, _) in &waiters {
+                state.notified.insert(*id, Notification::All);
+            }
+            waiters
+        };
+        for (_, waker) in waiters {
+            waker.wake();
+        }
+    }
+}
+
+impl Default for Notify {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Future returned by Notify::notified
+pub struct Notified<'a> {
+    notify: &'a Notify,
+
+    /// Identifier of this Future in the waiter queue, once it has started waiting
+    id: Option<u64>,
+}
+
+impl Future for Notified<'_> {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        let mut state = self.notify.state.lock();
+        match self.id {
+            Some(id) => {
+                if state.notified.remove(&id).is_some() {
+                    self.id = None;
+                    return Poll::Ready(());
+                }
+                if let Some((_, waker)) = state.waiters.iter_mut().find(|(w, _)| *w == id) {
+                    waker.clone_from(cx.waker());
+                }
+                Poll::Pending
+            }
+            None if core::mem::take(&mut state.permit) => Poll::Ready(()),
+            None => {
+                let id = state.next_id;
+                state.next_id += 1;
+                state.waiters.push_back((id, cx.waker().clone()));
+                self.id = Some(id);
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl Drop for Notified<'_> {
+    /// Leaves the waiter queue. If this was woken by notify_one but never saw it,
+    /// the notification is passed on so it is not lost.
+    fn drop(&mut self) {
+        let Some(id) = self.id else {
+            return;
+        };
+        let notification = {
+            let mut state = self.notify.state.lock();
+            state.waiters.retain(|(waiter, _)| *waiter != id);
+            state.notified.remove(&id)
+        };
+        if notification == Some(Notification::One) {
+            self.notify.notify_one();
+        }
+    }
+}
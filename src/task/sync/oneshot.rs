@@ -0,0 +1,122 @@
+//! This module provides a channel which sends a single value from one Task to another.
+
+use crate::sync::IrqSafeMutex;
+use alloc::sync::Arc;
+use core::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll, Waker},
+};
+
+/// Error returned by the Receiver when the Sender was dropped without sending a value
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RecvError;
+
+struct State<T> {
+    value: Option<T>,
+
+    /// Whether the Sender has sent a value or been dropped
+    complete: bool,
+
+    /// Whether the Receiver has been dropped
+    closed: bool,
+
+    /// Waker of the Task waiting on the Receiver
+    waker: Option<Waker>,
+}
+
+/// Sending half of a oneshot channel
+pub struct Sender<T> {
+    state: Arc<IrqSafeMutex<State<T>>>,
+}
+
+/// Receiving half of a oneshot channel, which is a Future of the value sent
+pub struct Receiver<T> {
+    state: Arc<IrqSafeMutex<State<T>>>,
+}
+
+/// Creates a oneshot channel
+pub fn channel<T>() -> (Sender<T>, Receiver<T>) {
+    let state = Arc::new(IrqSafeMutex::new(State {
+        value: None,
+        complete: false,
+        closed: false,
+        waker: None,
+    }));
+    (
+        Sender {
+            state: state.clone(),
+        },
+        Receiver { state },
+    )
+}
+
+impl<T> Sender<T> {
+    /// Sends the value, returning it if the Receiver has been dropped
+    pub fn send(self, value: T) -> Result<(), T> {
+        let waker = {
+            let mut state = self.state.lock();
+            if state.closed {
+                return Err(value);
+            }
+            state.value = Some(value);
+            state.complete = true;
+            state.waker.take()
+        };
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+        Ok(())
+    }
+
+    /// Returns whether the Receiver has been dropped, so a value sent would be discarded
+    pub fn is_closed(&self) -> bool {
+        self.state.lock().closed
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    /// Wakes the Receiver if no value was sent, so it sees the Sender is gone
+    fn drop(&mut self) {
+        let waker = {
+            let mut state = self.state.lock();
+            if state.complete {
+                return;
+            }
+            state.complete = true;
+            state.waker.take()
+        };
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+}
+
+impl<T> Receiver<T> {
+    /// Returns the value if it has been sent
+    pub fn try_recv(&mut self) -> Option<T> {
+        self.state.lock().value.take()
+    }
+}
+
+impl<T> Future for Receiver<T> {
+    type Output = Result<T, RecvError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<T, RecvError>> {
+        let mut state = self.state.lock();
+        if let Some(value) = state.value.take() {
+            Poll::Ready(Ok(value))
+        } else if state.complete {
+            Poll::Ready(Err(RecvError))
+        } else {
+            state.waker = Some(cx.waker().clone());
+            Poll::Pending
+        }
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        self.state.lock().closed = true;
+    }
+}
@@ -0,0 +1,109 @@
+//! This module provides an async reader-writer lock.
+
+use super::Semaphore;
+use core::{
+    cell::UnsafeCell,
+    marker::PhantomData,
+    ops::{Deref, DerefMut},
+};
+
+/// Maximum number of readers which can hold the lock at once.
+///
+/// A reader acquires one of the semaphore's permits, and a writer acquires all of them.
+const MAX_READERS: usize = 1 << 16;
+
+/// An async reader-writer lock, which is held either by any number of readers or by one writer.
+///
+/// Tasks acquire the lock in the order they started waiting for it, so a waiting writer
+/// holds up readers which arrive after it, and is not starved by a steady stream of readers.
+pub struct RwLock<T> {
+    semaphore: Semaphore,
+    value: UnsafeCell<T>,
+}
+
+// The semaphore ensures a write guard is never held at the same time as any other guard
+unsafe impl<T: Send> Send for RwLock<T> {}
+unsafe impl<T: Send + Sync> Sync for RwLock<T> {}
+
+/// Guard giving shared access to the value of an RwLock, which releases it when dropped
+pub struct RwLockReadGuard<'a, T> {
+    lock: &'a RwLock<T>,
+    _value: PhantomData<&'a T>,
+}
+
+/// Guard giving exclusive access to the value of an RwLock, which releases it when dropped
+pub struct RwLockWriteGuard<'a, T> {
+    lock: &'a RwLock<T>,
+    _value: PhantomData<&'a mut T>,
+}
+
+impl<T> RwLock<T> {
+    pub const fn new(value: T) -> Self {
+        RwLock {
+            semaphore: Semaphore::new(MAX_READERS),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    /// Waits until shared access is acquired
+    pub async fn read(&self) -> RwLockReadGuard<'_, T> {
+        self.semaphore.acquire().await.forget();
+        RwLockReadGuard {
+            lock: self,
+            _value: PhantomData,
+        }
+    }
+
+    /// Waits until exclusive access is acquired
+    pub async fn write(&self) -> RwLockWriteGuard<'_, T> {
+        self.semaphore.acquire_many(MAX_READERS).await.forget();
+        RwLockWriteGuard {
+            lock: self,
+            _value: PhantomData,
+        }
+    }
+
+    /// Returns a mutable reference to the value, which needs no locking as the
+    /// RwLock is borrowed mutably
+    pub fn get_mut(&mut self) -> &mut T {
+        self.value.get_mut()
+    }
+
+    pub fn into_inner(self) -> T {
+        self.value.into_inner()
+    }
+}
+
+impl<T> Deref for RwLockReadGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.value.get() }
+    }
+}
+
+impl<T> Drop for RwLockReadGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.semaphore.add_permits(1);
+    }
+}
+
+impl<T> Deref for RwLockWriteGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.value.get() }
+    }
+}
+
+impl<T> DerefMut for RwLockWriteGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.value.get() }
+    }
+}
+
+impl<T> Drop for RwLockWriteGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.semaphore.add_permits(MAX_READERS);
+    }
+}
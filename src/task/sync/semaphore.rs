@@ -0,0 +1,198 @@
+//! This module provides an async counting semaphore, which the Mutex and RwLock are built on.
+
+use crate::sync::IrqSafeMutex;
+use alloc::collections::VecDeque;
+use core::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll, Waker},
+};
+
+/// A Task waiting to acquire permits
+struct Waiter {
+    id: u64,
+    permits: usize,
+    waker: Waker,
+}
+
+struct State {
+    /// Permits which are available to acquire
+    permits: usize,
+
+    /// Tasks waiting to acquire permits, in the order they started waiting
+    waiters: VecDeque<Waiter>,
+
+    /// Identifier given to the next Task which waits
+    next_id: u64,
+}
+
+/// An async semaphore which hands out permits in first-in, first-out order.
+///
+/// A Task which cannot acquire enough permits waits without blocking the executor, and is
+/// woken when permits are released. Waiting Tasks acquire permits in the order they started
+/// waiting, so a Task acquiring many permits is not starved by Tasks acquiring few.
+pub struct Semaphore {
+    state: IrqSafeMutex<State>,
+}
+
+/// Permits acquired from a Semaphore, which are released when this is dropped
+pub struct SemaphorePermit<'a> {
+    semaphore: &'a Semaphore,
+    permits: usize,
+}
+
+impl Semaphore {
+    /// Creates a semaphore with `permits` permits available
+    pub const fn new(permits: usize) -> Self {
+        Semaphore {
+            state: IrqSafeMutex::new(State {
+                permits,
+                waiters: VecDeque::new(),
+                next_id: 0,
+            }),
+        }
+    }
+
+    /// Returns the number of permits available
+    pub fn available_permits(&self) -> usize {
+        self.state.lock().permits
+    }
+
+    /// Waits until one permit can be acquired
+    pub fn acquire(&self) -> Acquire<'_> {
+        self.acquire_many(1)
+    }
+
+    /// Waits until `permits` permits can be acquired at once
+    pub fn acquire_many(&self, permits: usize) -> Acquire<'_> {
+        Acquire {
+            semaphore: self,
+            permits,
+            id: None,
+        }
+    }
+
+    /// Acquires one permit if it is available and no Task is waiting
+    pub fn try_acquire(&self) -> Option<SemaphorePermit<'_>> {
+        let mut state = self.state.lock();
+        if state.waiters.is_empty() && state.permits >= 1 {
+            state.permits -= 1;
+            Some(SemaphorePermit {
+                semaphore: self,
+                permits: 1,
+            })
+        } else {
+            None
+        }
+    }
+
+    /// Makes `permits` permits available, waking the first waiting Task
+    pub fn add_permits(&self, permits: usize) {
+        let waker = {
+            let mut state = self.state.lock();
+            state.permits += permits;
+            state.first_waker_if_ready()
+        };
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+}
+
+impl State {
+    /// Returns the Waker of the first waiting Task if enough permits are available for it
+    fn first_waker_if_ready(&self) -> Option<Waker> {
+        self.waiters
+            .front()
+            .filter(|waiter| waiter.permits <= self.permits)
+            .map(|waiter| waiter.waker.clone())
+    }
+}
+
+impl SemaphorePermit<'_> {
+    /// Keeps the permits acquired, so they are not released when this is dropped
+    pub fn forget(self) {
+        core::mem::forget(self);
+    }
+}
+
+impl Drop for SemaphorePermit<'_> {
+    fn drop(&mut self) {
+        self.semaphore.add_permits(self.permits);
+    }
+}
+
+/// Future returned by Semaphore::acquire and Semaphore::acquire_many
+pub struct Acquire<'a> {
+    semaphore: &'a Semaphore,
+    permits: usize,
+
+    /// Identifier of this Future in the waiter queue, once it has started waiting
+    id: Option<u64>,
+}
+
+impl<'a> Future for Acquire<'a> {
+    type Output = SemaphorePermit<'a>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<SemaphorePermit<'a>> {
+        let semaphore = self.semaphore;
+        let permits = self.permits;
+        let (result, next_waker) = {
+            let mut state = semaphore.state.lock();
+
+            // Only the first waiting Task may take permits, or any Task if none are waiting
+            let first = state.waiters.front().map(|waiter| waiter.id);
+            let is_next = first.is_none() || first == self.id;
+            if is_next && state.permits >= permits {
+                state.permits -= permits;
+                if self.id.take().is_some() {
+                    state.waiters.pop_front();
+                }
+
+                // There may be permits left for the Task which is now first
+                (Poll::Ready(()), state.first_waker_if_ready())
+            } else {
+                match self.id {
+                    Some(id) => {
+                        if let Some(waiter) = state.waiters.iter_mut().find(|w| w.id == id) {
+                            waiter.waker.clone_from(cx.waker());
+                        }
+                    }
+                    None => {
+                        let id = state.next_id;
+                        state.next_id += 1;
+                        state.waiters.push_back(Waiter {
+                            id,
+                            permits,
+                            waker: cx.waker().clone(),
+                        });
+                        self.id = Some(id);
+                    }
+                }
+                (Poll::Pending, None)
+            }
+        };
+
+        if let Some(waker) = next_waker {
+            waker.wake();
+        }
+        result.map(|()| SemaphorePermit { semaphore, permits })
+    }
+}
+
+impl Drop for Acquire<'_> {
+    /// Leaves the waiter queue, waking the next Task if this one was holding it up
+    fn drop(&mut self) {
+        let Some(id) = self.id else {
+            return;
+        };
+        let waker = {
+            let mut state = self.semaphore.state.lock();
+            state.waiters.retain(|waiter| waiter.id != id);
+            state.first_waker_if_ready()
+        };
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+}
@@ -0,0 +1,142 @@
+//! This module provides task-local storage, which is declared with the task_local! macro.
+//!
+//! Each Task owns a TaskLocals holding its value of every task-local it has used. Executors
+//! poll a Task inside TaskLocals::enter, which records the Task's TaskLocals in the per-CPU
+//! data for the duration of the poll, so a task-local always refers to the value of the Task
+//! being polled. A Task's value is created by the task-local's initializer on first use, and
+//! dropped along with the Task. If the thread polling a Task is preempted, the thread
+//! scheduler saves the Task's TaskLocals until the thread runs again.
+//!
+//! Task-locals must not be used from interrupt handlers, which would see the values of
+//! whichever Task they interrupted.
+
+use crate::per_cpu;
+use alloc::{boxed::Box, collections::BTreeMap};
+use core::{any::Any, cell::RefCell, fmt, ptr};
+
+/// Declares task-locals, each of which has a separate value in every Task.
+///
+/// ```ignore
+/// task_local! {
+///     static REQUEST_ID: Cell<u64> = Cell::new(0);
+/// }
+///
+/// REQUEST_ID.with(|id| id.set(id.get() + 1));
+/// ```
+#[macro_export]
+macro_rules! task_local {
+    () => {};
+    ($(#[$attr:meta])* $vis:vis static $name:ident: $t:ty = $init:expr; $($rest:tt)*) => {
+        $(#[$attr])*
+        $vis static $name: $crate::task::task_local::LocalKey<$t> = {
+            fn init() -> $t {
+                $init
+            }
+            $crate::task::task_local::LocalKey::new(init)
+        };
+        $crate::task_local!($($rest)*);
+    };
+}
+
+/// Error returned by LocalKey::try_with when no Task is being polled
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AccessError;
+
+impl fmt::Display for AccessError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "task-local accessed outside of a task")
+    }
+}
+
+/// Key of a task-local, which is declared with the task_local! macro
+pub struct LocalKey<T: 'static> {
+    /// Creates the value of a Task which has not used this task-local before
+    init: fn() -> T,
+}
+
+impl<T: Send + 'static> LocalKey<T> {
+    #[doc(hidden)]
+    pub const fn new(init: fn() -> T) -> Self {
+        LocalKey { init }
+    }
+
+    /// Calls `f` with a reference to the current Task's value, panicking if no Task is being polled
+    pub fn with<F, R>(&'static self, f: F) -> R
+    where
+        F: FnOnce(&T) -> R,
+    {
+        self.try_with(f)
+            .expect("task-local accessed outside of a task")
+    }
+
+    /// Calls `f` with a reference to the current Task's value, if a Task is being polled
+    pub fn try_with<F, R>(&'static self, f: F) -> Result<R, AccessError>
+    where
+        F: FnOnce(&T) -> R,
+    {
+        let locals = per_cpu!(task_locals());
+        if locals.is_null() {
+            return Err(AccessError);
+        }
+
+        // The TaskLocals outlive the poll of their Task, which this is called from
+        let value = unsafe { &*locals }.get_or_init(self);
+        Ok(f(value))
+    }
+
+    /// Identifies this task-local in a TaskLocals
+    fn key(&'static self) -> usize {
+        ptr::from_ref(self).addr()
+    }
+}
+
+/// The values of the task-locals a Task has used, indexed by the address of their LocalKey
+pub(crate) struct TaskLocals {
+    values: RefCell<BTreeMap<usize, Box<dyn Any + Send>>>,
+}
+
+impl TaskLocals {
+    pub(crate) fn new() -> Self {
+        TaskLocals {
+            values: RefCell::new(BTreeMap::new()),
+        }
+    }
+
+    /// Runs `f`, which polls the Task owning these TaskLocals, with them as the current
+    /// CPU's task-locals. Those of any Task being polled already are restored afterwards,
+    /// so a Task can run a nested executor.
+    pub(crate) fn enter<R>(&self, f: impl FnOnce() -> R) -> R {
+        let previous = per_cpu!(task_locals());
+        per_cpu!(set_task_locals(self));
+        let result = f();
+        per_cpu!(set_task_locals(previous));
+        result
+    }
+
+    /// Returns this Task's value of `key`, creating it with the initializer on first use.
+    ///
+    /// Each value is boxed, so it stays in place while other values are added, and the
+    /// RefCell is only borrowed to look the value up, so the initializer and the caller
+    /// can use other task-locals.
+    fn get_or_init<T: Send + 'static>(&self, key: &'static LocalKey<T>) -> &T {
+        let existing = self
+            .values
+            .borrow()
+            .get(&key.key())
+            .map(|value| ptr::from_ref(value.as_ref()));
+        let value = existing.unwrap_or_else(|| {
+            let new: Box<dyn Any + Send> = Box::new((key.init)());
+            let mut values = self.values.borrow_mut();
+
+            // If the initializer used this task-local itself, keep the value it created
+            let value = values.entry(key.key()).or_insert(new);
+            ptr::from_ref(value.as_ref())
+        });
+
+        // Values are only removed when the TaskLocals are dropped
+        let value = unsafe { &*value };
+        value
+            .downcast_ref()
+            .expect("task-local value has the wrong type")
+    }
+}
@@ -0,0 +1,123 @@
+//! This module provides an Executor for tests, which runs Tasks against a virtual clock.
+//!
+//! While a TestExecutor exists, sleeps and timeouts measure their deadlines against a clock
+//! which starts at 0 and only moves when the test calls advance, so timers fire at exactly
+//! the same points on every run, however slow the machine is. Tests drive the Tasks with
+//! run_until_stalled, which polls until no Task is ready, and can inject scancodes as if
+//! they had been received by the keyboard interrupt handler.
+
+use super::{JoinHandle, executor::Executor, executor::Spawner, keyboard, timer};
+use core::{
+    future::Future,
+    pin::Pin,
+    sync::atomic::{AtomicBool, Ordering},
+    task::{Context, Poll, Waker},
+};
+
+/// Whether a TestExecutor exists, as there is only one virtual clock
+static ACTIVE: AtomicBool = AtomicBool::new(false);
+
+/// An Executor whose Tasks see a virtual clock, which is advanced by the test
+pub struct TestExecutor {
+    executor: Executor,
+}
+
+impl TestExecutor {
+    /// Creates a TestExecutor and switches timers to a virtual clock starting at 0, until
+    /// it is dropped. Panics if another TestExecutor exists.
+    pub fn new() -> Self {
+        if ACTIVE.swap(true, Ordering::SeqCst) {
+            panic!("only one TestExecutor can exist at a time");
+        }
+        timer::set_virtual_time(Some(0));
+
+        let mut executor = Executor::new();
+        // Polls take real time, which has no bearing on the virtual clock
+        executor.set_poll_budget(None);
+        TestExecutor { executor }
+    }
+
+    /// Returns the virtual time in nanoseconds
+    pub fn now(&self) -> u64 {
+        timer::now()
+    }
+
+    /// Spawns a Future as a Task, which is first polled by the next call to
+    /// run_until_stalled, advance or run_until_complete
+    pub fn spawn<F>(&mut self, future: F) -> JoinHandle<F::Output>
+    where
+        F: Future + 'static,
+    {
+        self.executor.spawn(future)
+    }
+
+    /// Returns a Spawner for this TestExecutor
+    pub fn spawner(&self) -> Spawner {
+        self.executor.spawner()
+    }
+
+    /// Polls Tasks until none are ready, without moving the virtual clock
+    pub fn run_until_stalled(&mut self) {
+        self.executor.run_until_idle();
+    }
+
+    /// Moves the virtual clock forward by `nanos` nanoseconds, firing each timer whose
+    /// deadline is passed in order of deadline, and running the Tasks it wakes until they
+    /// stall before the clock moves on to the next deadline
+    pub fn advance(&mut self, nanos: u64) {
+        let target = self.now().saturating_add(nanos);
+        while let Some(deadline) = timer::next_deadline().filter(|&deadline| deadline <= target) {
+            timer::set_virtual_time(Some(deadline.max(self.now())));
+            timer::tick();
+            self.run_until_stalled();
+        }
+        timer::set_virtual_time(Some(target));
+        self.run_until_stalled();
+    }
+
+    /// Spawns a Future as a Task and runs the Tasks, advancing the virtual clock to the next
+    /// deadline whenever they stall, until the Future completes. Panics if the Tasks stall
+    /// with no timers pending, as nothing could then complete the Future.
+    pub fn run_until_complete<F>(&mut self, future: F) -> F::Output
+    where
+        F: Future + 'static,
+    {
+        let mut handle = self.spawn(future);
+        loop {
+            self.run_until_stalled();
+            if handle.is_finished() {
+                break;
+            }
+            let Some(deadline) = timer::next_deadline() else {
+                panic!("tasks stalled with no timers pending before the future completed");
+            };
+            self.advance(deadline.saturating_sub(self.now()));
+        }
+
+        // The Task has completed, so its output is ready without waiting
+        match Pin::new(&mut handle).poll(&mut Context::from_waker(Waker::noop())) {
+            Poll::Ready(output) => output.expect("task cancelled before completing"),
+            Poll::Pending => unreachable!("finished JoinHandle is pending"),
+        }
+    }
+
+    /// Passes a scancode to the keyboard's ScancodeStream as the keyboard interrupt handler
+    /// would, then runs the Tasks until they stall. The ScancodeStream must have been created.
+    pub fn inject_scancode(&mut self, scancode: u8) {
+        keyboard::add_scancode(scancode);
+        self.run_until_stalled();
+    }
+}
+
+impl Default for TestExecutor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for TestExecutor {
+    fn drop(&mut self) {
+        timer::set_virtual_time(None);
+        ACTIVE.store(false, Ordering::SeqCst);
+    }
+}
@@ -0,0 +1,168 @@
+//! This module provides async sleeps and timeouts, driven by the timer interrupt.
+//!
+//! A Sleep registers its Waker with its deadline the first time it is polled, and the timer
+//! interrupt handler wakes every Sleep whose deadline has passed, so a sleeping Task is woken
+//! on the first timer tick after its deadline. Deadlines are measured with time::now, so
+//! time::init must have been called for sleeps to ever complete.
+//!
+//! A TestExecutor replaces time::now with a virtual clock while it exists, which only moves
+//! when the test advances it, so tests can step through timers deterministically.
+
+use crate::{sync::IrqSafeMutex, time};
+use alloc::{collections::BTreeMap, vec::Vec};
+use core::{
+    fmt,
+    future::Future,
+    pin::Pin,
+    sync::atomic::{AtomicU64, Ordering},
+    task::{Context, Poll, Waker},
+};
+
+/// Wakers of pending Sleeps, indexed by their deadline and then a unique identifier
+static TIMERS: IrqSafeMutex<BTreeMap<(u64, u64), Waker>> = IrqSafeMutex::new(BTreeMap::new());
+
+/// Value of VIRTUAL_NOW while timers use time::now
+const REAL_TIME: u64 = u64::MAX;
+
+/// The virtual clock's time in nanoseconds, or REAL_TIME
+static VIRTUAL_NOW: AtomicU64 = AtomicU64::new(REAL_TIME);
+
+/// Returns the time in nanoseconds which deadlines are measured against. This is
+/// time::now, unless a TestExecutor has replaced it with a virtual clock.
+pub fn now() -> u64 {
+    match VIRTUAL_NOW.load(Ordering::SeqCst) {
+        REAL_TIME => time::now(),
+        virtual_now => virtual_now,
+    }
+}
+
+/// Switches timers to a virtual clock showing `nanos`, or back to time::now for None
+pub(crate) fn set_virtual_time(nanos: Option<u64>) {
+    VIRTUAL_NOW.store(nanos.unwrap_or(REAL_TIME), Ordering::SeqCst);
+}
+
+/// Returns the earliest deadline of any pending Sleep
+pub(crate) fn next_deadline() -> Option<u64> {
+    TIMERS.lock().keys().next().map(|&(deadline, _)| deadline)
+}
+
+/// Returns a Future which completes `nanos` nanoseconds from now
+pub fn sleep(nanos: u64) -> Sleep {
+    sleep_until(now().saturating_add(nanos))
+}
+
+/// Returns a Future which completes once now() reaches `deadline`
+pub fn sleep_until(deadline: u64) -> Sleep {
+    // Initialise the NEXT_ID static variable as 0 only once
+    static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+    Sleep {
+        deadline,
+        id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+        registered: false,
+    }
+}
+
+/// Future returned by sleep and sleep_until
+pub struct Sleep {
+    deadline: u64,
+    id: u64,
+
+    /// Whether the Waker is in TIMERS
+    registered: bool,
+}
+
+impl Sleep {
+    /// Returns the deadline, in nanoseconds as returned by now()
+    pub fn deadline(&self) -> u64 {
+        self.deadline
+    }
+
+    fn key(&self) -> (u64, u64) {
+        (self.deadline, self.id)
+    }
+}
+
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        if now() >= self.deadline {
+            if self.registered {
+                TIMERS.lock().remove(&self.key());
+                self.registered = false;
+            }
+            return Poll::Ready(());
+        }
+
+        // The timer interrupt may have removed the Waker since the last poll, which is fine as
+        // the deadline has not passed, so it was not woken yet. Reinserting it covers that case.
+        TIMERS.lock().insert(self.key(), cx.waker().clone());
+        self.registered = true;
+        Poll::Pending
+    }
+}
+
+impl Drop for Sleep {
+    fn drop(&mut self) {
+        if self.registered {
+            TIMERS.lock().remove(&self.key());
+        }
+    }
+}
+
+/// Error returned by a Timeout whose Future did not complete before the deadline
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Elapsed;
+
+impl fmt::Display for Elapsed {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "deadline has elapsed")
+    }
+}
+
+/// Returns a Future which outputs the output of `future`, or Elapsed if it does not
+/// complete within `nanos` nanoseconds, in which case `future` is dropped
+pub fn timeout<F: Future>(nanos: u64, future: F) -> Timeout<F> {
+    Timeout {
+        future,
+        sleep: sleep(nanos),
+    }
+}
+
+/// Future returned by timeout
+pub struct Timeout<F> {
+    future: F,
+    sleep: Sleep,
+}
+
+impl<F: Future> Future for Timeout<F> {
+    type Output = Result<F::Output, Elapsed>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<F::Output, Elapsed>> {
+        // The Future is pinned along with the Timeout, and the Sleep is Unpin
+        let this = unsafe { self.get_unchecked_mut() };
+        let future = unsafe { Pin::new_unchecked(&mut this.future) };
+
+        // The Future is polled first, so it wins if it completes by the deadline
+        if let Poll::Ready(output) = future.poll(cx) {
+            return Poll::Ready(Ok(output));
+        }
+        Pin::new(&mut this.sleep).poll(cx).map(|()| Err(Elapsed))
+    }
+}
+
+/// Called on each timer tick to wake the Tasks whose Sleeps have passed their deadline
+pub(crate) fn tick() {
+    let now = now();
+    let expired: Vec<Waker> = {
+        let mut timers = TIMERS.lock();
+        let pending = timers.split_off(&(now.saturating_add(1), 0));
+        core::mem::replace(&mut *timers, pending)
+            .into_values()
+            .collect()
+    };
+    for waker in expired {
+        waker.wake();
+    }
+}
@@ -0,0 +1,421 @@
+//! This module provides preemptive kernel threads.
+//!
+//! Each thread has a thread control block (Thread) and its own kernel stack on the heap. A
+//! thread is switched out by switch_context, which pushes the callee-saved registers onto the
+//! thread's stack, saves its stack pointer in the Thread, then loads the next thread's stack
+//! pointer and pops its registers.
+//!
+//! Threads have a priority, and the scheduler always runs a ready thread of the highest
+//! priority. Threads of the same priority take turns, each running for a time slice of a few
+//! timer ticks. The timer interrupt requests preemption when the running thread's time slice
+//! is used up, or a thread of a higher priority is ready, and once the interrupt has been
+//! acknowledged the IRQ stub switches threads. Threads can also give up the CPU by yielding,
+//! sleeping, or waiting in a WaitQueue, for example to join another thread.
+//!
+//! The code which first calls into the scheduler, such as by spawning a thread, becomes the
+//! boot thread, which keeps running on the stack it was already using. In the kernel this is
+//! kernel_main, which goes on to run the async Executor, so the Executor runs inside one
+//! thread alongside the others.
+//!
+//! The Task being polled and its task-locals are recorded in the per-CPU data, but belong to
+//! the thread polling it, which may be preempted mid-poll. A thread's values are therefore
+//! saved while it is switched out, and other threads start with none.
+//!
+//! Threads only run on the bootstrap processor, as it is the CPU which receives the timer
+//! interrupt. Sleep deadlines are checked on each timer tick, and use time::now(), so
+//! time::init must have been called before threads sleep.
+
+mod scheduler;
+mod wait_queue;
+
+pub use wait_queue::WaitQueue;
+
+use crate::backtrace::{self, StackBounds};
+use crate::{per_cpu, percpu, time};
+use alloc::{boxed::Box, vec, vec::Vec};
+use core::{
+    arch::naked_asm,
+    mem, ptr,
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+};
+use scheduler::{Scheduler, SwitchReason, TIME_SLICE_TICKS};
+use spin::Mutex;
+use x86_64::{VirtAddr, instructions::interrupts};
+
+/// Size of each thread's kernel stack
+const STACK_SIZE: usize = 4096 * 4;
+
+/// Identifier for Thread instances
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ThreadId(u64);
+
+impl ThreadId {
+    fn new() -> Self {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+        ThreadId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
+/// Scheduling priority of a thread. A thread only runs while no
+/// thread of a higher priority is ready.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    Low,
+    #[default]
+    Normal,
+    High,
+}
+
+impl Priority {
+    /// Every priority, from lowest to highest
+    const ALL: [Priority; 3] = [Priority::Low, Priority::Normal, Priority::High];
+}
+
+/// Scheduling state of a thread
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ThreadState {
+    /// The thread is running on the CPU
+    Running,
+
+    /// The thread is waiting for its turn to run
+    Ready,
+
+    /// The thread is waiting in a WaitQueue
+    Blocked,
+
+    /// The thread is sleeping until a deadline
+    Sleeping,
+}
+
+/// Information about a thread, as returned by threads()
+#[derive(Debug, Clone, Copy)]
+pub struct ThreadInfo {
+    pub id: ThreadId,
+    pub priority: Priority,
+    pub state: ThreadState,
+
+    /// Whether this is the idle thread, which runs while no other thread is ready
+    pub is_idle: bool,
+
+    /// Nanoseconds the thread has spent running
+    pub cpu_time: u64,
+}
+
+/// Thread control block
+struct Thread {
+    id: ThreadId,
+    priority: Priority,
+    state: ThreadState,
+
+    /// Whether this is the idle thread
+    is_idle: bool,
+
+    /// Stack pointer saved by switch_context while the thread is not running
+    rsp: u64,
+
+    /// The thread's kernel stack, or None for the boot thread, which keeps
+    /// the stack it was running on
+    stack: Option<Box<[u8]>>,
+
+    /// Timer ticks left in the thread's time slice
+    slice_left: u32,
+
+    /// time::now() at which a sleeping thread becomes ready
+    wake_at: u64,
+
+    /// Set if the thread was unblocked while it was still running, so it does not block
+    wake_pending: bool,
+
+    /// Nanoseconds the thread spent running before it was last switched to
+    cpu_time: u64,
+}
+
+impl Thread {
+    /// Creates the Thread of the code which is already running
+    fn boot() -> Box<Thread> {
+        Box::new(Thread {
+            id: ThreadId::new(),
+            priority: Priority::Normal,
+            state: ThreadState::Running,
+            is_idle: false,
+            rsp: 0,
+            stack: None,
+            slice_left: TIME_SLICE_TICKS,
+            wake_at: 0,
+            wake_pending: false,
+            cpu_time: 0,
+        })
+    }
+
+    /// Creates a Thread which calls `entry` on a new stack when it is first switched to
+    fn new(entry: fn(), priority: Priority, is_idle: bool) -> Box<Thread> {
+        let mut stack = vec![0u8; STACK_SIZE].into_boxed_slice();
+        let bounds = stack_bounds(&stack);
+        backtrace::register_stack(bounds);
+
+        // Lay out the registers which switch_context pops when it first switches to the
+        // thread, in the order it pops them: r15, r14, r13, r12, rbx, rbp and the return
+        // address. The entry point is passed in r12, and rbp is 0 to end backtraces.
+        // The stack is 16 byte aligned after thread_entry is "returned" to.
+        let frame: [u64; 7] = [
+            0,
+            0,
+            0,
+            entry as *const () as u64,
+            0,
+            0,
+            thread_entry as *const () as u64,
+        ];
+        let top = bounds.top.align_down(16u64);
+        let rsp = top - 16u64 - mem::size_of_val(&frame) as u64;
+        let offset = (rsp - bounds.bottom) as usize;
+        unsafe {
+            stack
+                .as_mut_ptr()
+                .add(offset)
+                .cast::<[u64; 7]>()
+                .write_unaligned(frame)
+        };
+
+        Box::new(Thread {
+            id: ThreadId::new(),
+            priority,
+            state: ThreadState::Ready,
+            is_idle,
+            rsp: rsp.as_u64(),
+            stack: Some(stack),
+            slice_left: TIME_SLICE_TICKS,
+            wake_at: 0,
+            wake_pending: false,
+            cpu_time: 0,
+        })
+    }
+}
+
+impl Drop for Thread {
+    fn drop(&mut self) {
+        if let Some(stack) = &self.stack {
+            backtrace::unregister_stack(stack_bounds(stack));
+        }
+    }
+}
+
+/// Returns the bounds of a thread's stack
+fn stack_bounds(stack: &[u8]) -> StackBounds {
+    let bottom = VirtAddr::from_ptr(stack.as_ptr());
+    StackBounds {
+        bottom,
+        top: bottom + stack.len(),
+    }
+}
+
+/// The scheduler, which is only locked with interrupts disabled, as the
+/// timer interrupt locks it to preempt the running thread
+static SCHEDULER: Mutex<Scheduler> = Mutex::new(Scheduler::new());
+
+/// Set by interrupt handlers to switch threads once the interrupt has been acknowledged
+static PREEMPTION_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+/// Woken whenever a thread exits, so join can check whether its thread has exited
+static EXITED: WaitQueue = WaitQueue::new();
+
+/// Spawns a kernel thread with normal priority which runs `entry`, then exits when it returns
+pub fn spawn_thread(entry: fn()) -> ThreadId {
+    spawn_thread_with_priority(entry, Priority::Normal)
+}
+
+/// Spawns a kernel thread with the given priority which runs `entry`, then exits when it
+/// returns. It runs after any ready threads of the same priority.
+pub fn spawn_thread_with_priority(entry: fn(), priority: Priority) -> ThreadId {
+    let thread = Thread::new(entry, priority, false);
+    let id = thread.id;
+
+    interrupts::without_interrupts(|| {
+        let mut scheduler = SCHEDULER.lock();
+        let current = scheduler.start(time::now());
+        if priority > current.priority || current.is_idle {
+            request_preemption();
+        }
+        scheduler.make_ready(thread);
+    });
+    id
+}
+
+/// Returns the ThreadId of the running thread, or None if called on a CPU other
+/// than the bootstrap processor, which does not run threads
+pub fn current_id() -> Option<ThreadId> {
+    if per_cpu!(cpu_id) != 0 {
+        return None;
+    }
+    interrupts::without_interrupts(|| Some(SCHEDULER.lock().start(time::now()).id))
+}
+
+/// Lets ready threads of the same or a higher priority run before the running thread continues
+pub fn yield_now() {
+    interrupts::without_interrupts(|| switch(SwitchReason::Ready));
+}
+
+/// Blocks the running thread for at least `nanos` nanoseconds.
+///
+/// The thread becomes ready on the first timer tick after the deadline. On CPUs other than
+/// the bootstrap processor, which do not run threads, this spins instead.
+pub fn sleep(nanos: u64) {
+    let deadline = time::now().saturating_add(nanos);
+    while time::now() < deadline {
+        interrupts::without_interrupts(|| switch(SwitchReason::Sleeping(deadline)));
+    }
+}
+
+/// Blocks the running thread until the thread with `id` has exited
+pub fn join(id: ThreadId) {
+    EXITED.wait_until(|| !interrupts::without_interrupts(|| SCHEDULER.lock().contains(id)));
+}
+
+/// Returns information about every thread which has not exited, including CPU time
+pub fn threads() -> Vec<ThreadInfo> {
+    interrupts::without_interrupts(|| SCHEDULER.lock().info(time::now()))
+}
+
+/// Called on each timer tick to wake sleeping threads, and to preempt the running thread
+/// if its time slice is used up or a thread of a higher priority is ready
+pub(crate) fn tick() {
+    if per_cpu!(cpu_id) != 0 {
+        return;
+    }
+
+    let mut scheduler = SCHEDULER.lock();
+    scheduler.wake_sleepers(time::now());
+    let highest_ready = scheduler.highest_ready();
+    let Some(current) = scheduler.current.as_mut() else {
+        return;
+    };
+
+    current.slice_left = current.slice_left.saturating_sub(1);
+    let higher_ready = highest_ready.is_some_and(|priority| priority > current.priority);
+    let any_ready = highest_ready.is_some();
+    if current.slice_left == 0 || higher_ready || (current.is_idle && any_ready) {
+        request_preemption();
+    }
+}
+
+/// Asks for the running thread to be preempted once the current interrupt has been handled
+fn request_preemption() {
+    PREEMPTION_REQUESTED.store(true, Ordering::Relaxed);
+}
+
+/// Switches to the next ready thread if preemption has been requested.
+///
+/// This is called by IRQ stubs with interrupts disabled, after the interrupt has been
+/// acknowledged, so the next thread can receive further interrupts.
+pub(crate) fn preempt_if_requested() {
+    if PREEMPTION_REQUESTED.swap(false, Ordering::Relaxed) {
+        switch(SwitchReason::Ready);
+    }
+}
+
+/// Makes a thread blocked in a WaitQueue ready, requesting preemption if
+/// it has a higher priority than the running thread
+fn unblock(id: ThreadId) {
+    interrupts::without_interrupts(|| {
+        let mut scheduler = SCHEDULER.lock();
+        scheduler.unblock(id);
+        let highest_ready = scheduler.highest_ready();
+        if let Some(current) = scheduler.current.as_ref()
+            && (current.is_idle
+                || highest_ready.is_some_and(|priority| priority > current.priority))
+        {
+            request_preemption();
+        }
+    });
+}
+
+/// Switches from the running thread to the next thread to run, which must be done with
+/// interrupts disabled. `reason` says what happens to the running thread. Returns when the
+/// running thread is switched back to, or immediately if it keeps running.
+fn switch(reason: SwitchReason) {
+    if per_cpu!(cpu_id) != 0 {
+        return;
+    }
+
+    let Some((prev_rsp, next_rsp)) = SCHEDULER.lock().switch(reason, time::now()) else {
+        return;
+    };
+
+    // The running thread's Task and task-locals are kept on its stack until it is switched
+    // back to, so the next thread does not see them
+    let cpu = percpu::current();
+    let (task, locals) = (cpu.current_task(), cpu.task_locals());
+    cpu.set_current_task(None);
+    cpu.set_task_locals(ptr::null());
+
+    // The scheduler is unlocked before switching, as the next thread may have been switched
+    // out anywhere. Interrupts are still disabled, so nothing else runs on this CPU meanwhile.
+    unsafe { switch_context(prev_rsp, next_rsp) };
+
+    cpu.set_current_task(task);
+    cpu.set_task_locals(locals);
+
+    // Every thread which has exited has now switched away from its stack, so they can be
+    // freed. They are dropped once the scheduler is unlocked.
+    let finished = mem::take(&mut SCHEDULER.lock().finished);
+    drop(finished);
+}
+
+/// Ends the running thread, waking any threads joining it
+fn exit() -> ! {
+    interrupts::disable();
+    EXITED.notify_all();
+    switch(SwitchReason::Exited);
+    unreachable!("an exited thread was switched back to");
+}
+
+/// Entry point of the idle thread, which runs while no other thread is ready
+fn idle_loop() {
+    crate::hlt_loop()
+}
+
+/// Saves the callee-saved registers and stack pointer of the running thread to `prev_rsp`,
+/// then restores those of the thread whose stack pointer is `next_rsp`, and returns into it.
+///
+/// The caller-saved registers are saved by the compiler around the call as usual, so the
+/// callee-saved registers are all which must be switched.
+#[unsafe(naked)]
+unsafe extern "C" fn switch_context(prev_rsp: *mut u64, next_rsp: u64) {
+    naked_asm!(
+        "push rbp",
+        "push rbx",
+        "push r12",
+        "push r13",
+        "push r14",
+        "push r15",
+        "mov [rdi], rsp",
+        "mov rsp, rsi",
+        "pop r15",
+        "pop r14",
+        "pop r13",
+        "pop r12",
+        "pop rbx",
+        "pop rbp",
+        "ret",
+    )
+}
+
+/// Where a new thread starts, once switch_context has popped its initial registers
+#[unsafe(naked)]
+unsafe extern "C" fn thread_entry() -> ! {
+    naked_asm!(
+        "mov rdi, r12",
+        "call {start}",
+        "ud2",
+        start = sym thread_start,
+    )
+}
+
+/// Runs a new thread's entry point with interrupts enabled, as it was switched
+/// to with interrupts disabled, then ends the thread
+extern "C" fn thread_start(entry: *const ()) -> ! {
+    let entry: fn() = unsafe { mem::transmute(entry) };
+    interrupts::enable();
+    entry();
+    exit()
+}
@@ -0,0 +1,226 @@
+//! This module holds the threads of the bootstrap processor in queues according to their state.
+//!
+//! Ready threads are kept in one queue per priority level, and the next thread to run is taken
+//! from the front of the highest non-empty level, so threads of the same priority take turns.
+//! When no thread is ready the idle thread runs, which halts until the next interrupt.
+
+use super::{Priority, Thread, ThreadId, ThreadInfo, ThreadState, idle_loop};
+use alloc::{boxed::Box, collections::BTreeMap, collections::VecDeque, vec::Vec};
+
+/// Number of timer ticks a thread runs for before the next ready thread of its priority
+pub(super) const TIME_SLICE_TICKS: u32 = 2;
+
+/// Number of priority levels
+const PRIORITY_LEVELS: usize = 3;
+
+/// What happens to the running thread when it is switched out
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum SwitchReason {
+    /// It was preempted or yielded, so it goes back on the ready queue
+    Ready,
+
+    /// It waits in a WaitQueue until it is unblocked
+    Blocked,
+
+    /// It sleeps until time::now() reaches the deadline
+    Sleeping(u64),
+
+    /// It has returned from its entry point
+    Exited,
+}
+
+/// The threads of the bootstrap processor.
+///
+/// Every Thread is boxed so that its rsp field stays put when it is moved between queues,
+/// as switch_context writes to it after the scheduler has been unlocked.
+pub(super) struct Scheduler {
+    /// The running thread, which is None until the scheduler is started
+    pub(super) current: Option<Box<Thread>>,
+
+    /// Threads waiting to run, in one queue per priority level
+    ready: [VecDeque<Box<Thread>>; PRIORITY_LEVELS],
+
+    /// Threads waiting in a WaitQueue
+    blocked: BTreeMap<ThreadId, Box<Thread>>,
+
+    /// Threads waiting for their wake_at deadline
+    sleeping: BTreeMap<ThreadId, Box<Thread>>,
+
+    /// The idle thread, while it is not running
+    idle: Option<Box<Thread>>,
+
+    /// Threads which have exited. A thread cannot free the stack it is running
+    /// on, so their stacks are freed by the next thread switched back to.
+    #[allow(clippy::vec_box)]
+    pub(super) finished: Vec<Box<Thread>>,
+
+    /// time::now() when the running thread was switched to, for counting CPU time
+    switched_in_at: u64,
+}
+
+impl Scheduler {
+    pub(super) const fn new() -> Self {
+        Scheduler {
+            current: None,
+            ready: [const { VecDeque::new() }; PRIORITY_LEVELS],
+            blocked: BTreeMap::new(),
+            sleeping: BTreeMap::new(),
+            idle: None,
+            finished: Vec::new(),
+            switched_in_at: 0,
+        }
+    }
+
+    /// Creates a Thread for the code which is already running, and the idle thread,
+    /// unless the scheduler has already been started. Returns the running thread.
+    pub(super) fn start(&mut self, now: u64) -> &mut Thread {
+        if self.idle.is_none() && self.current.is_none() {
+            self.idle = Some(Thread::new(idle_loop, Priority::Low, true));
+            self.switched_in_at = now;
+        }
+        self.current.get_or_insert_with(Thread::boot)
+    }
+
+    /// Adds a thread to the back of its priority's ready queue
+    pub(super) fn make_ready(&mut self, mut thread: Box<Thread>) {
+        thread.state = ThreadState::Ready;
+        self.ready[thread.priority as usize].push_back(thread);
+    }
+
+    /// Returns the highest priority of the ready threads, if any are ready
+    pub(super) fn highest_ready(&self) -> Option<Priority> {
+        Priority::ALL
+            .into_iter()
+            .rev()
+            .find(|&priority| !self.ready[priority as usize].is_empty())
+    }
+
+    /// Takes the next thread to run, which is the first thread of the highest non-empty
+    /// priority level, as long as its priority is at least `min`
+    fn pop_next(&mut self, min: Option<Priority>) -> Option<Box<Thread>> {
+        let priority = self
+            .highest_ready()
+            .filter(|&priority| Some(priority) >= min)?;
+        self.ready[priority as usize].pop_front()
+    }
+
+    /// Replaces the running thread with the next thread to run, putting the running thread
+    /// where `reason` says. Returns the running thread's rsp field, which it must be switched
+    /// out to, and the rsp of the next thread, or None if the running thread keeps running.
+    pub(super) fn switch(&mut self, reason: SwitchReason, now: u64) -> Option<(*mut u64, u64)> {
+        let current = self.start(now);
+        let current_priority = current.priority;
+        let current_is_idle = current.is_idle;
+
+        // A thread which is unblocked before it has finished blocking does not block
+        if reason == SwitchReason::Blocked && core::mem::take(&mut current.wake_pending) {
+            return None;
+        }
+
+        // A thread which can keep running is only replaced by ready threads of at least its
+        // priority, except for the idle thread, which any thread replaces
+        let min = match reason {
+            SwitchReason::Ready if !current_is_idle => Some(current_priority),
+            _ => None,
+        };
+        let mut next = match self.pop_next(min) {
+            Some(next) => next,
+            None if reason == SwitchReason::Ready || current_is_idle => {
+                if let Some(current) = self.current.as_mut() {
+                    current.slice_left = TIME_SLICE_TICKS;
+                }
+                return None;
+            }
+            None => self.idle.take().expect("idle thread missing"),
+        };
+
+        next.state = ThreadState::Running;
+        next.slice_left = TIME_SLICE_TICKS;
+        let next_rsp = next.rsp;
+        let mut prev = self.current.replace(next).expect("scheduler started");
+        prev.cpu_time += now.saturating_sub(self.switched_in_at);
+        self.switched_in_at = now;
+        let prev_rsp = &raw mut prev.rsp;
+
+        if prev.is_idle {
+            prev.state = ThreadState::Ready;
+            self.idle = Some(prev);
+            return Some((prev_rsp, next_rsp));
+        }
+        match reason {
+            SwitchReason::Ready => self.make_ready(prev),
+            SwitchReason::Blocked => {
+                prev.state = ThreadState::Blocked;
+                self.blocked.insert(prev.id, prev);
+            }
+            SwitchReason::Sleeping(deadline) => {
+                prev.state = ThreadState::Sleeping;
+                prev.wake_at = deadline;
+                self.sleeping.insert(prev.id, prev);
+            }
+            SwitchReason::Exited => self.finished.push(prev),
+        }
+        Some((prev_rsp, next_rsp))
+    }
+
+    /// Makes a blocked thread ready. If the thread is still running, because it has not yet
+    /// finished blocking, it is marked so that it does not block.
+    pub(super) fn unblock(&mut self, id: ThreadId) {
+        if let Some(thread) = self.blocked.remove(&id) {
+            self.make_ready(thread);
+        } else if let Some(current) = self.current.as_mut().filter(|current| current.id == id) {
+            current.wake_pending = true;
+        }
+    }
+
+    /// Makes every sleeping thread whose deadline has passed ready
+    pub(super) fn wake_sleepers(&mut self, now: u64) {
+        let expired: Vec<ThreadId> = self
+            .sleeping
+            .values()
+            .filter(|thread| thread.wake_at <= now)
+            .map(|thread| thread.id)
+            .collect();
+        for id in expired {
+            if let Some(thread) = self.sleeping.remove(&id) {
+                self.make_ready(thread);
+            }
+        }
+    }
+
+    /// Returns every thread which has not exited
+    fn threads(&self) -> impl Iterator<Item = &Thread> {
+        self.current
+            .iter()
+            .chain(self.ready.iter().flatten())
+            .chain(self.blocked.values())
+            .chain(self.sleeping.values())
+            .chain(self.idle.iter())
+            .map(|thread| &**thread)
+    }
+
+    /// Returns whether the thread with `id` has not exited
+    pub(super) fn contains(&self, id: ThreadId) -> bool {
+        self.threads().any(|thread| thread.id == id)
+    }
+
+    /// Returns information about every thread which has not exited
+    pub(super) fn info(&self, now: u64) -> Vec<ThreadInfo> {
+        self.threads()
+            .map(|thread| {
+                // The running thread has also been running since it was switched to
+                let running = match thread.state {
+                    ThreadState::Running => now.saturating_sub(self.switched_in_at),
+                    _ => 0,
+                };
+                ThreadInfo {
+                    id: thread.id,
+                    priority: thread.priority,
+                    state: thread.state,
+                    is_idle: thread.is_idle,
+                    cpu_time: thread.cpu_time + running,
+                }
+            })
+            .collect()
+    }
+}
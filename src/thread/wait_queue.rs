@@ -0,0 +1,75 @@
+//! This module provides a queue of threads waiting for a condition to become true.
+
+use super::scheduler::SwitchReason;
+use super::{ThreadId, current_id, switch, unblock};
+use alloc::collections::VecDeque;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+/// A queue of threads which are blocked until another thread or an interrupt
+/// handler changes some state and notifies the queue.
+///
+/// Threads wait with wait_until, which only returns once its condition is true, so a
+/// notification only needs to be sent after the state the condition checks has changed.
+pub struct WaitQueue {
+    /// The threads waiting, in the order they started waiting
+    waiting: Mutex<VecDeque<ThreadId>>,
+}
+
+impl WaitQueue {
+    pub const fn new() -> Self {
+        WaitQueue {
+            waiting: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    /// Blocks the running thread until `condition` returns true.
+    ///
+    /// The thread is added to the queue before the condition is checked, so a notification
+    /// sent after the check wakes it, even if it has not finished blocking. On CPUs other
+    /// than the bootstrap processor, which do not run threads, this spins instead.
+    pub fn wait_until(&self, mut condition: impl FnMut() -> bool) {
+        loop {
+            let done = interrupts::without_interrupts(|| {
+                let id = current_id();
+                if let Some(id) = id {
+                    self.waiting.lock().push_back(id);
+                }
+                if condition() {
+                    if let Some(id) = id {
+                        self.waiting.lock().retain(|&waiting| waiting != id);
+                    }
+                    return true;
+                }
+                switch(SwitchReason::Blocked);
+                false
+            });
+            if done {
+                return;
+            }
+            core::hint::spin_loop();
+        }
+    }
+
+    /// Wakes the thread which has been waiting longest
+    pub fn notify_one(&self) {
+        let id = interrupts::without_interrupts(|| self.waiting.lock().pop_front());
+        if let Some(id) = id {
+            unblock(id);
+        }
+    }
+
+    /// Wakes every waiting thread
+    pub fn notify_all(&self) {
+        let waiting = interrupts::without_interrupts(|| core::mem::take(&mut *self.waiting.lock()));
+        for id in waiting {
+            unblock(id);
+        }
+    }
+}
+
+impl Default for WaitQueue {
+    fn default() -> Self {
+        Self::new()
+    }
+}
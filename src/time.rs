@@ -0,0 +1,100 @@
+//! This module provides monotonic high resolution timestamps for profiling and timekeeping.
+//!
+//! Clock sources implement the Clock trait, which returns nanoseconds since the clock
+//! source was initialised. The HPET is discovered through its ACPI table, and the TSC is
+//! calibrated against the HPET, or the PIT if there is no HPET. The TSC is the cheapest to
+//! read, so it is preferred when the CPU guarantees it ticks at a constant rate.
+//!
+//! Wall clock time is read from the CMOS real time clock at boot, and then advanced
+//! with the monotonic clock.
+
+use conquer_once::spin::OnceCell;
+
+pub mod hpet;
+pub mod pit;
+pub mod rtc;
+pub mod tsc;
+
+use hpet::Hpet;
+use tsc::Tsc;
+
+/// Number of nanoseconds in a second
+pub const NANOS_PER_SEC: u64 = 1_000_000_000;
+
+/// A monotonic clock source
+pub trait Clock: Sync {
+    /// Returns the number of nanoseconds since the clock source was initialised.
+    /// Successive calls never return a smaller value.
+    fn now(&self) -> u64;
+}
+
+/// The HPET, if the firmware describes one in the ACPI tables
+static HPET: OnceCell<Hpet> = OnceCell::uninit();
+
+/// The TSC, once it has been calibrated
+static TSC: OnceCell<Tsc> = OnceCell::uninit();
+
+/// Unix time in nanoseconds read from the RTC at boot, and the monotonic clock's timestamp at that moment
+static WALL_CLOCK_BASE: OnceCell<(u64, u64)> = OnceCell::uninit();
+
+/// Initialises the HPET, calibrates the TSC, and reads the wall clock time from the RTC.
+///
+/// acpi::init must be called first so the HPET can be found. This busy waits
+/// for the length of the TSC calibration, so it should only be called once.
+pub fn init() {
+    if let Some(hpet) = Hpet::from_acpi() {
+        HPET.init_once(|| hpet);
+    }
+
+    let tsc = match HPET.try_get() {
+        Ok(hpet) => Tsc::calibrate(|| {
+            let start = hpet.now();
+            while hpet.now() - start < tsc::CALIBRATION_NANOS {
+                core::hint::spin_loop();
+            }
+        }),
+        Err(_) => Tsc::calibrate(pit::wait_for_calibration),
+    };
+    if let Some(tsc) = tsc {
+        TSC.init_once(|| tsc);
+    }
+
+    let boot_time = rtc::read_date_time().to_unix_timestamp() * NANOS_PER_SEC;
+    WALL_CLOCK_BASE.init_once(|| (boot_time, now()));
+}
+
+/// Returns the HPET, if it has been initialised
+pub fn hpet() -> Option<&'static Hpet> {
+    HPET.try_get().ok()
+}
+
+/// Returns the TSC clock source, if it has been calibrated
+pub fn tsc() -> Option<&'static Tsc> {
+    TSC.try_get().ok()
+}
+
+/// Returns the preferred clock source.
+///
+/// The TSC is preferred if it is invariant, as it is read without accessing memory mapped
+/// registers. Otherwise the HPET is preferred, as the TSC's rate may change with the CPU's
+/// power state. Returns None if init has not been called.
+pub fn clock() -> Option<&'static dyn Clock> {
+    match (tsc(), hpet()) {
+        (Some(tsc), _) if tsc.is_invariant() => Some(tsc),
+        (_, Some(hpet)) => Some(hpet),
+        (Some(tsc), None) => Some(tsc),
+        (None, None) => None,
+    }
+}
+
+/// Returns the preferred clock source's timestamp in nanoseconds, or 0 if there is no clock source
+pub fn now() -> u64 {
+    clock().map_or(0, |clock| clock.now())
+}
+
+/// Returns the wall clock time in nanoseconds since 1970-01-01 00:00:00 UTC, or None if init
+/// has not been called. Use rtc::DateTime::from_unix_timestamp to convert it to a date.
+pub fn wall_clock_now() -> Option<u64> {
+    let (boot_time, boot_timestamp) = WALL_CLOCK_BASE.try_get().ok()?;
+    Some(boot_time + now().saturating_sub(*boot_timestamp))
+}
@@ -0,0 +1,99 @@
+//! This module provides a driver for the high precision event timer (HPET).
+//!
+//! Only the HPET's main counter is used, as a clock source. Its memory mapped registers are
+//! found through the ACPI HPET table, and accessed through the bootloader's mapping of
+//! physical memory.
+
+use super::Clock;
+use crate::{acpi, bytes, memory::phys_to_virt};
+use x86_64::{PhysAddr, VirtAddr};
+
+/// Offset of the HPET's base address within the ACPI HPET table (in its generic address structure)
+const ACPI_BASE_ADDRESS_OFFSET: usize = 44;
+
+/// Register offsets from the HPET's base address
+const GENERAL_CAPABILITIES: u64 = 0x0;
+const GENERAL_CONFIGURATION: u64 = 0x10;
+const MAIN_COUNTER: u64 = 0xf0;
+
+/// Set in the general capabilities register if the main counter is 64 bits wide
+const COUNT_SIZE_CAP: u64 = 1 << 13;
+
+/// Set in the general configuration register to make the main counter run
+const ENABLE_CNF: u64 = 1 << 0;
+
+/// Largest main counter period the HPET specification allows, in femtoseconds (100ns)
+const MAX_PERIOD_FS: u64 = 0x05f5_e100;
+
+/// Number of femtoseconds in a nanosecond
+const FEMTOS_PER_NANO: u128 = 1_000_000;
+
+/// The HPET's main counter
+pub struct Hpet {
+    /// Virtual address of the HPET's registers
+    base: VirtAddr,
+
+    /// Number of femtoseconds between main counter ticks
+    period_fs: u64,
+}
+
+impl Hpet {
+    /// Finds the HPET through the ACPI tables, then resets and starts its main counter.
+    ///
+    /// Returns None if there is no HPET, its main counter is only 32 bits wide (as it would
+    /// wrap within minutes, so could not provide monotonic timestamps), or it reports a
+    /// period of zero or above the specification's maximum.
+    pub fn from_acpi() -> Option<Hpet> {
+        let table = acpi::find_table(b"HPET")?;
+        let phys = bytes::read::<u64>(table, ACPI_BASE_ADDRESS_OFFSET)?;
+        let base = phys_to_virt(PhysAddr::try_new(phys).ok()?)?;
+
+        let hpet = Hpet { base, period_fs: 0 };
+        let capabilities = hpet.read(GENERAL_CAPABILITIES);
+        if capabilities & COUNT_SIZE_CAP == 0 {
+            return None;
+        }
+        let period_fs = capabilities >> 32;
+        if period_fs == 0 || period_fs > MAX_PERIOD_FS {
+            return None;
+        }
+
+        // The main counter may only be written while it is halted
+        let configuration = hpet.read(GENERAL_CONFIGURATION);
+        hpet.write(GENERAL_CONFIGURATION, configuration & !ENABLE_CNF);
+        hpet.write(MAIN_COUNTER, 0);
+        hpet.write(GENERAL_CONFIGURATION, configuration | ENABLE_CNF);
+
+        Some(Hpet { period_fs, ..hpet })
+    }
+
+    /// Reads the register at `offset` from the HPET's base address
+    fn read(&self, offset: u64) -> u64 {
+        unsafe { (self.base + offset).as_ptr::<u64>().read_volatile() }
+    }
+
+    /// Writes `value` to the register at `offset` from the HPET's base address
+    fn write(&self, offset: u64, value: u64) {
+        unsafe {
+            (self.base + offset)
+                .as_mut_ptr::<u64>()
+                .write_volatile(value)
+        }
+    }
+
+    /// Returns the raw value of the main counter
+    pub fn counter(&self) -> u64 {
+        self.read(MAIN_COUNTER)
+    }
+
+    /// Returns the frequency of the main counter in Hz
+    pub fn frequency(&self) -> u64 {
+        (1_000_000_000_000_000 / u128::from(self.period_fs)) as u64
+    }
+}
+
+impl Clock for Hpet {
+    fn now(&self) -> u64 {
+        (u128::from(self.counter()) * u128::from(self.period_fs) / FEMTOS_PER_NANO) as u64
+    }
+}
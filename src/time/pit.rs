@@ -0,0 +1,53 @@
+//! This module uses channel 2 of the programmable interval timer (PIT) to busy wait for a
+//! fixed interval, which is used to calibrate the TSC when there is no HPET.
+//!
+//! Channel 0 drives the timer interrupt, and is left untouched. Channel 2's output is
+//! normally connected to the PC speaker, but its gate and output can be controlled and
+//! read through port 0x61 without the speaker sounding.
+
+use super::tsc::CALIBRATION_NANOS;
+use x86_64::instructions::port::Port;
+
+/// Frequency of the PIT's input clock in Hz
+const PIT_FREQUENCY: u64 = 1_193_182;
+
+/// Port addresses of channel 2's data port, the mode/command register, and the port which
+/// controls channel 2's gate and reports its output
+const CHANNEL_2_DATA_PORT: u16 = 0x42;
+const COMMAND_PORT: u16 = 0x43;
+const CONTROL_PORT: u16 = 0x61;
+
+/// Command which selects channel 2, low then high byte access, mode 0 (interrupt on
+/// terminal count) and binary counting
+const CHANNEL_2_ONE_SHOT: u8 = 0b1011_0000;
+
+/// Bits of the control port
+const GATE_2: u8 = 1 << 0;
+const SPEAKER_ENABLE: u8 = 1 << 1;
+const OUTPUT_2: u8 = 1 << 5;
+
+/// Busy waits for CALIBRATION_NANOS using PIT channel 2
+pub fn wait_for_calibration() {
+    let count = PIT_FREQUENCY * CALIBRATION_NANOS / super::NANOS_PER_SEC;
+    let mut data: Port<u8> = Port::new(CHANNEL_2_DATA_PORT);
+    let mut command: Port<u8> = Port::new(COMMAND_PORT);
+    let mut control: Port<u8> = Port::new(CONTROL_PORT);
+
+    unsafe {
+        // Disconnect the speaker and hold the gate low while the count is loaded
+        let value = control.read() & !(SPEAKER_ENABLE | GATE_2);
+        control.write(value);
+
+        command.write(CHANNEL_2_ONE_SHOT);
+        data.write(count as u8);
+        data.write((count >> 8) as u8);
+
+        // A rising edge on the gate starts the count, and the output goes
+        // high when the count reaches zero
+        control.write(value | GATE_2);
+        while control.read() & OUTPUT_2 == 0 {
+            core::hint::spin_loop();
+        }
+        control.write(value);
+    }
+}
@@ -0,0 +1,425 @@
+//! This module provides a driver for the CMOS real time clock (RTC), which keeps the date
+//! and time while the computer is off.
+//!
+//! The RTC is read once at boot, and wall clock time is then derived from the monotonic
+//! clock, as reading the RTC is slow and it only has a resolution of one second. The RTC
+//! can also raise periodic and alarm interrupts on IRQ 8, which are available as optional
+//! timer sources.
+
+use crate::interrupts::irq::{self, HandlerId, IrqError};
+use crate::{acpi, bytes};
+use alloc::boxed::Box;
+use conquer_once::spin::OnceCell;
+use core::fmt;
+use spin::{Mutex, MutexGuard, RwLock};
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::instructions::port::Port;
+
+/// Port used to select a CMOS register, and port used to read/write the selected register
+const CMOS_ADDRESS_PORT: u16 = 0x70;
+const CMOS_DATA_PORT: u16 = 0x71;
+
+/// Setting this bit in the address port disables non-maskable interrupts while a register
+/// is selected, as recommended when accessing the CMOS. Nothing else in the kernel disables
+/// NMIs, so each access ends by selecting STATUS_D with this bit clear to enable them again.
+const NMI_DISABLE: u8 = 1 << 7;
+
+/// IRQ line of the RTC
+const RTC_IRQ: u8 = 8;
+
+/// CMOS register indices
+const SECONDS: u8 = 0x00;
+const SECONDS_ALARM: u8 = 0x01;
+const MINUTES: u8 = 0x02;
+const MINUTES_ALARM: u8 = 0x03;
+const HOURS: u8 = 0x04;
+const HOURS_ALARM: u8 = 0x05;
+const DAY_OF_MONTH: u8 = 0x07;
+const MONTH: u8 = 0x08;
+const YEAR: u8 = 0x09;
+const STATUS_A: u8 = 0x0a;
+const STATUS_B: u8 = 0x0b;
+const STATUS_C: u8 = 0x0c;
+const STATUS_D: u8 = 0x0d;
+
+/// Status register A bits
+const UPDATE_IN_PROGRESS: u8 = 1 << 7;
+const RATE_MASK: u8 = 0x0f;
+
+/// Status register B bits
+const PERIODIC_INTERRUPT_ENABLE: u8 = 1 << 6;
+const ALARM_INTERRUPT_ENABLE: u8 = 1 << 5;
+const BINARY_MODE: u8 = 1 << 2;
+const HOUR_24_MODE: u8 = 1 << 1;
+
+/// Status register C bits, which report which interrupt occurred
+const PERIODIC_INTERRUPT_FLAG: u8 = 1 << 6;
+const ALARM_INTERRUPT_FLAG: u8 = 1 << 5;
+
+/// Set in the hours register in 12 hour mode for PM times
+const HOUR_PM: u8 = 1 << 7;
+
+/// Offset of the century register index within the ACPI FADT
+const FADT_CENTURY_OFFSET: usize = 108;
+
+/// Century assumed if the firmware does not provide a century register
+const DEFAULT_CENTURY: u16 = 20;
+
+/// Serialises access to the CMOS address and data ports, as selecting a register and
+/// accessing it must not be interleaved with another access
+static CMOS: Mutex<()> = Mutex::new(());
+
+/// Reads CMOS register `register`. The CMOS lock must be held.
+fn read_register(register: u8) -> u8 {
+    let mut address: Port<u8> = Port::new(CMOS_ADDRESS_PORT);
+    let mut data: Port<u8> = Port::new(CMOS_DATA_PORT);
+    unsafe {
+        address.write(NMI_DISABLE | register);
+        let value = data.read();
+        address.write(STATUS_D);
+        value
+    }
+}
+
+/// Writes `value` to CMOS register `register`. The CMOS lock must be held.
+fn write_register(register: u8, value: u8) {
+    let mut address: Port<u8> = Port::new(CMOS_ADDRESS_PORT);
+    let mut data: Port<u8> = Port::new(CMOS_DATA_PORT);
+    unsafe {
+        address.write(NMI_DISABLE | register);
+        data.write(value);
+        address.write(STATUS_D);
+    }
+}
+
+/// A calendar date and time of day in UTC
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DateTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl DateTime {
+    /// Returns the number of days between 1970-01-01 and the given civil date
+    fn days_since_epoch(year: i64, month: i64, day: i64) -> i64 {
+        // Count years from March, so the leap day is the last day of the year
+        let year = if month <= 2 { year - 1 } else { year };
+        let era = year.div_euclid(400);
+        let year_of_era = year - era * 400;
+        let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+        let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+        era * 146_097 + day_of_era - 719_468
+    }
+
+    /// Returns the number of seconds since 1970-01-01 00:00:00 UTC
+    pub fn to_unix_timestamp(&self) -> u64 {
+        let days = Self::days_since_epoch(
+            i64::from(self.year),
+            i64::from(self.month),
+            i64::from(self.day),
+        );
+        let seconds =
+            i64::from(self.hour) * 3600 + i64::from(self.minute) * 60 + i64::from(self.second);
+        (days * 86_400 + seconds).max(0) as u64
+    }
+
+    /// Converts a number of seconds since 1970-01-01 00:00:00 UTC to a date and time
+    pub fn from_unix_timestamp(timestamp: u64) -> DateTime {
+        let days = (timestamp / 86_400) as i64;
+        let seconds = timestamp % 86_400;
+
+        // Inverse of days_since_epoch
+        let days = days + 719_468;
+        let era = days.div_euclid(146_097);
+        let day_of_era = days - era * 146_097;
+        let year_of_era =
+            (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+        let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+        let shifted_month = (5 * day_of_year + 2) / 153;
+        let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+        let month = if shifted_month < 10 {
+            shifted_month + 3
+        } else {
+            shifted_month - 9
+        };
+        let year = year_of_era + era * 400 + i64::from(month <= 2);
+
+        DateTime {
+            year: year as u16,
+            month: month as u8,
+            day: day as u8,
+            hour: (seconds / 3600) as u8,
+            minute: (seconds / 60 % 60) as u8,
+            second: (seconds % 60) as u8,
+        }
+    }
+}
+
+impl fmt::Display for DateTime {
+    /// Formats the date and time in ISO 8601 format
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )
+    }
+}
+
+/// Converts a binary coded decimal byte to binary
+fn from_bcd(value: u8) -> u8 {
+    (value >> 4) * 10 + (value & 0x0f)
+}
+
+/// Converts a binary byte (less than 100) to binary coded decimal
+fn to_bcd(value: u8) -> u8 {
+    ((value / 10) << 4) | (value % 10)
+}
+
+/// Raw values of the time and date registers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct RawDateTime {
+    second: u8,
+    minute: u8,
+    hour: u8,
+    day: u8,
+    month: u8,
+    year: u8,
+    century: u8,
+}
+
+/// Reads the time and date registers once the RTC is not updating them. The CMOS lock must be held.
+fn read_raw(century_register: Option<u8>) -> RawDateTime {
+    while read_register(STATUS_A) & UPDATE_IN_PROGRESS != 0 {
+        core::hint::spin_loop();
+    }
+    RawDateTime {
+        second: read_register(SECONDS),
+        minute: read_register(MINUTES),
+        hour: read_register(HOURS),
+        day: read_register(DAY_OF_MONTH),
+        month: read_register(MONTH),
+        year: read_register(YEAR),
+        century: century_register.map_or(0, read_register),
+    }
+}
+
+/// Returns the CMOS register holding the century, if the firmware reports one in the FADT
+fn century_register() -> Option<u8> {
+    let fadt = acpi::find_table(b"FACP")?;
+    bytes::read::<u8>(fadt, FADT_CENTURY_OFFSET).filter(|&register| register != 0)
+}
+
+/// Reads the current date and time from the RTC.
+///
+/// An update may begin between checking the update in progress flag and reading the
+/// registers, so the registers are read until two consecutive reads agree.
+pub fn read_date_time() -> DateTime {
+    let century_register = century_register();
+    let (raw, status_b) = without_interrupts(|| {
+        let _cmos = CMOS.lock();
+        let mut raw = read_raw(century_register);
+        loop {
+            let next = read_raw(century_register);
+            if next == raw {
+                break;
+            }
+            raw = next;
+        }
+        (raw, read_register(STATUS_B))
+    });
+
+    // The PM bit is kept separate from the hour, as it is not part of the BCD value
+    let pm = status_b & HOUR_24_MODE == 0 && raw.hour & HOUR_PM != 0;
+    let decode = |value: u8| {
+        if status_b & BINARY_MODE == 0 {
+            from_bcd(value)
+        } else {
+            value
+        }
+    };
+
+    // 12 hour mode counts 12, 1, ..., 11, so 12 AM is hour 0 and 12 PM is hour 12
+    let mut hour = decode(raw.hour & !HOUR_PM);
+    if status_b & HOUR_24_MODE == 0 {
+        hour %= 12;
+        if pm {
+            hour += 12;
+        }
+    }
+
+    let century = match century_register {
+        Some(_) => u16::from(decode(raw.century)),
+        None => DEFAULT_CENTURY,
+    };
+
+    DateTime {
+        year: century * 100 + u16::from(decode(raw.year)),
+        month: decode(raw.month),
+        day: decode(raw.day),
+        hour,
+        minute: decode(raw.minute),
+        second: decode(raw.second),
+    }
+}
+
+/// Handler called on each periodic interrupt
+static PERIODIC_HANDLER: RwLock<Option<Box<dyn Fn() + Send + Sync>>> = RwLock::new(None);
+
+/// Handler called on the alarm interrupt
+static ALARM_HANDLER: RwLock<Option<Box<dyn Fn() + Send + Sync>>> = RwLock::new(None);
+
+/// The interrupt handler registered on the RTC's IRQ line, once either timer source is started
+static RTC_IRQ_HANDLER: OnceCell<Result<HandlerId, IrqError>> = OnceCell::uninit();
+
+/// Handles IRQ 8 by reading status register C to find which interrupt occurred, then calling
+/// its handler. The RTC does not raise another interrupt until register C has been read.
+fn rtc_interrupt_handler() {
+    let flags = {
+        let _cmos = CMOS.lock();
+        read_register(STATUS_C)
+    };
+    if flags & PERIODIC_INTERRUPT_FLAG != 0
+        && let Some(handler) = PERIODIC_HANDLER.read().as_ref()
+    {
+        handler();
+    }
+    if flags & ALARM_INTERRUPT_FLAG != 0
+        && let Some(handler) = ALARM_HANDLER.read().as_ref()
+    {
+        handler();
+    }
+}
+
+/// Registers rtc_interrupt_handler on the RTC's IRQ line if it is not already registered
+fn register_irq_handler() -> Result<(), IrqError> {
+    let result = RTC_IRQ_HANDLER.get_or_init(|| irq::register(RTC_IRQ, rtc_interrupt_handler));
+    result.as_ref().map(|_| ()).map_err(|error| *error)
+}
+
+/// Sets or clears `bits` in status register B. Taking the held CMOS guard makes the whole
+/// read-modify-write happen under one lock.
+fn update_status_b(_cmos: &mut MutexGuard<()>, bits: u8, enable: bool) {
+    let status_b = read_register(STATUS_B);
+    let status_b = if enable {
+        status_b | bits
+    } else {
+        status_b & !bits
+    };
+    write_register(STATUS_B, status_b);
+}
+
+/// Starts the RTC's periodic interrupt, calling `handler` on every interrupt.
+///
+/// The interrupt frequency is 32768 >> (rate - 1) Hz, with `rate` between 3 (8192 Hz)
+/// and 15 (2 Hz). The handler runs in interrupt context, so must not block.
+pub fn start_periodic(
+    rate: u8,
+    handler: impl Fn() + Send + Sync + 'static,
+) -> Result<(), IrqError> {
+    assert!(
+        (3..=15).contains(&rate),
+        "RTC periodic rate must be between 3 and 15"
+    );
+    without_interrupts(|| *PERIODIC_HANDLER.write() = Some(Box::new(handler)));
+    register_irq_handler()?;
+
+    without_interrupts(|| {
+        let mut cmos = CMOS.lock();
+        let status_a = read_register(STATUS_A);
+        write_register(STATUS_A, (status_a & !RATE_MASK) | rate);
+        update_status_b(&mut cmos, PERIODIC_INTERRUPT_ENABLE, true);
+    });
+    Ok(())
+}
+
+/// Stops the RTC's periodic interrupt
+pub fn stop_periodic() {
+    without_interrupts(|| {
+        update_status_b(&mut CMOS.lock(), PERIODIC_INTERRUPT_ENABLE, false);
+        *PERIODIC_HANDLER.write() = None;
+    });
+}
+
+/// Sets the RTC's alarm to go off at the given UTC time of day, calling `handler` when it does.
+///
+/// The handler runs in interrupt context, so must not block.
+pub fn set_alarm(
+    hour: u8,
+    minute: u8,
+    second: u8,
+    handler: impl Fn() + Send + Sync + 'static,
+) -> Result<(), IrqError> {
+    assert!(
+        hour < 24 && minute < 60 && second < 60,
+        "invalid alarm time"
+    );
+    without_interrupts(|| *ALARM_HANDLER.write() = Some(Box::new(handler)));
+    register_irq_handler()?;
+
+    without_interrupts(|| {
+        let mut cmos = CMOS.lock();
+        let status_b = read_register(STATUS_B);
+
+        // The alarm registers use the same encoding as the time registers
+        let encode = |value: u8| {
+            if status_b & BINARY_MODE == 0 {
+                to_bcd(value)
+            } else {
+                value
+            }
+        };
+        let alarm_hour = if status_b & HOUR_24_MODE == 0 {
+            let hour_12 = if hour.is_multiple_of(12) {
+                12
+            } else {
+                hour % 12
+            };
+            encode(hour_12) | if hour >= 12 { HOUR_PM } else { 0 }
+        } else {
+            encode(hour)
+        };
+
+        write_register(SECONDS_ALARM, encode(second));
+        write_register(MINUTES_ALARM, encode(minute));
+        write_register(HOURS_ALARM, alarm_hour);
+
+        update_status_b(&mut cmos, ALARM_INTERRUPT_ENABLE, true);
+    });
+    Ok(())
+}
+
+/// Cancels the RTC's alarm
+pub fn cancel_alarm() {
+    without_interrupts(|| {
+        update_status_b(&mut CMOS.lock(), ALARM_INTERRUPT_ENABLE, false);
+        *ALARM_HANDLER.write() = None;
+    });
+}
+
+/// Tests conversion of dates to and from Unix timestamps, including a leap day
+#[test_case]
+fn test_unix_timestamp_round_trip() {
+    let date_time = DateTime {
+        year: 2024,
+        month: 2,
+        day: 29,
+        hour: 13,
+        minute: 45,
+        second: 30,
+    };
+    assert_eq!(date_time.to_unix_timestamp(), 1_709_214_330);
+    assert_eq!(DateTime::from_unix_timestamp(1_709_214_330), date_time);
+    assert_eq!(DateTime::from_unix_timestamp(0).year, 1970);
+}
+
+/// Tests conversion of values to and from binary coded decimal
+#[test_case]
+fn test_bcd() {
+    assert_eq!(from_bcd(0x59), 59);
+    assert_eq!(to_bcd(59), 0x59);
+}
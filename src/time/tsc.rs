@@ -0,0 +1,82 @@
+//! This module provides a clock source based on the CPU's time stamp counter (TSC).
+//!
+//! The TSC counts CPU clock cycles (or a constant rate on modern CPUs), and its frequency
+//! is not reported by the CPU, so it is measured against a reference clock of known rate.
+
+use super::{Clock, NANOS_PER_SEC};
+use core::arch::x86_64::{__cpuid, _rdtsc};
+
+/// Length of the interval used to measure the TSC's frequency (10 ms)
+pub const CALIBRATION_NANOS: u64 = 10_000_000;
+
+/// CPUID leaf 1 reports the TSC in bit 4 of edx
+const CPUID_FEATURES: u32 = 0x1;
+const CPUID_TSC: u32 = 1 << 4;
+
+/// CPUID leaf 0x80000007 reports an invariant TSC in bit 8 of edx
+const CPUID_ADVANCED_POWER_MANAGEMENT: u32 = 0x8000_0007;
+const CPUID_INVARIANT_TSC: u32 = 1 << 8;
+
+/// A calibrated TSC
+pub struct Tsc {
+    /// TSC value when it was calibrated, which is when its timestamps start
+    start: u64,
+
+    /// Number of TSC ticks per second
+    frequency: u64,
+
+    /// Whether the TSC ticks at a constant rate regardless of the CPU's power state
+    invariant: bool,
+}
+
+impl Tsc {
+    /// Measures the TSC's frequency by counting its ticks while `wait` busy waits
+    /// for CALIBRATION_NANOS. Returns None if the CPU has no TSC.
+    pub fn calibrate(wait: impl FnOnce()) -> Option<Tsc> {
+        let features = __cpuid(CPUID_FEATURES);
+        if features.edx & CPUID_TSC == 0 {
+            return None;
+        }
+
+        let max_extended_leaf = __cpuid(0x8000_0000).eax;
+        let invariant = max_extended_leaf >= CPUID_ADVANCED_POWER_MANAGEMENT
+            && __cpuid(CPUID_ADVANCED_POWER_MANAGEMENT).edx & CPUID_INVARIANT_TSC != 0;
+
+        // Interrupts are disabled so the interval is not lengthened by interrupt handlers
+        let (start, end) = x86_64::instructions::interrupts::without_interrupts(|| {
+            let start = read_tsc();
+            wait();
+            (start, read_tsc())
+        });
+
+        let frequency = (u128::from(end - start) * u128::from(NANOS_PER_SEC)
+            / u128::from(CALIBRATION_NANOS)) as u64;
+        (frequency > 0).then_some(Tsc {
+            start,
+            frequency,
+            invariant,
+        })
+    }
+
+    /// Returns the TSC's measured frequency in Hz
+    pub fn frequency(&self) -> u64 {
+        self.frequency
+    }
+
+    /// Returns whether the TSC ticks at a constant rate regardless of the CPU's power state
+    pub fn is_invariant(&self) -> bool {
+        self.invariant
+    }
+}
+
+/// Reads the time stamp counter
+pub fn read_tsc() -> u64 {
+    unsafe { _rdtsc() }
+}
+
+impl Clock for Tsc {
+    fn now(&self) -> u64 {
+        let ticks = read_tsc().saturating_sub(self.start);
+        (u128::from(ticks) * u128::from(NANOS_PER_SEC) / u128::from(self.frequency)) as u64
+    }
+}
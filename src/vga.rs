@@ -4,9 +4,9 @@
 //! to this module, therefore callers of this module do not have
 //! to use unsafe blocks.
 
+use crate::sync::IrqSafeMutex;
 use core::fmt;
 use lazy_static::lazy_static;
-use spin::Mutex;
 use volatile::Volatile;
 
 // Dimensions of the VGA text buffer
@@ -173,9 +173,10 @@ impl fmt::Write for Writer {
 // converted to a reference at compile time.
 lazy_static! {
 
-    // This writer is protected by a spinlock mutex (the
-    // std::sync::Mutex is unavailable)
-    pub static ref WRITER: Mutex<Writer> = Mutex::new(Writer {
+    // This writer is protected by a spinlock mutex (the std::sync::Mutex is unavailable)
+    // which disables interrupts while held, so an interrupt handler which prints cannot
+    // deadlock on it
+    pub static ref WRITER: IrqSafeMutex<Writer> = IrqSafeMutex::new(Writer {
         column_position: 0,
         colour_code: ColourCode::new(Colour::Yellow, Colour::Black),
         buffer: unsafe { &mut *(VGA_BUF_ADDR as *mut Buffer) },
@@ -198,13 +199,8 @@ macro_rules! println {
 #[doc(hidden)]
 pub fn _print(args: fmt::Arguments) {
     use core::fmt::Write;
-    use x86_64::instructions::interrupts;
 
-    // Disable interrupts to avoid the interrupt handler, and _print
-    // function deadlocking on the writer lock
-    interrupts::without_interrupts(|| {
-        WRITER.lock().write_fmt(args).unwrap();
-    });
+    WRITER.lock().write_fmt(args).unwrap();
 }
 
 /// Tests the printing of a single line does not panic
@@ -225,19 +221,16 @@ fn test_println_many() {
 #[test_case]
 fn test_println_output() {
     use core::fmt::Write;
-    use x86_64::instructions::interrupts;
 
     let s = "Some test string that fits on a single line";
 
-    // Disable interrupts to avoid deadlock on the writer lock
-    interrupts::without_interrupts(|| {
-        let mut writer = WRITER.lock();
+    // Holding the writer lock keeps interrupts disabled, so no timer interrupt dots are printed
+    let mut writer = WRITER.lock();
 
-        // Start by writing newline to ensure that no timer interrupt dots are already on the line
-        writeln!(writer, "\n{}", s).expect("Failed to write line to VGA buffer\n");
-        for (i, c) in s.chars().enumerate() {
-            let screen_char = writer.buffer.chars[BUFFER_HEIGHT - 2][i].read();
-            assert_eq!(char::from(screen_char.ascii_character), c);
-        }
-    });
+    // Start by writing newline to ensure that no timer interrupt dots are already on the line
+    writeln!(writer, "\n{}", s).expect("Failed to write line to VGA buffer\n");
+    for (i, c) in s.chars().enumerate() {
+        let screen_char = writer.buffer.chars[BUFFER_HEIGHT - 2][i].read();
+        assert_eq!(char::from(screen_char.ascii_character), c);
+    }
 }
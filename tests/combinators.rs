@@ -0,0 +1,192 @@
+//! This integration test checks the join!, select!, join_all and FutureSet combinators,
+//! and the sleeps and timeouts woken by the timer interrupt, on the Executor.
+
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+mod common;
+
+use alloc::{boxed::Box, rc::Rc, vec, vec::Vec};
+use bootloader::{BootInfo, entry_point};
+use core::{
+    cell::Cell,
+    future::{Future, pending, ready},
+    panic::PanicInfo,
+    pin::Pin,
+    task::{Context, Poll},
+};
+use futures_util::StreamExt;
+use rust_os::{
+    join, select,
+    task::{
+        combinator::{FutureSet, join_all},
+        executor::Executor,
+        sync::oneshot,
+        timer::{Elapsed, sleep, timeout},
+    },
+    time,
+};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use rust_os::allocator;
+    use rust_os::memory::{self, BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    rust_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    rust_os::acpi::init();
+    time::init();
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
+
+/// Milliseconds in nanoseconds
+const MILLIS: u64 = 1_000_000;
+
+/// Runs the Future as a Task on a new Executor until it completes
+fn block_on<T: 'static>(future: impl Future<Output = T> + 'static) -> T {
+    common::block_on(&mut Executor::new(), future)
+}
+
+/// Future which counts how many times it is polled, and never completes
+struct CountPolls(Rc<Cell<usize>>);
+
+impl Future for CountPolls {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<()> {
+        self.0.set(self.0.get() + 1);
+        Poll::Pending
+    }
+}
+
+#[test_case]
+fn sleep_waits_for_deadline() {
+    let start = time::now();
+    block_on(sleep(100 * MILLIS));
+    assert!(time::now() - start >= 100 * MILLIS);
+}
+
+#[test_case]
+fn join_waits_for_all() {
+    let start = time::now();
+    let (a, b, c) = block_on(async {
+        join!(
+            async {
+                sleep(100 * MILLIS).await;
+                1
+            },
+            async {
+                sleep(100 * MILLIS).await;
+                "two"
+            },
+            ready(3.0),
+        )
+    });
+    assert_eq!((a, b, c), (1, "two", 3.0));
+
+    // The sleeps ran concurrently, rather than one after the other
+    let elapsed = time::now() - start;
+    assert!((100 * MILLIS..200 * MILLIS).contains(&elapsed));
+}
+
+#[test_case]
+fn select_runs_first_ready_branch() {
+    let chosen = block_on(async {
+        let (sender, receiver) = oneshot::channel();
+        let mut waited = 0;
+        let chosen = select! {
+            () = pending::<()>() => "pending",
+            value = receiver => {
+                assert_eq!(value, Ok(5));
+                "received"
+            },
+            () = async {
+                waited += 1;
+                sender.send(5).unwrap();
+                sleep(1000 * MILLIS).await
+            } => "slept",
+        };
+
+        // The other branches were dropped before the handler ran, so waited can be used again
+        assert_eq!(waited, 1);
+        chosen
+    });
+    assert_eq!(chosen, "received");
+}
+
+#[test_case]
+fn join_all_keeps_order() {
+    let outputs = block_on(join_all((1..=4).map(|i| async move {
+        sleep((5 - i) * 20 * MILLIS).await;
+        i
+    })));
+    assert_eq!(outputs, [1, 2, 3, 4]);
+}
+
+#[test_case]
+fn future_set_yields_in_completion_order() {
+    let outputs = block_on(async {
+        let mut set = FutureSet::new();
+        for i in [3, 1, 2] {
+            set.push(async move {
+                sleep(i * 30 * MILLIS).await;
+                i
+            });
+        }
+        assert_eq!(set.len(), 3);
+        set.collect::<Vec<_>>().await
+    });
+    assert_eq!(outputs, [1, 2, 3]);
+}
+
+#[test_case]
+fn future_set_polls_only_woken_futures() {
+    let polls = Rc::new(Cell::new(0));
+    let counted = polls.clone();
+    block_on(async move {
+        let mut set: FutureSet<Pin<Box<dyn Future<Output = ()>>>> = FutureSet::new();
+        set.push(Box::pin(CountPolls(counted)));
+        for _ in 0..3 {
+            set.push(Box::pin(sleep(20 * MILLIS)));
+        }
+        for _ in 0..3 {
+            set.next().await;
+        }
+        assert_eq!(set.len(), 1);
+    });
+
+    // The counting Future never wakes itself, so it was only polled when pushed
+    assert_eq!(polls.get(), 1);
+}
+
+#[test_case]
+fn timeout_elapses() {
+    let result = block_on(timeout(50 * MILLIS, pending::<()>()));
+    assert_eq!(result, Err(Elapsed));
+}
+
+#[test_case]
+fn timeout_passes_output() {
+    let result = block_on(timeout(1000 * MILLIS, async {
+        sleep(20 * MILLIS).await;
+        vec![1, 2]
+    }));
+    assert_eq!(result, Ok(vec![1, 2]));
+}
@@ -0,0 +1,88 @@
+//! Helpers shared by the integration tests. Each test includes this module with `mod common`,
+//! and only uses some of the helpers.
+
+#![allow(dead_code)]
+
+use alloc::{rc::Rc, sync::Arc};
+use core::{
+    cell::Cell,
+    future::Future,
+    pin::Pin,
+    sync::atomic::{AtomicBool, Ordering},
+    task::{Context, Poll},
+};
+use rust_os::task::executor::Executor;
+
+/// Returns a Future which wakes its Task and returns Poll::Pending once, so the executor
+/// runs any other ready Tasks before the caller continues
+pub fn yield_now() -> YieldNow {
+    YieldNow { yielded: false }
+}
+
+/// Future returned by yield_now
+pub struct YieldNow {
+    yielded: bool,
+}
+
+impl Future for YieldNow {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        if self.yielded {
+            Poll::Ready(())
+        } else {
+            self.yielded = true;
+            cx.waker().wake_by_ref();
+            Poll::Pending
+        }
+    }
+}
+
+/// Flag which is set once a guard created by it is dropped, for checking that cancelling a
+/// Task drops its state
+#[derive(Debug, Clone, Default)]
+pub struct DropFlag(Arc<AtomicBool>);
+
+impl DropFlag {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns a guard which sets the flag when dropped, to move into a Future
+    pub fn guard(&self) -> DropGuard {
+        DropGuard(self.0.clone())
+    }
+
+    /// Returns whether a guard has been dropped
+    pub fn is_set(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+/// Guard returned by DropFlag::guard
+#[derive(Debug)]
+pub struct DropGuard(Arc<AtomicBool>);
+
+impl Drop for DropGuard {
+    fn drop(&mut self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+}
+
+/// Runs the Future as a Task on `executor`, halting until the next interrupt whenever no Task
+/// is ready, until it completes. Tasks spawned through the executor's Spawners run meanwhile.
+pub fn block_on<T: 'static>(
+    executor: &mut Executor,
+    future: impl Future<Output = T> + 'static,
+) -> T {
+    let output = Rc::new(Cell::new(None));
+    let result = output.clone();
+    executor.spawn(async move { result.set(Some(future.await)) });
+    loop {
+        executor.run_until_idle();
+        if let Some(output) = output.take() {
+            return output;
+        }
+        x86_64::instructions::hlt();
+    }
+}
@@ -0,0 +1,172 @@
+//! This integration test checks the Executor runs any number of ready Tasks, and queues
+//! a Task only once however many times it is woken.
+
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::rc::Rc;
+use bootloader::{BootInfo, entry_point};
+use core::{
+    cell::Cell,
+    future::Future,
+    panic::PanicInfo,
+    pin::Pin,
+    task::{Context, Poll},
+};
+use rust_os::{task::executor::Executor, time};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use rust_os::allocator;
+    use rust_os::memory::{self, BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    rust_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    rust_os::acpi::init();
+    rust_os::time::init();
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
+
+#[test_case]
+fn many_ready_tasks() {
+    let completed = Rc::new(Cell::new(0));
+    let mut executor = Executor::new();
+    for _ in 0..1000 {
+        let completed = completed.clone();
+        executor.spawn(async move { completed.set(completed.get() + 1) });
+    }
+    executor.run_until_idle();
+    assert_eq!(completed.get(), 1000);
+}
+
+/// Future which wakes itself many times on its first poll, then never completes,
+/// counting how often it is polled
+struct WakeFlood {
+    polls: Rc<Cell<usize>>,
+}
+
+impl Future for WakeFlood {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        self.polls.set(self.polls.get() + 1);
+        if self.polls.get() == 1 {
+            for _ in 0..1000 {
+                cx.waker().wake_by_ref();
+            }
+        }
+        Poll::Pending
+    }
+}
+
+#[test_case]
+fn repeated_wakes_queue_task_once() {
+    let polls = Rc::new(Cell::new(0));
+    let mut executor = Executor::new();
+    executor.spawn(WakeFlood {
+        polls: polls.clone(),
+    });
+    executor.run_until_idle();
+
+    // Polled once when spawned, and once more for all of the wakes
+    assert_eq!(polls.get(), 2);
+}
+
+/// Future which busy-waits for `nanos` nanoseconds in each poll, completing after `polls` polls
+struct SlowPolls {
+    nanos: u64,
+    polls: usize,
+}
+
+impl Future for SlowPolls {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        let start = time::now();
+        while time::now() - start < self.nanos {
+            core::hint::spin_loop();
+        }
+        self.polls -= 1;
+        if self.polls == 0 {
+            Poll::Ready(())
+        } else {
+            cx.waker().wake_by_ref();
+            cx.waker().wake_by_ref();
+            Poll::Pending
+        }
+    }
+}
+
+#[test_case]
+fn tasks_lists_statistics() {
+    let mut executor = Executor::new();
+    let slow = executor.spawn_named(
+        "slow",
+        SlowPolls {
+            nanos: 1_000_000,
+            polls: 3,
+        },
+    );
+    let waiting = executor.spawn(WakeFlood {
+        polls: Rc::new(Cell::new(0)),
+    });
+    let tasks = executor.tasks();
+    assert_eq!(tasks.len(), 2);
+    assert_eq!(tasks[0].id, slow.id());
+    assert_eq!(tasks[0].name.as_deref(), Some("slow"));
+    assert!(tasks[0].ready);
+    assert_eq!(tasks[0].polls, 0);
+    assert_eq!(tasks[1].name, None);
+
+    // The slow Task also goes over the poll budget, which only prints a warning
+    executor.set_poll_budget(Some(500_000));
+    for _ in 0..2 {
+        executor.run_until_idle();
+    }
+    let tasks = executor.spawner().tasks();
+
+    // The slow Task has completed, so only the waiting Task is listed
+    assert_eq!(tasks.len(), 1);
+    assert_eq!(tasks[0].id, waiting.id());
+    assert!(!tasks[0].ready);
+    assert_eq!(tasks[0].polls, 2);
+    assert_eq!(tasks[0].wakes, 1000);
+}
+
+#[test_case]
+fn poll_time_recorded() {
+    let mut executor = Executor::new();
+    let spawner = executor.spawner();
+    executor.spawn(async move {
+        SlowPolls {
+            nanos: 1_000_000,
+            polls: 2,
+        }
+        .await;
+        let tasks = spawner.tasks();
+        assert_eq!(tasks.len(), 1);
+        assert_eq!(tasks[0].polls, 1);
+        assert_eq!(tasks[0].wakes, 2);
+        assert!(tasks[0].longest_poll >= 1_000_000);
+        assert!(tasks[0].poll_time >= tasks[0].longest_poll);
+    });
+    executor.run_until_idle();
+    assert!(executor.tasks().is_empty());
+}
@@ -0,0 +1,79 @@
+//! This integration test initialises the heap, then tests that an IrqChannel delivers pushed
+//! events through its Stream, and counts the events it has to drop
+
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{BootInfo, entry_point};
+use core::panic::PanicInfo;
+use core::pin::Pin;
+use core::task::{Context, Poll};
+use futures_util::{Stream, task::noop_waker_ref};
+use rust_os::hlt_loop;
+use rust_os::task::irq_channel::{IrqChannel, PushError};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use rust_os::allocator;
+    use rust_os::memory::{self, BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    rust_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+
+    test_main();
+    hlt_loop()
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
+
+/// Polls a Stream once with a Waker which does nothing
+fn poll_once<S: Stream + Unpin>(stream: &mut S) -> Poll<Option<S::Item>> {
+    let mut context = Context::from_waker(noop_waker_ref());
+    Pin::new(stream).poll_next(&mut context)
+}
+
+#[test_case]
+fn push_before_stream_is_dropped() {
+    static CHANNEL: IrqChannel<u8> = IrqChannel::new(4);
+
+    assert_eq!(CHANNEL.push(1), Err(PushError::Uninitialized(1)));
+    assert_eq!(CHANNEL.dropped(), 1);
+}
+
+#[test_case]
+fn events_are_received_in_order() {
+    static CHANNEL: IrqChannel<u32> = IrqChannel::new(4);
+
+    let mut stream = CHANNEL.stream();
+    assert_eq!(poll_once(&mut stream), Poll::Pending);
+    for i in 0..3 {
+        CHANNEL.push(i).expect("push failed");
+    }
+    for i in 0..3 {
+        assert_eq!(poll_once(&mut stream), Poll::Ready(Some(i)));
+    }
+    assert_eq!(poll_once(&mut stream), Poll::Pending);
+}
+
+#[test_case]
+fn overflow_is_counted() {
+    static CHANNEL: IrqChannel<u8> = IrqChannel::new(2);
+
+    let mut stream = CHANNEL.stream();
+    CHANNEL.push(1).expect("push failed");
+    CHANNEL.push(2).expect("push failed");
+    assert_eq!(CHANNEL.push(3), Err(PushError::Full(3)));
+    assert_eq!(CHANNEL.dropped(), 1);
+    assert_eq!(poll_once(&mut stream), Poll::Ready(Some(1)));
+}
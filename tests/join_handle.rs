@@ -0,0 +1,138 @@
+//! This integration test runs Tasks on the SimpleExecutor and checks their outputs
+//! can be awaited, and that they can be cancelled, through their JoinHandles.
+
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+mod common;
+
+use alloc::{rc::Rc, string::String};
+use bootloader::{BootInfo, entry_point};
+use common::{DropFlag, yield_now};
+use core::{
+    cell::{Cell, RefCell},
+    future::pending,
+    panic::PanicInfo,
+};
+use rust_os::task::{JoinError, Task, simple_executor::SimpleExecutor};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use rust_os::allocator;
+    use rust_os::memory::{self, BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    rust_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
+
+#[test_case]
+fn output_awaited_by_another_task() {
+    let (task, handle) = Task::joinable(async {
+        yield_now().await;
+        String::from("done")
+    });
+    let output = Rc::new(RefCell::new(None));
+
+    let awaited = output.clone();
+    let mut executor = SimpleExecutor::new();
+    executor.spawn(task);
+    executor.spawn(Task::new(async move {
+        *awaited.borrow_mut() = Some(handle.await);
+    }));
+    executor.run();
+
+    assert_eq!(*output.borrow(), Some(Ok(String::from("done"))));
+}
+
+#[test_case]
+fn abort_drops_future() {
+    let dropped = DropFlag::new();
+    let guard = dropped.guard();
+    let (task, handle) = Task::joinable(async move {
+        let _guard = guard;
+        pending::<u32>().await
+    });
+    let output = Rc::new(RefCell::new(None));
+
+    let awaited = output.clone();
+    let mut executor = SimpleExecutor::new();
+    executor.spawn(task);
+    executor.spawn(Task::new(async move {
+        yield_now().await;
+        assert!(!handle.is_finished());
+        handle.abort();
+        *awaited.borrow_mut() = Some(handle.await);
+    }));
+    executor.run();
+
+    assert!(dropped.is_set());
+    assert_eq!(*output.borrow(), Some(Err(JoinError::Cancelled)));
+}
+
+#[test_case]
+fn dropped_handle_detaches() {
+    let completed = Rc::new(Cell::new(false));
+    let set = completed.clone();
+    let (task, handle) = Task::joinable(async move {
+        yield_now().await;
+        set.set(true);
+    });
+    drop(handle);
+
+    let mut executor = SimpleExecutor::new();
+    executor.spawn(task);
+    executor.run();
+
+    assert!(completed.get());
+}
+
+#[test_case]
+fn abort_on_drop_cancels() {
+    let dropped = DropFlag::new();
+    let guard = dropped.guard();
+    let (task, handle) = Task::joinable(async move {
+        let _guard = guard;
+        pending::<()>().await
+    });
+    drop(handle.abort_on_drop());
+
+    let mut executor = SimpleExecutor::new();
+    executor.spawn(task);
+    executor.run();
+
+    assert!(dropped.is_set());
+}
+
+#[test_case]
+fn abort_after_completion_keeps_output() {
+    let (task, handle) = Task::joinable(async { 7 });
+    let mut executor = SimpleExecutor::new();
+    executor.spawn(task);
+    executor.run();
+
+    assert!(handle.is_finished());
+    handle.abort();
+    let output = Rc::new(Cell::new(None));
+    let awaited = output.clone();
+    executor.spawn(Task::new(async move { awaited.set(Some(handle.await)) }));
+    executor.run();
+    assert_eq!(output.get(), Some(Ok(7)));
+}
@@ -0,0 +1,89 @@
+//! This integration test checks that the keyboard service decodes injected scancodes into
+//! KeyEvents for its subscribers, and follows layout changes made while it runs.
+
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+use bootloader::{BootInfo, entry_point};
+use core::panic::PanicInfo;
+use rust_os::task::{
+    keyboard::{self, KeyCode, KeyEvent, KeyState, Layout, run_keyboard_service},
+    sync::mpsc::Receiver,
+    test_executor::TestExecutor,
+};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use rust_os::allocator;
+    use rust_os::memory::{self, BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    rust_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
+
+/// Returns the KeyEvents a subscriber has received so far
+fn received(events: &mut Receiver<KeyEvent>) -> Vec<KeyEvent> {
+    core::iter::from_fn(|| events.try_recv().ok()).collect()
+}
+
+/// There is only one ScancodeStream, so the keyboard service can only be started once,
+/// and everything is checked in one test
+#[test_case]
+fn keyboard_service() {
+    let mut executor = TestExecutor::new();
+    executor.spawn(run_keyboard_service());
+    executor.run_until_stalled();
+
+    let mut first = keyboard::subscribe();
+    let mut second = keyboard::subscribe();
+
+    // Shift-A, then release both, reaches every subscriber
+    for scancode in [0x2A, 0x1E, 0x9E, 0xAA] {
+        executor.inject_scancode(scancode);
+    }
+    let events = received(&mut first);
+    assert_eq!(events, received(&mut second));
+    assert_eq!(events.len(), 4);
+    assert_eq!(events[1].code, KeyCode::A);
+    assert_eq!(events[1].state, KeyState::Down);
+    assert_eq!(events[1].char, Some('A'));
+    assert!(events[1].modifiers.shift);
+    assert_eq!(events[3].code, KeyCode::LShift);
+    assert!(!events[3].modifiers.shift);
+
+    // Ctrl-C is delivered as a control character
+    drop(second);
+    for scancode in [0x1D, 0x2E, 0xAE, 0x9D] {
+        executor.inject_scancode(scancode);
+    }
+    let events = received(&mut first);
+    assert_eq!(events[1].char, Some('\u{3}'));
+    assert!(events[1].modifiers.ctrl);
+
+    // Changing the layout applies to the next key
+    keyboard::set_layout(Layout::Azerty);
+    executor.inject_scancode(0x10);
+    assert_eq!(received(&mut first)[0].char, Some('a'));
+    keyboard::set_layout(Layout::Us104);
+    executor.inject_scancode(0x10);
+    assert_eq!(received(&mut first)[0].char, Some('q'));
+}
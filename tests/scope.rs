@@ -0,0 +1,131 @@
+//! This integration test checks a scope runs its children as Tasks of their own, waits for
+//! all of them, and cancels the others when one fails.
+
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+mod common;
+
+use alloc::sync::Arc;
+use bootloader::{BootInfo, entry_point};
+use common::{DropFlag, block_on, yield_now};
+use core::{
+    future::pending,
+    panic::PanicInfo,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+use rust_os::{
+    per_cpu,
+    task::{executor::Executor, scope, sync::mpsc},
+};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use rust_os::allocator;
+    use rust_os::memory::{self, BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    rust_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
+
+#[test_case]
+fn waits_for_every_child() {
+    let completed = Arc::new(AtomicUsize::new(0));
+    let total = completed.clone();
+    let mut executor = Executor::new();
+    let spawner = executor.spawner();
+    let result = block_on(&mut executor, async move {
+        let (sender, mut receiver) = mpsc::unbounded();
+        scope(&spawner, |s| {
+            s.spawn(async move {
+                while let Some(value) = receiver.recv().await {
+                    total.fetch_add(value, Ordering::SeqCst);
+                }
+                Ok::<(), ()>(())
+            });
+            s.spawn(async move {
+                for value in 1..=3 {
+                    sender.send(value).unwrap();
+                    yield_now().await;
+                }
+                Ok(())
+            });
+        })
+        .await
+    });
+
+    assert_eq!(result, Ok(()));
+    assert_eq!(completed.load(Ordering::SeqCst), 6);
+}
+
+#[test_case]
+fn children_are_separate_tasks() {
+    let mut executor = Executor::new();
+    let spawner = executor.spawner();
+    let result = block_on(&mut executor, async move {
+        let parent = per_cpu!(current_task());
+        scope(&spawner, |s| {
+            s.spawn(async move {
+                if per_cpu!(current_task()) == parent {
+                    Err("child polled as part of its parent")
+                } else {
+                    Ok(())
+                }
+            });
+        })
+        .await
+    });
+
+    assert_eq!(result, Ok(()));
+}
+
+#[test_case]
+fn failure_cancels_other_children() {
+    let cancelled = DropFlag::new();
+    let guard = cancelled.guard();
+    let mut executor = Executor::new();
+    let spawner = executor.spawner();
+    let result = block_on(&mut executor, async move {
+        scope(&spawner, |s| {
+            s.spawn(async move {
+                let _guard = guard;
+                pending::<Result<(), &str>>().await
+            });
+            s.spawn(async {
+                yield_now().await;
+                Err("failed")
+            });
+        })
+        .await
+    });
+
+    // The aborted child is dropped by the Executor, which has run until no Task is ready
+    assert_eq!(result, Err("failed"));
+    assert!(cancelled.is_set());
+}
+
+#[test_case]
+fn empty_scope_completes() {
+    let mut executor = Executor::new();
+    let spawner = executor.spawner();
+    let result = block_on(&mut executor, scope::<_, ()>(&spawner, |_| {}));
+    assert_eq!(result, Ok(()));
+}
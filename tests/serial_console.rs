@@ -0,0 +1,107 @@
+//! This integration test checks the line editing and commands of the Console, by driving
+//! it with bytes and collecting its output in a String.
+
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::string::String;
+use bootloader::{BootInfo, entry_point};
+use core::panic::PanicInfo;
+use rust_os::task::{
+    console::{Console, PROMPT},
+    executor::Executor,
+};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use rust_os::allocator;
+    use rust_os::memory::{self, BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    rust_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
+
+/// Feeds `input` to a Console one byte at a time, returning everything it wrote
+fn run(mut console: Console<String>, input: &str) -> String {
+    for byte in input.bytes() {
+        console.input(byte).unwrap();
+    }
+    console.output().clone()
+}
+
+#[test_case]
+fn echo_command_runs_on_enter() {
+    let output = run(Console::new(String::new()), "echo hello world\r");
+    assert_eq!(output, "echo hello world\nhello world\n> ");
+}
+
+#[test_case]
+fn crlf_ends_one_line() {
+    let output = run(Console::new(String::new()), "echo a\r\necho b\n");
+    assert_eq!(output, "echo a\na\n> echo b\nb\n> ");
+}
+
+#[test_case]
+fn backspace_edits_line() {
+    let output = run(Console::new(String::new()), "echo abx\x7fc\r");
+    assert!(output.ends_with("\nabc\n> "));
+}
+
+#[test_case]
+fn ctrl_c_discards_line() {
+    let output = run(Console::new(String::new()), "echo lost\x03echo kept\r");
+    assert!(output.contains("^C\n> "));
+    assert!(output.ends_with("\nkept\n> "));
+    assert!(!output.contains("\nlost\n"));
+}
+
+#[test_case]
+fn unknown_command_is_reported() {
+    let output = run(Console::new(String::new()), "frobnicate now\r");
+    assert!(output.contains("unknown command: frobnicate"));
+}
+
+#[test_case]
+fn help_lists_commands() {
+    let mut console = Console::new(String::new());
+    console.execute("help").unwrap();
+    for name in [
+        "help", "echo", "uptime", "cpus", "layout", "serial", "tasks", "exit",
+    ] {
+        assert!(console.output().lines().any(|line| line.starts_with(name)));
+    }
+}
+
+#[test_case]
+fn tasks_lists_named_tasks() {
+    let mut executor = Executor::new();
+    executor.spawn_named("worker", core::future::pending::<()>());
+    let mut console = Console::new(String::new()).with_spawner(executor.spawner());
+    console.execute("tasks").unwrap();
+    assert!(console.output().contains("worker"));
+}
+
+#[test_case]
+fn prompt_is_printed() {
+    let mut console = Console::new(String::new());
+    console.prompt().unwrap();
+    assert_eq!(console.output(), PROMPT);
+}
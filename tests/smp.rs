@@ -0,0 +1,50 @@
+//! This integration test starts the application processors, then tests that every CPU
+//! listed in the ACPI MADT came online. QEMU is started with 4 CPUs for tests.
+
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{BootInfo, entry_point};
+use core::panic::PanicInfo;
+use rust_os::{hlt_loop, smp};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use rust_os::memory::{self, BootInfoFrameAllocator};
+    use rust_os::{acpi, allocator, time};
+    use x86_64::VirtAddr;
+
+    rust_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    acpi::init();
+    time::init();
+    smp::init(&mut mapper, &mut frame_allocator, &boot_info.memory_map)
+        .expect("SMP initialization failed");
+
+    test_main();
+    hlt_loop()
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
+
+#[test_case]
+fn multiple_cpus_found() {
+    assert!(smp::cpu_count() > 1);
+}
+
+#[test_case]
+fn all_cpus_online() {
+    assert_eq!(smp::online_cpus(), smp::cpu_count());
+}
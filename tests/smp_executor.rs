@@ -0,0 +1,137 @@
+//! This integration test starts the application processors, which run the SMP executor,
+//! then tests that Tasks spawned from the bootstrap processor are run and woken on them.
+
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{BootInfo, entry_point};
+use core::{
+    future::Future,
+    panic::PanicInfo,
+    pin::Pin,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+    task::{Context, Poll},
+};
+use futures_util::task::AtomicWaker;
+use rust_os::{hlt_loop, per_cpu, percpu::RUN_QUEUE_CAPACITY, smp, task::smp_executor, time};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use rust_os::memory::{self, BootInfoFrameAllocator};
+    use rust_os::{acpi, allocator};
+    use x86_64::VirtAddr;
+
+    rust_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    acpi::init();
+    time::init();
+    smp::init(&mut mapper, &mut frame_allocator, &boot_info.memory_map)
+        .expect("SMP initialization failed");
+
+    test_main();
+    hlt_loop()
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
+
+/// How long to wait for the APs to run Tasks before failing
+const TIMEOUT_NANOS: u64 = 1_000_000_000;
+
+/// Spins until `done` returns true, panicking if that takes longer than TIMEOUT_NANOS
+fn wait_until(done: impl Fn() -> bool) {
+    let start = time::now();
+    while !done() {
+        assert!(time::now() - start < TIMEOUT_NANOS, "timed out");
+        core::hint::spin_loop();
+    }
+}
+
+#[test_case]
+fn spawned_tasks_run_on_aps() {
+    static COMPLETED: AtomicUsize = AtomicUsize::new(0);
+    static ON_BSP: AtomicUsize = AtomicUsize::new(0);
+
+    for _ in 0..50 {
+        smp_executor::spawn(async {
+            if per_cpu!(cpu_id) == 0 {
+                ON_BSP.fetch_add(1, Ordering::SeqCst);
+            }
+            COMPLETED.fetch_add(1, Ordering::SeqCst);
+        });
+    }
+
+    wait_until(|| COMPLETED.load(Ordering::SeqCst) == 50);
+    assert_eq!(ON_BSP.load(Ordering::SeqCst), 0);
+}
+
+/// Tests that Tasks which do not fit on any CPU's run queue are still run. The first Tasks
+/// keep the APs busy until every Task has been spawned, so the run queues cannot drain.
+#[test_case]
+fn more_tasks_than_run_queues_hold() {
+    static RELEASE: AtomicBool = AtomicBool::new(false);
+    static COMPLETED: AtomicUsize = AtomicUsize::new(0);
+
+    let count = (smp::cpu_count() + 1) * RUN_QUEUE_CAPACITY;
+    for _ in 0..count {
+        smp_executor::spawn(async {
+            while !RELEASE.load(Ordering::SeqCst) {
+                core::hint::spin_loop();
+            }
+            COMPLETED.fetch_add(1, Ordering::SeqCst);
+        });
+    }
+
+    RELEASE.store(true, Ordering::SeqCst);
+    wait_until(|| COMPLETED.load(Ordering::SeqCst) == count);
+}
+
+/// Future which completes once SIGNAL is set, waking its Task through WAKER
+struct Signal;
+
+static SIGNAL: AtomicBool = AtomicBool::new(false);
+static WAKER: AtomicWaker = AtomicWaker::new();
+
+impl Future for Signal {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        WAKER.register(cx.waker());
+        if SIGNAL.load(Ordering::SeqCst) {
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+#[test_case]
+fn task_woken_from_another_cpu() {
+    static POLLED: AtomicBool = AtomicBool::new(false);
+    static DONE: AtomicBool = AtomicBool::new(false);
+
+    smp_executor::spawn(async {
+        POLLED.store(true, Ordering::SeqCst);
+        Signal.await;
+        DONE.store(true, Ordering::SeqCst);
+    });
+
+    // Wait for an AP to poll the Task and go idle, then wake it from the BSP
+    wait_until(|| POLLED.load(Ordering::SeqCst));
+    assert!(!DONE.load(Ordering::SeqCst));
+    SIGNAL.store(true, Ordering::SeqCst);
+    WAKER.wake();
+
+    wait_until(|| DONE.load(Ordering::SeqCst));
+}
@@ -0,0 +1,106 @@
+//! This integration test checks that Tasks spawned through a Spawner, from running Tasks
+//! and from an interrupt handler, are run by the Executor.
+
+#![no_std]
+#![no_main]
+#![feature(abi_x86_interrupt)]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{BootInfo, entry_point};
+use conquer_once::spin::OnceCell;
+use core::{
+    panic::PanicInfo,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+use rust_os::task::executor::{Executor, Spawner};
+use x86_64::structures::idt::InterruptStackFrame;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use rust_os::allocator;
+    use rust_os::memory::{self, BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    rust_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
+
+#[test_case]
+fn tasks_spawn_sub_tasks() {
+    static COMPLETED: AtomicUsize = AtomicUsize::new(0);
+
+    let mut executor = Executor::new();
+    let spawner = executor.spawner();
+    executor.spawn(async move {
+        let handles = [1, 2, 3].map(|i| spawner.spawn(async move { i * 10 }));
+        let mut sum = 0;
+        for handle in handles {
+            sum += handle.await.unwrap();
+        }
+        assert_eq!(sum, 60);
+
+        // A sub-task can spawn further Tasks through its own clone of the Spawner
+        let nested = spawner.clone();
+        spawner.spawn(async move {
+            nested.spawn(async {
+                COMPLETED.fetch_add(1, Ordering::SeqCst);
+            });
+        });
+    });
+    executor.run_until_idle();
+
+    assert_eq!(COMPLETED.load(Ordering::SeqCst), 1);
+}
+
+/// Spawner used by the breakpoint handler below
+static SPAWNER: OnceCell<Spawner> = OnceCell::uninit();
+static SPAWNED_BY_INTERRUPT: AtomicUsize = AtomicUsize::new(0);
+
+extern "x86-interrupt" fn spawning_breakpoint_handler(_stack_frame: InterruptStackFrame) {
+    SPAWNER.get().unwrap().spawn(async {
+        SPAWNED_BY_INTERRUPT.fetch_add(1, Ordering::SeqCst);
+    });
+}
+
+#[test_case]
+fn interrupt_handler_spawns_task() {
+    use x86_64::{instructions::interrupts, structures::idt::InterruptDescriptorTable};
+
+    static IDT: OnceCell<InterruptDescriptorTable> = OnceCell::uninit();
+
+    let mut executor = Executor::new();
+    SPAWNER.init_once(|| executor.spawner());
+
+    // Load an IDT whose breakpoint handler spawns a Task, then restore the kernel's IDT.
+    // Hardware interrupts are disabled meanwhile, as the test IDT has no handlers for them.
+    let idt = IDT.get_or_init(|| {
+        let mut idt = InterruptDescriptorTable::new();
+        idt.breakpoint.set_handler_fn(spawning_breakpoint_handler);
+        idt
+    });
+    interrupts::without_interrupts(|| {
+        idt.load();
+        interrupts::int3();
+        rust_os::interrupts::init_idt();
+    });
+
+    assert_eq!(SPAWNED_BY_INTERRUPT.load(Ordering::SeqCst), 0);
+    executor.run_until_idle();
+    assert_eq!(SPAWNED_BY_INTERRUPT.load(Ordering::SeqCst), 1);
+}
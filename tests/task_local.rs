@@ -0,0 +1,91 @@
+//! This integration test checks each Task has its own values of task-locals, which the
+//! Executor switches between as it polls different Tasks.
+
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+mod common;
+
+use alloc::{rc::Rc, vec::Vec};
+use bootloader::{BootInfo, entry_point};
+use common::yield_now;
+use core::{
+    cell::{Cell, RefCell},
+    panic::PanicInfo,
+};
+use rust_os::{
+    task::{executor::Executor, task_local::AccessError},
+    task_local,
+};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use rust_os::allocator;
+    use rust_os::memory::{self, BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    rust_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
+
+task_local! {
+    static COUNTER: Cell<u32> = Cell::new(0);
+    static BASE: u32 = COUNTER.with(|counter| counter.get() + 100);
+}
+
+#[test_case]
+fn not_accessible_outside_tasks() {
+    assert_eq!(COUNTER.try_with(|counter| counter.get()), Err(AccessError));
+}
+
+#[test_case]
+fn each_task_has_its_own_value() {
+    let results = Rc::new(RefCell::new(Vec::new()));
+    let mut executor = Executor::new();
+    for increments in 1..=3 {
+        let results = results.clone();
+        executor.spawn(async move {
+            for _ in 0..increments {
+                COUNTER.with(|counter| counter.set(counter.get() + 1));
+                yield_now().await;
+            }
+            results
+                .borrow_mut()
+                .push(COUNTER.with(|counter| counter.get()));
+        });
+    }
+    executor.run_until_idle();
+
+    assert_eq!(*results.borrow(), [1, 2, 3]);
+}
+
+#[test_case]
+fn initializer_can_use_other_task_locals() {
+    let base = Rc::new(Cell::new(0));
+    let result = base.clone();
+    let mut executor = Executor::new();
+    executor.spawn(async move {
+        COUNTER.with(|counter| counter.set(5));
+        result.set(BASE.with(|base| *base));
+    });
+    executor.run_until_idle();
+
+    assert_eq!(base.get(), 105);
+}
@@ -0,0 +1,250 @@
+//! This integration test runs Tasks on the SimpleExecutor which coordinate through the
+//! async synchronization primitives in task::sync.
+
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+mod common;
+
+use alloc::{rc::Rc, vec, vec::Vec};
+use bootloader::{BootInfo, entry_point};
+use common::yield_now;
+use core::{
+    cell::RefCell,
+    panic::PanicInfo,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+use futures_util::StreamExt;
+use rust_os::task::{
+    Task,
+    simple_executor::SimpleExecutor,
+    sync::{Mutex, Notify, RwLock, Semaphore, mpsc, oneshot},
+};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use rust_os::allocator;
+    use rust_os::memory::{self, BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    rust_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
+
+/// Runs the Futures as Tasks until all of them have completed
+fn run(futures: Vec<Task>) {
+    let mut executor = SimpleExecutor::new();
+    for task in futures {
+        executor.spawn(task);
+    }
+    executor.run();
+}
+
+#[test_case]
+fn mutex_held_across_await() {
+    static COUNTER: Mutex<u64> = Mutex::new(0);
+
+    async fn increment() {
+        for _ in 0..100 {
+            let mut counter = COUNTER.lock().await;
+            let value = *counter;
+            yield_now().await;
+            *counter = value + 1;
+        }
+    }
+
+    run(vec![Task::new(increment()), Task::new(increment())]);
+    assert_eq!(*COUNTER.try_lock().unwrap(), 200);
+}
+
+#[test_case]
+fn mutex_try_lock_fails_while_locked() {
+    let mutex = Mutex::new(());
+    let guard = mutex.try_lock().unwrap();
+    assert!(mutex.try_lock().is_none());
+    drop(guard);
+    assert!(mutex.try_lock().is_some());
+}
+
+#[test_case]
+fn rwlock_readers_share_and_writer_excludes() {
+    static LOCK: RwLock<u64> = RwLock::new(0);
+    static READING: AtomicUsize = AtomicUsize::new(0);
+    static MAX_READING: AtomicUsize = AtomicUsize::new(0);
+
+    async fn reader() {
+        let value = LOCK.read().await;
+        let reading = READING.fetch_add(1, Ordering::SeqCst) + 1;
+        MAX_READING.fetch_max(reading, Ordering::SeqCst);
+        yield_now().await;
+        assert!(*value == 0 || *value == 1);
+        READING.fetch_sub(1, Ordering::SeqCst);
+    }
+
+    async fn writer() {
+        let mut value = LOCK.write().await;
+        assert_eq!(READING.load(Ordering::SeqCst), 0);
+        yield_now().await;
+        *value += 1;
+    }
+
+    run(vec![
+        Task::new(reader()),
+        Task::new(reader()),
+        Task::new(writer()),
+        Task::new(reader()),
+    ]);
+    assert_eq!(MAX_READING.load(Ordering::SeqCst), 2);
+}
+
+#[test_case]
+fn semaphore_limits_concurrency() {
+    static SEMAPHORE: Semaphore = Semaphore::new(3);
+    static RUNNING: AtomicUsize = AtomicUsize::new(0);
+    static MAX_RUNNING: AtomicUsize = AtomicUsize::new(0);
+
+    async fn worker() {
+        let _permit = SEMAPHORE.acquire().await;
+        let running = RUNNING.fetch_add(1, Ordering::SeqCst) + 1;
+        MAX_RUNNING.fetch_max(running, Ordering::SeqCst);
+        yield_now().await;
+        yield_now().await;
+        RUNNING.fetch_sub(1, Ordering::SeqCst);
+    }
+
+    run((0..10).map(|_| Task::new(worker())).collect());
+    assert_eq!(MAX_RUNNING.load(Ordering::SeqCst), 3);
+    assert_eq!(SEMAPHORE.available_permits(), 3);
+}
+
+#[test_case]
+fn notify_wakes_waiter() {
+    static NOTIFY: Notify = Notify::new();
+    let order = Rc::new(RefCell::new(Vec::new()));
+
+    let waiter_order = order.clone();
+    let waiter = async move {
+        NOTIFY.notified().await;
+        waiter_order.borrow_mut().push("woken");
+    };
+    let notifier_order = order.clone();
+    let notifier = async move {
+        yield_now().await;
+        notifier_order.borrow_mut().push("notifying");
+        NOTIFY.notify_one();
+    };
+
+    run(vec![Task::new(waiter), Task::new(notifier)]);
+    assert_eq!(*order.borrow(), ["notifying", "woken"]);
+}
+
+#[test_case]
+fn notify_stores_permit() {
+    let notify = Rc::new(Notify::new());
+    notify.notify_one();
+
+    let waiter = notify.clone();
+    run(vec![Task::new(async move { waiter.notified().await })]);
+}
+
+#[test_case]
+fn bounded_channel_in_order() {
+    let (sender, mut receiver) = mpsc::channel(2);
+    let received = Rc::new(RefCell::new(Vec::new()));
+
+    let producer = async move {
+        for i in 0..10 {
+            sender.send(i).await.unwrap();
+        }
+    };
+    let consumer_received = received.clone();
+    let consumer = async move {
+        while let Some(value) = receiver.recv().await {
+            consumer_received.borrow_mut().push(value);
+        }
+    };
+
+    run(vec![Task::new(producer), Task::new(consumer)]);
+    assert_eq!(*received.borrow(), (0..10).collect::<Vec<_>>());
+}
+
+#[test_case]
+fn bounded_channel_full() {
+    let (sender, mut receiver) = mpsc::channel(1);
+    assert!(sender.try_send(1).is_ok());
+    assert_eq!(sender.try_send(2), Err(mpsc::TrySendError::Full(2)));
+    assert_eq!(receiver.try_recv(), Ok(1));
+    drop(receiver);
+    assert_eq!(sender.try_send(3), Err(mpsc::TrySendError::Closed(3)));
+}
+
+#[test_case]
+fn unbounded_channel_stream() {
+    let (sender, receiver) = mpsc::unbounded();
+    let second = sender.clone();
+    for i in 0..50 {
+        sender.send(i).unwrap();
+    }
+    second.send(50).unwrap();
+    drop(sender);
+    drop(second);
+
+    let sum = Rc::new(RefCell::new(0));
+    let consumer_sum = sum.clone();
+    run(vec![Task::new(async move {
+        *consumer_sum.borrow_mut() = receiver.fold(0, |sum, i| async move { sum + i }).await;
+    })]);
+    assert_eq!(*sum.borrow(), (0..=50).sum::<i32>());
+}
+
+#[test_case]
+fn oneshot_sends_value() {
+    let (sender, receiver) = oneshot::channel();
+    let result = Rc::new(RefCell::new(None));
+
+    let receiver_result = result.clone();
+    let receiving = async move {
+        *receiver_result.borrow_mut() = Some(receiver.await);
+    };
+    let sending = async move {
+        yield_now().await;
+        sender.send(42).unwrap();
+    };
+
+    run(vec![Task::new(receiving), Task::new(sending)]);
+    assert_eq!(*result.borrow(), Some(Ok(42)));
+}
+
+#[test_case]
+fn oneshot_sender_dropped() {
+    let (sender, receiver) = oneshot::channel::<u32>();
+    let result = Rc::new(RefCell::new(None));
+
+    let receiver_result = result.clone();
+    let receiving = async move {
+        *receiver_result.borrow_mut() = Some(receiver.await);
+    };
+
+    run(vec![
+        Task::new(receiving),
+        Task::new(async move { drop(sender) }),
+    ]);
+    assert_eq!(*result.borrow(), Some(Err(oneshot::RecvError)));
+}
@@ -0,0 +1,127 @@
+//! This integration test checks that the TestExecutor steps Tasks and timers deterministically
+//! against its virtual clock, and delivers injected scancodes.
+
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::{rc::Rc, vec, vec::Vec};
+use bootloader::{BootInfo, entry_point};
+use core::{cell::RefCell, future::pending, panic::PanicInfo};
+use futures_util::StreamExt;
+use rust_os::task::{
+    keyboard::ScancodeStream,
+    test_executor::TestExecutor,
+    timer::{self, Elapsed, sleep, timeout},
+};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use rust_os::allocator;
+    use rust_os::memory::{self, BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    rust_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
+
+/// Milliseconds in nanoseconds
+const MILLIS: u64 = 1_000_000;
+
+#[test_case]
+fn virtual_clock_starts_at_zero() {
+    let executor = TestExecutor::new();
+    assert_eq!(executor.now(), 0);
+    assert_eq!(timer::now(), 0);
+}
+
+#[test_case]
+fn sleep_completes_only_when_advanced() {
+    let mut executor = TestExecutor::new();
+    let handle = executor.spawn(sleep(100 * MILLIS));
+
+    executor.run_until_stalled();
+    assert!(!handle.is_finished());
+
+    executor.advance(99 * MILLIS);
+    assert!(!handle.is_finished());
+
+    executor.advance(MILLIS);
+    assert!(handle.is_finished());
+    assert_eq!(executor.now(), 100 * MILLIS);
+}
+
+#[test_case]
+fn advance_fires_timers_in_order() {
+    let mut executor = TestExecutor::new();
+    let log = Rc::new(RefCell::new(Vec::new()));
+    for delay in [30, 10, 20] {
+        let log = log.clone();
+        executor.spawn(async move {
+            sleep(delay * MILLIS).await;
+            log.borrow_mut().push((delay, timer::now()));
+        });
+    }
+
+    // Each Task sees the clock at its own deadline, not at the end of the advance
+    executor.advance(1000 * MILLIS);
+    assert_eq!(
+        *log.borrow(),
+        [(10, 10 * MILLIS), (20, 20 * MILLIS), (30, 30 * MILLIS)]
+    );
+}
+
+#[test_case]
+fn run_until_complete_skips_to_deadlines() {
+    let mut executor = TestExecutor::new();
+    let output = executor.run_until_complete(async {
+        sleep(5000 * MILLIS).await;
+        sleep(5000 * MILLIS).await;
+        vec![1, 2]
+    });
+    assert_eq!(output, [1, 2]);
+    assert_eq!(executor.now(), 10_000 * MILLIS);
+}
+
+#[test_case]
+fn timeout_elapses_on_virtual_clock() {
+    let mut executor = TestExecutor::new();
+    let result = executor.run_until_complete(timeout(50 * MILLIS, pending::<()>()));
+    assert_eq!(result, Err(Elapsed));
+    assert_eq!(executor.now(), 50 * MILLIS);
+}
+
+#[test_case]
+fn injected_scancodes_reach_stream() {
+    let mut executor = TestExecutor::new();
+    let received = Rc::new(RefCell::new(Vec::new()));
+    let log = received.clone();
+    executor.spawn(async move {
+        let mut scancodes = ScancodeStream::new();
+        while let Some(scancode) = scancodes.next().await {
+            log.borrow_mut().push(scancode);
+        }
+    });
+    executor.run_until_stalled();
+
+    executor.inject_scancode(0x1e);
+    assert_eq!(*received.borrow(), [0x1e]);
+    executor.inject_scancode(0x9e);
+    assert_eq!(*received.borrow(), [0x1e, 0x9e]);
+}
@@ -0,0 +1,202 @@
+//! This integration test spawns kernel threads, then tests that the timer interrupt
+//! preempts them, so a thread which never yields does not stop other threads running,
+//! and that the scheduler's priorities, sleeping, joining and wait queues work.
+
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{BootInfo, entry_point};
+use core::cell::Cell;
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use rust_os::task::executor::Executor;
+use rust_os::thread::{self, Priority, WaitQueue, spawn_thread, spawn_thread_with_priority};
+use rust_os::{hlt_loop, per_cpu, task_local, time};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use rust_os::memory::{self, BootInfoFrameAllocator};
+    use rust_os::{acpi, allocator};
+    use x86_64::VirtAddr;
+
+    rust_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    acpi::init();
+    time::init();
+
+    test_main();
+    hlt_loop()
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
+
+/// How long to wait for other threads before failing
+const TIMEOUT_NANOS: u64 = 2_000_000_000;
+
+/// Spins without yielding until `done` returns true, panicking if that takes
+/// longer than TIMEOUT_NANOS. This only returns if the current thread is preempted.
+fn wait_until(done: impl Fn() -> bool) {
+    let start = time::now();
+    while !done() {
+        assert!(time::now() - start < TIMEOUT_NANOS, "timed out");
+        core::hint::spin_loop();
+    }
+}
+
+#[test_case]
+fn busy_thread_is_preempted() {
+    static SPINS: AtomicUsize = AtomicUsize::new(0);
+    static STOP: AtomicBool = AtomicBool::new(false);
+    static EXITED: AtomicBool = AtomicBool::new(false);
+
+    spawn_thread(|| {
+        while !STOP.load(Ordering::SeqCst) {
+            SPINS.fetch_add(1, Ordering::SeqCst);
+            core::hint::spin_loop();
+        }
+        EXITED.store(true, Ordering::SeqCst);
+    });
+
+    // The boot thread spins as well, so each only runs when the other is preempted
+    wait_until(|| SPINS.load(Ordering::SeqCst) > 0);
+    let spins = SPINS.load(Ordering::SeqCst);
+    wait_until(|| SPINS.load(Ordering::SeqCst) > spins);
+
+    STOP.store(true, Ordering::SeqCst);
+    wait_until(|| EXITED.load(Ordering::SeqCst));
+}
+
+#[test_case]
+fn many_threads_run_to_completion() {
+    static FINISHED: AtomicUsize = AtomicUsize::new(0);
+
+    for _ in 0..8 {
+        spawn_thread(|| {
+            FINISHED.fetch_add(1, Ordering::SeqCst);
+        });
+    }
+
+    wait_until(|| FINISHED.load(Ordering::SeqCst) == 8);
+}
+
+#[test_case]
+fn join_waits_for_exit() {
+    static EXITED: AtomicBool = AtomicBool::new(false);
+
+    let id = spawn_thread(|| {
+        thread::sleep(100_000_000);
+        EXITED.store(true, Ordering::SeqCst);
+    });
+    thread::join(id);
+    assert!(EXITED.load(Ordering::SeqCst));
+}
+
+#[test_case]
+fn sleep_lasts_at_least_the_duration() {
+    let start = time::now();
+    thread::sleep(200_000_000);
+    assert!(time::now() - start >= 200_000_000);
+}
+
+#[test_case]
+fn higher_priority_runs_first() {
+    static ORDER: AtomicUsize = AtomicUsize::new(0);
+    static HIGH_RAN_AT: AtomicUsize = AtomicUsize::new(0);
+    static LOW_RAN_AT: AtomicUsize = AtomicUsize::new(0);
+
+    let low = spawn_thread_with_priority(
+        || LOW_RAN_AT.store(ORDER.fetch_add(1, Ordering::SeqCst) + 1, Ordering::SeqCst),
+        Priority::Low,
+    );
+    let high = spawn_thread_with_priority(
+        || HIGH_RAN_AT.store(ORDER.fetch_add(1, Ordering::SeqCst) + 1, Ordering::SeqCst),
+        Priority::High,
+    );
+
+    // Blocking lets both run, so the high priority thread must run first
+    thread::join(low);
+    thread::join(high);
+    assert!(HIGH_RAN_AT.load(Ordering::SeqCst) < LOW_RAN_AT.load(Ordering::SeqCst));
+}
+
+#[test_case]
+fn wait_queue_wakes_waiter() {
+    static QUEUE: WaitQueue = WaitQueue::new();
+    static READY: AtomicBool = AtomicBool::new(false);
+    static WOKEN: AtomicBool = AtomicBool::new(false);
+
+    let waiter = spawn_thread(|| {
+        QUEUE.wait_until(|| READY.load(Ordering::SeqCst));
+        WOKEN.store(true, Ordering::SeqCst);
+    });
+
+    thread::sleep(100_000_000);
+    assert!(!WOKEN.load(Ordering::SeqCst));
+    READY.store(true, Ordering::SeqCst);
+    QUEUE.notify_all();
+    thread::join(waiter);
+    assert!(WOKEN.load(Ordering::SeqCst));
+}
+
+#[test_case]
+fn cpu_time_is_counted() {
+    static STOP: AtomicBool = AtomicBool::new(false);
+
+    let busy = spawn_thread(|| {
+        while !STOP.load(Ordering::SeqCst) {
+            core::hint::spin_loop();
+        }
+    });
+
+    // While this thread sleeps, the busy thread has the CPU to itself
+    thread::sleep(300_000_000);
+    let cpu_time = thread::threads()
+        .into_iter()
+        .find(|info| info.id == busy)
+        .map(|info| info.cpu_time)
+        .expect("busy thread is listed");
+    assert!(cpu_time > 0);
+
+    STOP.store(true, Ordering::SeqCst);
+    thread::join(busy);
+    assert!(thread::threads().iter().all(|info| info.id != busy));
+}
+
+task_local! {
+    static VALUE: Cell<u32> = Cell::new(0);
+}
+
+/// Tests that a thread switched to while a Task is being polled does not see the Task or
+/// its task-locals, and that the polling thread has them back once it runs again
+#[test_case]
+fn task_locals_stay_with_the_polling_thread() {
+    static OUTSIDE_TASK: AtomicBool = AtomicBool::new(false);
+    static VALUE_AFTER_JOIN: AtomicUsize = AtomicUsize::new(0);
+
+    let mut executor = Executor::new();
+    executor.spawn(async {
+        VALUE.with(|value| value.set(42));
+        let other = spawn_thread(|| {
+            let outside = per_cpu!(current_task()).is_none() && VALUE.try_with(|_| ()).is_err();
+            OUTSIDE_TASK.store(outside, Ordering::SeqCst);
+        });
+        thread::join(other);
+        VALUE_AFTER_JOIN.store(VALUE.with(|value| value.get()) as usize, Ordering::SeqCst);
+    });
+    executor.run_until_idle();
+
+    assert!(OUTSIDE_TASK.load(Ordering::SeqCst));
+    assert_eq!(VALUE_AFTER_JOIN.load(Ordering::SeqCst), 42);
+}